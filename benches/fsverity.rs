@@ -0,0 +1,61 @@
+/* Hashing with fs-verity: on one thread, or on all of them
+ *
+ * FsVerityHasher::hash() computes the hashes of the data blocks of large buffers on several
+ * threads (see digest.rs).  This compares it with feeding the same buffer to update(), which does
+ * everything on the calling thread, for a range of sizes around the threshold.  Run it with
+ * `cargo bench`.
+ */
+
+use std::{
+    hint::black_box,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use composefs_experiments::fsverity::{
+    Sha256HashValue,
+    digest::FsVerityHasher,
+};
+
+// Each measurement is the fastest of this many runs, which filters out most of the noise
+const RUNS: usize = 5;
+
+fn fastest<F: FnMut() -> Sha256HashValue>(mut f: F) -> (Duration, Sha256HashValue) {
+    let mut best = Duration::MAX;
+    let mut digest = [0; 32];
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        digest = black_box(f());
+        best = best.min(start.elapsed());
+    }
+    (best, digest)
+}
+
+fn throughput(size: usize, time: Duration) -> f64 {
+    size as f64 / (1 << 20) as f64 / time.as_secs_f64()
+}
+
+fn main() {
+    let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{n_threads} threads available");
+
+    for size in [64 << 10, 1 << 20, 16 << 20, 256 << 20] {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+
+        let (single, expected) = fastest(|| {
+            let mut hasher = FsVerityHasher::<Sha256HashValue>::new();
+            hasher.update(black_box(&data));
+            hasher.digest()
+        });
+        let (parallel, digest) = fastest(|| FsVerityHasher::<Sha256HashValue>::hash(black_box(&data)));
+        assert_eq!(digest, expected, "the parallel and single-threaded digests differ");
+
+        println!(
+            "{:>6} KiB: single-threaded {:>7.1} MiB/s, parallel {:>7.1} MiB/s ({:.2}x)",
+            size >> 10, throughput(size, single), throughput(size, parallel),
+            single.as_secs_f64() / parallel.as_secs_f64()
+        );
+    }
+}
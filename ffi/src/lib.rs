@@ -0,0 +1,165 @@
+//! C bindings for the core operations of composefs_experiments.
+//!
+//! The header (composefs_ffi.h) is generated from this file by cbindgen when building, in the
+//! OUT_DIR of the build.  `make install` installs it along with the libraries.
+//! Functions which can fail return 0 on success and -1 on failure, in which case
+//! cfs_last_error() describes what went wrong.  Digests are 32-byte binary fs-verity digests.
+
+use std::{
+    cell::RefCell,
+    ffi::{
+        CStr,
+        CString,
+        c_char,
+        c_int,
+    },
+    fs::File,
+    mem::ManuallyDrop,
+    os::fd::FromRawFd,
+    ptr::null_mut,
+};
+
+use anyhow::{
+    Context,
+    Result,
+};
+
+use composefs_experiments::{
+    Repository,
+    oci,
+};
+
+/// An open repository.  This is opaque for C.
+pub struct CfsRepository(Repository);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(err: anyhow::Error) {
+    let message = CString::new(format!("{err:#}").replace('\0', "")).expect("no NULs left");
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Runs f, converting the result to a return code for C.  Panics must not unwind into C.
+fn call<F: FnOnce() -> Result<()>>(f: F) -> c_int {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            set_error(err);
+            -1
+        },
+        Err(_) => {
+            set_error(anyhow::anyhow!("internal error (panic)"));
+            -1
+        },
+    }
+}
+
+/// # Safety
+/// str must be a valid NUL-terminated string.
+unsafe fn to_str<'a>(str: *const c_char) -> Result<&'a str> {
+    CStr::from_ptr(str).to_str().context("String isn't UTF-8")
+}
+
+/// Borrows a file descriptor from the caller, without taking ownership of it.
+fn borrow_fd(fd: c_int) -> ManuallyDrop<File> {
+    // SAFETY: we never close it (that's what the ManuallyDrop is for)
+    ManuallyDrop::new(unsafe { File::from_raw_fd(fd) })
+}
+
+/// # Safety
+/// digest must point to 32 writable bytes.
+unsafe fn write_digest(id: &[u8; 32], digest: *mut u8) {
+    std::ptr::copy_nonoverlapping(id.as_ptr(), digest, id.len());
+}
+
+/// Returns a description of the last error on this thread.  The string stays valid until the
+/// next call of a cfs_ function on the same thread.
+#[no_mangle]
+pub extern "C" fn cfs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Opens the repository at path, or returns NULL on failure.  With insecure set, it can be on a
+/// filesystem without fs-verity.  Free it with cfs_repository_free().
+///
+/// # Safety
+/// path must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cfs_repository_open(path: *const c_char, insecure: bool) -> *mut CfsRepository {
+    let mut repo = None;
+    let result = call(|| {
+        let mut opened = Repository::open_path(to_str(path)?.to_string())?;
+        if insecure {
+            opened.set_insecure(true);
+        }
+        repo = Some(Box::new(CfsRepository(opened)));
+        Ok(())
+    });
+    match (result, repo) {
+        (0, Some(repo)) => Box::into_raw(repo),
+        _ => null_mut(),
+    }
+}
+
+/// Closes a repository opened with cfs_repository_open().
+///
+/// # Safety
+/// repo must come from cfs_repository_open() (or be NULL), and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cfs_repository_free(repo: *mut CfsRepository) {
+    if !repo.is_null() {
+        drop(Box::from_raw(repo));
+    }
+}
+
+/// Reads a tar stream from fd (which stays open) and stores it as a stream with the given ref
+/// name.  Writes the digest of the stream to digest.
+///
+/// # Safety
+/// repo must be a valid repository, name a valid NUL-terminated string, and digest must point to
+/// 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cfs_import_layer(
+    repo: *const CfsRepository, name: *const c_char, fd: c_int, digest: *mut u8
+) -> c_int {
+    call(|| {
+        let id = oci::import_layer(&(*repo).0, to_str(name)?, &mut *borrow_fd(fd))?;
+        write_digest(&id, digest);
+        Ok(())
+    })
+}
+
+/// Reads a composefs image from fd (which stays open) and stores it with the given ref name.
+/// Writes the fs-verity digest of the image to digest.
+///
+/// # Safety
+/// repo must be a valid repository, name a valid NUL-terminated string, and digest must point to
+/// 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cfs_import_image(
+    repo: *const CfsRepository, name: *const c_char, fd: c_int, digest: *mut u8
+) -> c_int {
+    call(|| {
+        let id = (*repo).0.import_image(to_str(name)?, &mut *borrow_fd(fd), None)?;
+        write_digest(&id, digest);
+        Ok(())
+    })
+}
+
+/// Writes the fs-verity digest of the named image (as used for composefs= on the kernel command
+/// line, for example) to digest.
+///
+/// # Safety
+/// repo must be a valid repository, name a valid NUL-terminated string, and digest must point to
+/// 32 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cfs_image_digest(
+    repo: *const CfsRepository, name: *const c_char, digest: *mut u8
+) -> c_int {
+    call(|| {
+        write_digest(&(*repo).0.image_digest(to_str(name)?)?, digest);
+        Ok(())
+    })
+}
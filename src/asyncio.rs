@@ -0,0 +1,136 @@
+/* Async variants of the stream operations, for embedding in tokio-based services
+ *
+ * The repository does its I/O with blocking system calls, and that doesn't change here: there's
+ * no such thing as non-blocking file I/O (short of io_uring).  What these functions do is connect
+ * async readers and writers (network connections, typically) to the blocking code.  The data
+ * flows through a bounded channel, and the blocking side runs as a single blocking task per
+ * operation, rather than one per read or write.
+ */
+
+use std::{
+    io::{
+        BufWriter,
+        ErrorKind,
+        Read,
+        Write,
+    },
+    sync::Arc,
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+    },
+    sync::mpsc::{
+        Receiver,
+        Sender,
+        channel,
+    },
+    task::spawn_blocking,
+};
+
+use crate::{
+    fsverity::Sha256HashValue,
+    oci,
+    repository::Repository,
+};
+
+// The size of the blocks passed between the async and the blocking side, and how many of them
+// can be in flight
+const BLOCK_SIZE: usize = 1 << 20;
+const CHANNEL_BLOCKS: usize = 16;
+
+/// The blocking side of an async reader.
+struct ChannelReader {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.offset == self.block.len() {
+            match self.receiver.blocking_recv() {
+                Some(block) => (self.block, self.offset) = (block?, 0),
+                None => return Ok(0),  // EOF
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.offset);
+        buf[..n].copy_from_slice(&self.block[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// The blocking side of an async writer.  Wrap it in a BufWriter: every write is sent as a block.
+struct ChannelWriter {
+    sender: Sender<Vec<u8>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.sender.blocking_send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(_) => Err(ErrorKind::BrokenPipe.into()),  // the async writer failed
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Like oci::import_layer(), for a tar stream coming from an async reader.
+pub async fn import_layer<R: AsyncRead + Unpin>(
+    repo: Arc<Repository>, name: &str, tar_stream: &mut R
+) -> Result<Sha256HashValue> {
+    let (sender, receiver) = channel(CHANNEL_BLOCKS);
+    let name = name.to_string();
+    let task = spawn_blocking(move || {
+        oci::import_layer(&repo, &name, &mut ChannelReader { receiver, block: vec![], offset: 0 })
+    });
+
+    loop {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let result = match tar_stream.read(&mut block).await {
+            Ok(0) => break,
+            Ok(n) => {
+                block.truncate(n);
+                Ok(block)
+            },
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let failed = result.is_err();
+        if sender.send(result).await.is_err() || failed {
+            break;  // the import failed (we'll get the error below), or we told it about ours
+        }
+    }
+    drop(sender);
+
+    task.await?
+}
+
+/// Like Repository::merge_splitstream(), writing the merged stream to an async writer.
+pub async fn merge_splitstream<W: AsyncWrite + Unpin>(
+    repo: Arc<Repository>, name: &str, stream: &mut W
+) -> Result<()> {
+    let (sender, mut receiver) = channel(CHANNEL_BLOCKS);
+    let name = name.to_string();
+    let task = spawn_blocking(move || -> Result<()> {
+        let mut writer = BufWriter::with_capacity(BLOCK_SIZE, ChannelWriter { sender });
+        repo.merge_splitstream(&name, &mut writer)?;
+        Ok(writer.flush()?)
+    });
+
+    // If writing fails, the receiver gets dropped, which makes the blocking side fail too.
+    while let Some(block) = receiver.recv().await {
+        stream.write_all(&block).await?;
+    }
+    task.await??;
+    Ok(stream.flush().await?)
+}
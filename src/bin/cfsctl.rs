@@ -1,9 +1,53 @@
-use anyhow::Result;
+use std::{
+    fs::File,
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
 use clap::{Parser, Subcommand};
+use tracing_subscriber::{
+    EnvFilter,
+    fmt::format::FmtSpan,
+};
 
 use composefs_experiments::{
+    boot,
+    dmverity,
+    idmap::{
+        IdMap,
+        IdMapping,
+    },
+    image::{
+        self,
+        Change,
+        DevicePolicy,
+        ListEntry,
+    },
+    json::Json,
+    mount,
     oci,
-    repository::Repository,
+    ostree,
+    progress::{
+        self,
+        Format,
+        Verbosity,
+    },
+    repository::{
+        Durability,
+        Repository,
+    },
+    selinux::FileContexts,
+    signing::{
+        PublicKey,
+        SigningKey,
+    },
+    sysext,
+    virtiofs,
 };
 
 
@@ -17,6 +61,31 @@ pub struct App {
     user: bool,
     #[clap(long, group="repopath")]
     system: bool,
+    /// Allow operating on filesystems without fs-verity, checking digests in userspace
+    /// (with init: mark the repository to always work like that)
+    #[clap(long)]
+    insecure: bool,
+    /// The zstd compression level for new streams (0 for the default)
+    #[clap(long, default_value_t = 0)]
+    zstd_level: i32,
+    /// Don't sync each new object, but sync the filesystem once before writing refs
+    #[clap(long, conflicts_with = "no_fsync")]
+    batch_fsync: bool,
+    /// Don't sync new objects at all (only for repositories that can be thrown away)
+    #[clap(long)]
+    no_fsync: bool,
+    /// Print results as JSON, for consumption by other programs
+    #[clap(long)]
+    json: bool,
+    /// Only print warnings (and results) on long operations
+    #[clap(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print details of what's happening, like every object that's removed by gc
+    #[clap(long, short)]
+    verbose: bool,
+    /// Print progress and log messages as JSON lines on stderr
+    #[clap(long)]
+    log_json: bool,
 
     #[clap(subcommand)]
     cmd: Command,
@@ -32,11 +101,156 @@ enum OciCommand {
     LsLayer {
         /// the name of the stream
         name: String,
+        /// refuse paths with more than this many components
+        #[clap(long)]
+        max_depth: Option<usize>,
+        /// add security.ima xattrs with the digests of regular files, for IMA appraisal
+        #[clap(long)]
+        ima: bool,
+        /// label entries which have no security.selinux xattr according to this file_contexts
+        #[clap(long)]
+        selinux: Option<String>,
+        /// shift the uids of entries (and the rootids of file capabilities) with this map, as
+        /// 'inside:outside:count' ranges, like '0:100000:65536'
+        #[clap(long)]
+        uidmap: Option<String>,
+        /// shift the gids of entries with this map (the same as --uidmap, if not given)
+        #[clap(long, requires = "uidmap")]
+        gidmap: Option<String>,
+    },
+    /// Shows the differences between the contents of two tar streams
+    Diff {
+        /// the name of the old stream
+        old: String,
+        /// the name of the new stream (or image, with --image)
+        new: String,
+        /// compare the old stream to an image instead
+        #[clap(long)]
+        image: bool,
+    },
+    /// Creates an OCI runtime bundle for an image, to run it with crun or runc
+    Bundle {
+        /// the name of the image, either a sha256 digest or prefixed with 'ref/'
+        image: String,
+        /// the bundle directory (created if needed)
+        dir: String,
+        /// copy the content of the image to rootfs/ instead of mounting it (read-only) there
+        #[clap(long)]
+        extract: bool,
+        /// an environment variable for the process, as NAME=value (can be given more than once)
+        #[clap(long)]
+        env: Vec<String>,
+        /// the working directory of the process
+        #[clap(long, default_value = "/")]
+        cwd: String,
+        /// give the process a terminal
+        #[clap(long)]
+        tty: bool,
+        /// the command to run in the container
+        #[clap(last = true, default_value = "/bin/sh")]
+        args: Vec<String>,
+    },
+    /// Writes layers as an image in a podman image store (for use as an additional image store)
+    ExportPodman {
+        /// the image store directory, like /usr/lib/containers/storage
+        store: String,
+        /// the layer streams of the image, lowest layer first
+        #[clap(required = true)]
+        layers: Vec<String>,
+        /// a name for the image, like 'localhost/myimage:latest' (can be given more than once)
+        #[clap(long)]
+        name: Vec<String>,
+    },
+    /// Merges a number of layers (applying whiteouts) into a single new layer stream
+    Squash {
+        /// the name of the new stream
+        name: String,
+        /// the layer streams to merge, lowest layer first
+        layers: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum StreamsCommand {
+    /// Lists the streams which aren't referenced by any ref (and would be removed by gc)
+    Orphans,
+}
+
+#[derive(Debug, Subcommand)]
+enum StateCommand {
+    /// Removes the writable state of images which are no longer in the repository
+    Prune,
+}
+
+#[derive(Debug, Subcommand)]
+enum SystemdCommand {
+    /// Writes mount units which mount an image at boot
+    Generate {
+        /// the name of the image to mount, either a sha256 digest or prefixed with 'ref/'
+        image: String,
+        /// the (absolute) path to mount the image on
+        mountpoint: String,
+        /// the directory to write the units to
+        #[clap(long, default_value = "/etc/systemd/system")]
+        unit_dir: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SysextCommand {
+    /// Builds a system extension image, and stores it in the repository as sysext/<name>
+    Build {
+        /// an image in the repository, or a directory (containing usr/ and/or opt/)
+        source: String,
+        /// the name of the extension
+        name: String,
+        /// the ID of the OS the extension is for (as in os-release)
+        #[clap(long, default_value = "_any")]
+        id: String,
+        /// the VERSION_ID of the OS the extension is for
+        #[clap(long)]
+        version_id: Option<String>,
+        /// the SYSEXT_LEVEL of the OS the extension is for
+        #[clap(long)]
+        sysext_level: Option<String>,
+        /// also write the image to this file (like /var/lib/extensions/<name>.raw)
+        #[clap(long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum DeployCommand {
+    /// Marks the running deployment as good, for automatic boot assessment (run this from boot-complete.target)
+    MarkGood {
+        /// the root of the boot partition
+        #[clap(long, default_value = "/boot")]
+        boot_dir: String,
+    },
+    /// Populates the persistent /var from an image, unless that was done before (run this at boot)
+    PopulateVar {
+        /// the name of the booted image, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the persistent /var
+        #[clap(long, default_value = "/var")]
+        var_dir: String,
+    },
+    /// Activates staged deployments (run this at shutdown)
+    Finalize {
+        /// the root of the boot partition
+        #[clap(long, default_value = "/boot")]
+        boot_dir: String,
     },
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
+    /// Initializes a new, empty repository
+    Init {
+        /// a salt (in hex, up to 32 bytes) to use for the fs-verity digests of all objects
+        #[clap(long)]
+        salt: Option<String>,
+    },
     /// Take a transaction lock on the repository.
     /// This prevents garbage collection from occurring.
     Transaction,
@@ -44,12 +258,213 @@ enum Command {
     Cat {
         /// the name of the stream to cat, either a sha256 digest or prefixed with 'ref/'
         name: String,
+        /// only output the content starting at this offset
+        #[clap(long)]
+        offset: Option<u64>,
+        /// only output (at most) this many bytes
+        #[clap(long)]
+        length: Option<u64>,
+        /// compress the output with zstd, at the given level
+        #[clap(long, conflicts_with_all = ["offset", "length"])]
+        zstd: Option<i32>,
+    },
+    /// Stores an arbitrary file as a stream, named for the sha256 digest of its content
+    AddFile {
+        /// the file to add
+        path: String,
+        /// an additional name for the stream, like 'kernels/6.11.0'
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Writes the content of a file added with add-file to stdout
+    CatStream {
+        /// the sha256 digest of the file, or a name prefixed with 'refs/'
+        name: String,
     },
     /// Perform garbage collection
-    GC,
+    GC {
+        /// only report what would be removed
+        #[clap(long)]
+        dry_run: bool,
+        /// keep anything added less than this long ago, like '30m', '12h' or '7d'
+        #[clap(long, value_parser = parse_duration)]
+        keep_younger_than: Option<Duration>,
+    },
+    /// Protects an image (or a stream) from garbage collection
+    Pin {
+        /// the name of the image (or stream), or its digest
+        name: String,
+        /// pin a stream instead of an image
+        #[clap(long)]
+        stream: bool,
+    },
+    /// Removes a pin added with pin
+    Unpin {
+        /// the name of the image (or stream), or its digest
+        name: String,
+        /// unpin a stream instead of an image
+        #[clap(long)]
+        stream: bool,
+    },
+    /// Signs an image with an Ed25519 key, and stores the signature in the repository
+    Sign {
+        /// the name of the image, or its digest
+        name: String,
+        /// the private key, in PEM format (like from 'openssl genpkey -algorithm ed25519')
+        #[clap(long)]
+        key: String,
+    },
+    /// Checks that an image is signed by one of the given keys, or of the trusted keys of the repository
+    Verify {
+        /// the name of the image, or its digest
+        name: String,
+        /// a public key, in PEM format (can be given more than once)
+        #[clap(long)]
+        key: Vec<String>,
+    },
+    /// Rewrites streams from before the stream format had a header
+    MigrateStreams,
+    /// Trains a zstd dictionary on the existing streams, for compressing new streams
+    TrainDictionary {
+        /// the maximum size of the dictionary, in bytes
+        #[clap(long, default_value_t = 112640)]
+        max_size: usize,
+    },
+    /// Re-measures all objects in the repository and reports any with the wrong digest
+    VerifyObjects {
+        /// move objects which fail verification to the quarantine/ directory
+        #[clap(long)]
+        quarantine: bool,
+    },
     /// Imports a composefs image (unsafe!)
     ImportImage {
         reference: String,
+        /// a file containing a PKCS#7 signature of the fs-verity digest of the image
+        #[clap(long)]
+        signature: Option<String>,
+    },
+    /// Extracts the content of an image into an existing directory
+    ExtractImage {
+        /// the name of the image to extract, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the target directory
+        target: String,
+        /// don't create character and block devices
+        #[clap(long, conflicts_with = "devices_as_empty")]
+        skip_devices: bool,
+        /// create empty regular files in place of character and block devices
+        #[clap(long)]
+        devices_as_empty: bool,
+    },
+    /// Writes the content of an image as a raw filesystem image, protected by a dm-verity hash tree
+    ExportBlockImage {
+        /// the name of the image to export, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the file to write the block image to
+        output: String,
+        /// make an ext4 filesystem instead of erofs
+        #[clap(long)]
+        ext4: bool,
+    },
+    /// Writes the content of an image as a commit in an (existing) ostree repository
+    ExportOstree {
+        /// the name of the image to export, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the ostree repository, in bare or bare-user mode
+        ostree_repo: String,
+        /// the branch to commit to (the previous commit on it becomes the parent)
+        branch: String,
+        /// the subject of the commit message
+        #[clap(long, default_value = "")]
+        subject: String,
+    },
+    /// Writes the content of a file in an image to stdout
+    CatFile {
+        /// the name of the image, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the path of the file in the image
+        path: String,
+    },
+    /// Lists the contents of a directory in an image
+    Ls {
+        /// the name of the image, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the directory (or file) in the image
+        #[clap(default_value = "/")]
+        path: String,
+        /// list all descendants of the directory
+        #[clap(short = 'R', long)]
+        recursive: bool,
+    },
+    /// Shows the differences between the contents of two images
+    DiffImages {
+        /// the name of the old image
+        old: String,
+        /// the name of the new image
+        new: String,
+    },
+    /// Installs the kernel of an image on the boot partition, with a boot entry which mounts the image
+    #[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Deploy {
+        #[clap(subcommand)]
+        cmd: Option<DeployCommand>,
+        /// the name of the image to deploy, either a sha256 digest or prefixed with 'ref/'
+        #[clap(required = true)]
+        name: Option<String>,
+        /// the root of the boot partition
+        #[clap(long, default_value = "/boot")]
+        boot_dir: String,
+        /// additional kernel command line options
+        #[clap(long, default_value = "")]
+        options: String,
+        /// don't activate the boot entry until 'deploy finalize' is run
+        #[clap(long)]
+        stage: bool,
+        /// the image being replaced: carry local changes to /etc over from its state
+        #[clap(long)]
+        merge_etc_from: Option<String>,
+        /// relabel the state of the image according to its SELinux policy
+        #[clap(long)]
+        relabel: bool,
+        /// use automatic boot assessment, giving the new entry this many tries to boot
+        #[clap(long)]
+        tries: Option<u32>,
+    },
+    /// Builds a signable Unified Kernel Image which boots an image
+    Uki {
+        /// the name of the image, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the file to write the UKI to
+        output: String,
+        /// additional kernel command line options
+        #[clap(long, default_value = "")]
+        options: String,
+        /// the private key to sign the UKI with, for Secure Boot
+        #[clap(long, requires = "secureboot_certificate")]
+        secureboot_private_key: Option<String>,
+        /// the certificate to sign the UKI with, for Secure Boot
+        #[clap(long, requires = "secureboot_private_key")]
+        secureboot_certificate: Option<String>,
+    },
+    /// Commands for dealing with streams
+    Streams {
+        #[clap(subcommand)]
+        cmd: StreamsCommand
+    },
+    /// Commands for dealing with the writable state of mounted images
+    State {
+        #[clap(subcommand)]
+        cmd: StateCommand
+    },
+    /// Commands for integrating with systemd
+    Systemd {
+        #[clap(subcommand)]
+        cmd: SystemdCommand
+    },
+    /// Commands for building system extensions
+    Sysext {
+        #[clap(subcommand)]
+        cmd: SysextCommand
     },
     /// Commands for dealing with OCI layers
     Oci {
@@ -62,13 +477,159 @@ enum Command {
         name: String,
         /// the mountpoint
         mountpoint: String,
+        /// make the mount writable, keeping changes in the repository's state for this image
+        #[clap(long)]
+        writable: bool,
+        /// require the image and all of the objects it refers to to have the expected fs-verity digest
+        #[clap(long)]
+        require_verity: bool,
+    },
+    /// Shares an image with a virtual machine, by running virtiofsd on a mount of it
+    ServeVirtiofs {
+        /// the name of the image to share, either a sha256 digest or prefixed with 'ref/'
+        name: String,
+        /// the vhost-user socket for the VMM (like qemu's -chardev socket,path=...) to connect to
+        socket: String,
+        /// the virtiofsd binary to run
+        #[clap(long, default_value = "/usr/libexec/virtiofsd")]
+        virtiofsd: String,
+        /// require the image and all of the objects it refers to to have the expected fs-verity digest
+        #[clap(long)]
+        require_verity: bool,
+    },
+    /// Unmounts a composefs mounted with the mount command
+    Unmount {
+        /// the mountpoint
+        mountpoint: String,
+        /// detach the filesystem now, even if it's busy, and clean up later
+        #[clap(long)]
+        lazy: bool,
     },
 }
 
+/// Parses a duration like '90s', '30m', '12h' or '7d' (or a plain number of seconds).
+fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => value.split_at(idx),
+        None => (value, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Unknown unit '{unit}' (expected s, m, h or d)"),
+    };
+    match number.parse::<u64>()?.checked_mul(seconds) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => bail!("Duration '{value}' is too long"),
+    }
+}
+
+/// Prints the result of a command: as JSON if requested, otherwise in the human format.
+fn report<F: FnOnce()>(json: bool, value: Json, human: F) {
+    if json {
+        println!("{value}");
+    } else {
+        human();
+    }
+}
+
+fn report_digest(json: bool, digest: &[u8]) {
+    report(json, Json::Object(vec![("digest", Json::string(hex::encode(digest)))]), || {
+        println!("{}", hex::encode(digest));
+    });
+}
+
+fn report_paths(json: bool, key: &'static str, paths: &[std::path::PathBuf], verb: &str) {
+    let value = Json::Object(vec![
+        (key, Json::Array(paths.iter().map(|path| Json::string(path.display())).collect())),
+    ]);
+    report(json, value, || {
+        for path in paths {
+            println!("{verb} {}", path.display());
+        }
+    });
+}
+
+fn report_changes(json: bool, changes: &[Change]) {
+    let value = Json::Array(changes.iter().map(|change| match change {
+        Change::Added(path) => Json::Object(vec![
+            ("change", Json::string("added")), ("path", Json::string(path.display())),
+        ]),
+        Change::Removed(path) => Json::Object(vec![
+            ("change", Json::string("removed")), ("path", Json::string(path.display())),
+        ]),
+        Change::Modified(path, what) => Json::Object(vec![
+            ("change", Json::string("modified")), ("path", Json::string(path.display())),
+            ("what", Json::Array(what.iter().map(Json::string).collect())),
+        ]),
+    }).collect());
+
+    report(json, value, || {
+        for change in changes {
+            match change {
+                Change::Added(path) => println!("+ {}", path.display()),
+                Change::Removed(path) => println!("- {}", path.display()),
+                Change::Modified(path, what) => println!("M {} ({})", path.display(), what.join(", ")),
+            }
+        }
+    });
+}
+
+fn report_entries(json: bool, entries: &[ListEntry]) {
+    let value = Json::Array(entries.iter().map(|entry| Json::Object(vec![
+        ("path", Json::string(entry.path.display())),
+        ("mode", Json::Number(entry.mode as u64)),
+        ("uid", Json::Number(entry.uid as u64)),
+        ("gid", Json::Number(entry.gid as u64)),
+        ("size", Json::Number(entry.size)),
+        ("digest", Json::optional(entry.digest.as_ref())),
+        ("symlink_target", Json::optional(entry.symlink_target.as_ref().map(|path| path.display()))),
+        ("hardlink_target", Json::optional(entry.hardlink_target.as_ref().map(|path| path.display()))),
+    ])).collect());
+
+    report(json, value, || {
+        for entry in entries {
+            let detail = if let Some(digest) = &entry.digest {
+                format!(" @ {digest}")
+            } else if let Some(target) = &entry.symlink_target {
+                format!(" -> {}", target.display())
+            } else if let Some(target) = &entry.hardlink_target {
+                format!(" => {}", target.display())
+            } else {
+                String::new()
+            };
+            println!(
+                "{} {:>5} {:>5} {:>10} {}{detail}",
+                image::mode_string(entry.mode), entry.uid, entry.gid, entry.size, entry.path.display()
+            );
+        }
+    });
+}
+
 fn main() -> Result<()> {
     let args = App::parse();
 
-    let repo = (
+    let verbosity = if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    progress::init(verbosity, if args.log_json { Format::Json } else { Format::Human })?;
+
+    // Diagnostics, with the timings of operations: RUST_LOG=composefs_experiments=debug, say
+    if let Ok(filter) = EnvFilter::try_from_default_env() {
+        tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .init();
+    }
+
+    let mut repo = (
         if let Some(path) = args.repo {
             Repository::open_path(path)
         } else if args.system {
@@ -81,36 +642,314 @@ fn main() -> Result<()> {
             Repository::open_user()
         }
     )?;
+    if args.insecure {
+        repo.set_insecure(true);
+    }
+    repo.set_zstd_level(args.zstd_level);
+    if args.batch_fsync {
+        repo.set_durability(Durability::Batch);
+    } else if args.no_fsync {
+        repo.set_durability(Durability::None);
+    }
+    let json = args.json;
 
     match args.cmd {
+        Command::Init { salt } => {
+            repo.init(&hex::decode(salt.unwrap_or_default())?)?;
+        },
         Command::Transaction => {
             // just wait for ^C
             loop {
                 std::thread::park();
             }
         },
-        Command::Cat { name } => {
+        Command::Cat { name, zstd: Some(level), .. } => {
+            let mut encoder = zstd::stream::read::Encoder::new(repo.read_splitstream(&name)?, level)?;
+            std::io::copy(&mut encoder, &mut std::io::stdout())?;
+        },
+        Command::Cat { name, offset: None, length: None, .. } => {
             repo.merge_splitstream(&name, &mut std::io::stdout())?;
         },
-        Command::ImportImage { reference, } => {
-            let image_id = repo.import_image(&reference, &mut std::io::stdin())?;
-            println!("{}", hex::encode(image_id));
+        Command::Cat { name, offset, length, .. } => {
+            let offset = offset.unwrap_or(0);
+            let length = length.unwrap_or(u64::MAX);
+            repo.merge_splitstream_range(&name, &mut std::io::stdout(), offset, length)?;
+        },
+        Command::AddFile { path, name } => {
+            let sha256 = repo.add_file(&mut File::open(path)?, name.as_deref())?;
+            report_digest(json, &sha256);
+        },
+        Command::CatStream { name } => {
+            let name = if name.contains('/') { name } else { format!("refs/sha256/{name}") };
+            repo.merge_splitstream(&name, &mut std::io::stdout())?;
+        },
+        Command::ImportImage { reference, signature } => {
+            let signature = signature.map(std::fs::read).transpose()?;
+            let image_id = repo.import_image(&reference, &mut std::io::stdin(), signature.as_deref())?;
+            report_digest(json, &image_id);
+        },
+        Command::ExtractImage { name, target, skip_devices, devices_as_empty } => {
+            let devices = if skip_devices {
+                DevicePolicy::Skip
+            } else if devices_as_empty {
+                DevicePolicy::Empty
+            } else {
+                DevicePolicy::Create
+            };
+            image::extract(&repo, &name, Path::new(&target), devices)?;
+        },
+        Command::ExportBlockImage { name, output, ext4 } => {
+            let format = if ext4 { dmverity::Filesystem::Ext4 } else { dmverity::Filesystem::Erofs };
+            let image = dmverity::export(&repo, &name, format, Path::new(&output))?;
+            report(json, Json::Object(vec![
+                ("root_hash", Json::string(hex::encode(image.root_hash))),
+                ("salt", Json::string(hex::encode(image.salt))),
+                ("data_blocks", Json::Number(image.data_blocks)),
+                ("hash_offset", Json::Number(image.hash_offset)),
+            ]), || {
+                println!("root hash:   {}", hex::encode(image.root_hash));
+                println!("salt:        {}", hex::encode(image.salt));
+                println!("hash offset: {}", image.hash_offset);
+            });
+        },
+        Command::ExportOstree { name, ostree_repo, branch, subject } => {
+            report_digest(json, &ostree::export(&repo, &name, Path::new(&ostree_repo), &branch, &subject)?);
+        },
+        Command::CatFile { name, path } => {
+            image::cat_file(&repo, &name, Path::new(&path), &mut std::io::stdout())?;
+        },
+        Command::Ls { name, path, recursive } => {
+            report_entries(json, &image::ls(&repo, &name, Path::new(&path), recursive)?);
+        },
+        Command::DiffImages { old, new } => {
+            report_changes(json, &image::diff(&repo, &old, &new)?);
+        },
+        Command::Deploy { cmd: Some(deploy_cmd), .. } => match deploy_cmd {
+            DeployCommand::MarkGood { boot_dir } => {
+                let entry = boot::mark_good(Path::new(&boot_dir))?;
+                report_paths(json, "marked_good", entry.as_slice(), "Marked as good:");
+            },
+            DeployCommand::PopulateVar { name, var_dir } => {
+                let populated = boot::populate_var(&repo, &name, Path::new(&var_dir))?;
+                report(json, Json::Object(vec![("populated", Json::Bool(populated))]), || {
+                    if populated {
+                        println!("Populated {var_dir} from {name}");
+                    }
+                });
+            },
+            DeployCommand::Finalize { boot_dir } => {
+                report_paths(json, "activated", &boot::finalize(Path::new(&boot_dir))?, "Activated");
+            },
+        },
+        Command::Deploy { cmd: None, name, boot_dir, options, stage, merge_etc_from, relabel, tries } => {
+            let name = name.expect("required by clap");
+            let merged = match &merge_etc_from {
+                Some(old) => repo.merge_state(old, &name, "etc")?,
+                None => false,
+            };
+            // Undo the merge if the deployment fails, so that running it again doesn't fail because
+            // the new image already has state for /etc
+            let deployed = (|| -> Result<_> {
+                let relabeled = relabel && boot::relabel_state(&repo, &name)?;
+                Ok((relabeled, boot::deploy(&repo, &name, Path::new(&boot_dir), &options, stage, tries)?))
+            })();
+            let (relabeled, entry) = match deployed {
+                Ok(deployed) => deployed,
+                Err(err) if merged => {
+                    repo.remove_state(&name, "etc").context("Failed to undo the merge of /etc")?;
+                    return Err(err);
+                },
+                Err(err) => return Err(err),
+            };
+
+            let value = Json::Object(vec![
+                ("entry", Json::string(entry.display())),
+                ("merged_etc", Json::Bool(merged)),
+                ("relabeled", Json::Bool(relabeled)),
+            ]);
+            report(json, value, || {
+                if let (true, Some(old)) = (merged, &merge_etc_from) {
+                    println!("Merged local changes to /etc from {old}");
+                }
+                if relabeled {
+                    println!("Relabeled the state of {name}");
+                }
+                println!("Wrote {}", entry.display());
+            });
+        },
+        Command::Uki { name, output, options, secureboot_private_key, secureboot_certificate } => {
+            let signing = secureboot_private_key.as_deref().map(Path::new)
+                .zip(secureboot_certificate.as_deref().map(Path::new));
+            boot::build_uki(&repo, &name, Path::new(&output), &options, signing)?;
+        },
+        Command::Streams { cmd: streams_cmd } => match streams_cmd {
+            StreamsCommand::Orphans => {
+                let orphans = repo.orphan_streams()?;
+                let value = Json::Array(orphans.iter().map(|(stream_id, size, n_objects, objects_size)| Json::Object(vec![
+                    ("stream", Json::string(hex::encode(stream_id))),
+                    ("size", Json::Number(*size)),
+                    ("objects", Json::Number(*n_objects as u64)),
+                    ("objects_size", Json::Number(*objects_size)),
+                ])).collect());
+                report(json, value, || {
+                    for (stream_id, size, n_objects, objects_size) in orphans.iter() {
+                        println!(
+                            "{} {size} bytes, references {n_objects} objects ({objects_size} bytes): no ref points to it",
+                            hex::encode(stream_id)
+                        );
+                    }
+                });
+            },
+        },
+        Command::State { cmd: state_cmd } => match state_cmd {
+            StateCommand::Prune => {
+                let pruned = repo.prune_state()?;
+                let value = Json::Object(vec![("removed", Json::Array(pruned.iter().map(Json::string).collect()))]);
+                report(json, value, || {
+                    for digest in pruned.iter() {
+                        println!("Removed state of {digest}");
+                    }
+                });
+            },
+        },
+        Command::Systemd { cmd: systemd_cmd } => match systemd_cmd {
+            SystemdCommand::Generate { image, mountpoint, unit_dir } => {
+                let mut written = vec![];
+                for unit in repo.mount_units(&image, &mountpoint)? {
+                    let path = Path::new(&unit_dir).join(unit.name());
+                    std::fs::write(&path, unit.render())?;
+                    written.push(path);
+                }
+                report_paths(json, "written", &written, "Wrote");
+            },
+        },
+        Command::Sysext { cmd: sysext_cmd } => match sysext_cmd {
+            SysextCommand::Build { source, name, id, version_id, sysext_level, output } => {
+                let source = if Path::new(&source).is_dir() {
+                    sysext::Source::Directory(Path::new(&source))
+                } else {
+                    sysext::Source::Image(&source)
+                };
+                let release = sysext::ExtensionRelease { id, version_id, sysext_level };
+                let digest = sysext::build(&repo, &source, &name, &release, output.as_deref().map(Path::new))?;
+                report_digest(json, &digest);
+            },
         },
         Command::Oci{ cmd: oci_cmd } => match oci_cmd {
             OciCommand::ImportLayer { name } => {
                 let stream_id = oci::import_layer(&repo, &name, &mut std::io::stdin())?;
-                println!("{}", hex::encode(stream_id));
+                report_digest(json, &stream_id);
+            },
+            OciCommand::LsLayer { name, max_depth, ima, selinux, uidmap, gidmap } => {
+                let mut limits = oci::tar::EntryLimits::default();
+                if let Some(max_depth) = max_depth {
+                    limits.max_depth = max_depth;
+                }
+                let contexts = selinux.map(|path| FileContexts::load(Path::new(&path))).transpose()?;
+                let idmap = match uidmap {
+                    Some(uidmap) => {
+                        let uids = IdMap::parse(&uidmap)?;
+                        let gids = gidmap.map(|gidmap| IdMap::parse(&gidmap)).transpose()?.unwrap_or(uids.clone());
+                        Some(IdMapping { uids, gids })
+                    },
+                    None => None,
+                };
+                oci::ls_layer(&repo, &name, &limits, ima, contexts.as_ref(), idmap.as_ref())?;
+            },
+            OciCommand::Diff { old, new, image } => {
+                report_changes(json, &oci::diff_layer(&repo, &old, &new, image, &oci::tar::EntryLimits::default())?);
+            },
+            OciCommand::Bundle { image, dir, extract, env, cwd, tty, args } => {
+                let process = oci::bundle::Process { args, env, cwd, terminal: tty };
+                oci::bundle::create(repo, &image, Path::new(&dir), &process, extract)?;
+            },
+            OciCommand::ExportPodman { store, layers, name } => {
+                let image_id = oci::podman::export(&repo, Path::new(&store), &layers, &name)?;
+                report(json, Json::Object(vec![("id", Json::string(&image_id))]), || println!("{image_id}"));
             },
-            OciCommand::LsLayer { name } => {
-                oci::ls_layer(&repo, &name)?;
+            OciCommand::Squash { name, layers } => {
+                let stream_id = oci::squash(&repo, &name, &layers)?;
+                report_digest(json, &stream_id);
             },
         }
-        Command::Mount { name, mountpoint } => {
-            repo.mount(&name, &mountpoint)?;
+        Command::Mount { name, mountpoint, writable, require_verity } => {
+            repo.mount(&name, &mountpoint, writable, require_verity)?;
+        },
+        Command::ServeVirtiofs { name, socket, virtiofsd, require_verity } => {
+            virtiofs::serve(repo, &name, Path::new(&socket), Path::new(&virtiofsd), require_verity)?;
+        },
+        Command::Unmount { mountpoint, lazy } => {
+            mount::unmount_composefs(&mountpoint, lazy)?;
+        },
+        Command::GC { dry_run, keep_younger_than } => {
+            let garbage = repo.gc(dry_run, keep_younger_than)?;
+            let value = Json::Object(vec![
+                ("dry_run", Json::Bool(dry_run)),
+                ("groups", Json::Array(garbage.iter().map(|group| Json::Object(vec![
+                    ("roots", Json::Array(group.roots.iter().map(Json::string).collect())),
+                    ("objects", Json::Array(group.objects.iter().map(hex::encode).map(Json::String).collect())),
+                    ("size", Json::Number(group.size)),
+                ])).collect())),
+            ]);
+            report(json, value, || {
+                let verb = if dry_run { "Would remove" } else { "Removed" };
+                let (mut total_objects, mut total_size) = (0, 0);
+                for group in garbage.iter() {
+                    let roots = match group.roots.as_slice() {
+                        [] => "unreferenced".to_string(),
+                        roots => roots.join(", "),
+                    };
+                    println!("{roots}: {} objects, {} bytes", group.objects.len(), group.size);
+                    total_objects += group.objects.len();
+                    total_size += group.size;
+                }
+                println!("{verb} {total_objects} objects, {total_size} bytes");
+            });
+        },
+        Command::Pin { name, stream } => {
+            let category = if stream { "streams" } else { "images" };
+            report_digest(json, &repo.pin(category, &name)?);
+        },
+        Command::Unpin { name, stream } => {
+            let category = if stream { "streams" } else { "images" };
+            report_digest(json, &repo.unpin(category, &name)?);
+        },
+        Command::Sign { name, key } => {
+            report_digest(json, &repo.sign_image(&name, &SigningKey::load(Path::new(&key))?)?);
+        },
+        Command::Verify { name, key } => {
+            let keys = if key.is_empty() {
+                repo.trusted_keys()?
+            } else {
+                key.iter().map(|path| PublicKey::load(Path::new(path))).collect::<Result<_>>()?
+            };
+            if keys.is_empty() {
+                bail!("No keys given, and the repository has no trusted keys");
+            }
+            let signer = repo.verify_image_signature(&name, &keys)?;
+            report(json, Json::Object(vec![("key", Json::string(signer.id()))]), || {
+                println!("Signed by {}", signer.id());
+            });
+        },
+        Command::MigrateStreams => {
+            let migrated = repo.migrate_streams()?;
+            report(json, Json::Object(vec![("migrated", Json::Number(migrated as u64))]), || {
+                println!("Migrated {migrated} streams");
+            });
+        },
+        Command::TrainDictionary { max_size } => {
+            let size = repo.train_dictionary(max_size)?;
+            report(json, Json::Object(vec![("size", Json::Number(size as u64))]), || {
+                println!("Trained a dictionary of {size} bytes");
+            });
+        },
+        Command::VerifyObjects { quarantine } => {
+            let failed = repo.verify_objects(quarantine)?;
+            report(json, Json::Object(vec![("failed", Json::Number(failed as u64))]), || {});
+            if failed > 0 {
+                bail!("{failed} objects failed verification");
+            }
         },
-        Command::GC => {
-            repo.gc()?;
-        }
     }
     Ok(())
 }
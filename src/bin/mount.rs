@@ -1,6 +1,10 @@
+use anyhow::Context;
 use clap::Parser;
 
-use composefs_experiments::mount::MountOptions;
+use composefs_experiments::{
+    keyring,
+    mount::MountOptions,
+};
 
 /// mount a composefs
 #[derive(Parser, Debug)]
@@ -17,18 +21,76 @@ struct Args {
 
     #[arg(short, long)]
     digest: Option<String>,
+
+    /// an X.509 certificate (DER) to load into the .fs-verity keyring before mounting
+    #[arg(long)]
+    verity_cert: Option<String>,
+
+    /// refuse files without an fs-verity signature by the certificate
+    #[arg(long, requires = "verity_cert")]
+    require_signatures: bool,
+
+    /// extend this TPM PCR with the digest of the image before mounting it
+    #[arg(long)]
+    tpm_pcr: Option<u32>,
+
+    /// only mount the image if it's signed by one of the public keys (PEM) in this directory
+    #[arg(long, requires = "signatures")]
+    trusted_keys: Option<String>,
+
+    /// the directory with the signatures, as signatures/ in a repository
+    #[arg(long, requires = "trusted_keys")]
+    signatures: Option<String>,
+
+    /// take the trusted keys and the signatures from this repository
+    #[arg(long, conflicts_with_all = ["trusted_keys", "signatures"])]
+    repo: Option<String>,
+}
+
+fn load_verity_cert(path: &str, require_signatures: bool) -> anyhow::Result<()> {
+    let certificate = std::fs::read(path).with_context(|| format!("Failed to read {path}"))?;
+    keyring::load_certificate(&certificate)?;
+    if require_signatures {
+        keyring::require_signatures(&certificate)?;
+    }
+    Ok(())
+}
+
+// The initramfs needs to see a failure to set up trust (or to mount) as a failure
+fn fail(err: anyhow::Error) -> ! {
+    eprintln!("err {:#}", err);
+    std::process::exit(1);
 }
 
 fn main() {
     let args = Args::parse();
 
+    // Signed files can't be opened without the key, so there's no point in mounting without it
+    if let Some(path) = &args.verity_cert {
+        if let Err(x) = load_verity_cert(path, args.require_signatures) {
+            fail(x);
+        }
+    }
+
+    let trust = match (&args.repo, &args.trusted_keys, &args.signatures) {
+        (Some(repo), ..) => Some((format!("{repo}/trusted-keys"), format!("{repo}/signatures"))),
+        (None, Some(trusted_keys), Some(signatures)) => Some((trusted_keys.clone(), signatures.clone())),
+        _ => None,
+    };
+
     let mut options = MountOptions::new(&args.image, &args.basedir);
     if let Some(expected) = &args.digest {
         options.set_digest(expected);
     }
     options.set_require_verity();
+    if let Some(pcr) = args.tpm_pcr {
+        options.set_measure_pcr(pcr);
+    }
+    if let Some((trusted_keys, signatures)) = &trust {
+        options.set_trust_policy(trusted_keys, signatures);
+    }
 
     if let Err(x) = options.mount(&args.mountpoint) {
-        println!("err {}", x);
+        fail(x);
     }
 }
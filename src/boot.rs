@@ -0,0 +1,329 @@
+/* Installing images as boot entries, following the Boot Loader Specification
+ *
+ * The kernel and initramfs are taken from /usr/lib/modules/$version/ in the image, which is where
+ * kernel packages put them for image-based systems, and copied to the boot partition, since the
+ * boot loader can't read them from the repository.  The entry passes the digest of the image as
+ * composefs= on the kernel command line, for the initramfs to mount.
+ *
+ * A deployment can also be staged while the system is running: everything is installed, but the
+ * entry is written under a name that the boot loader ignores.  finalize() (run from a unit which
+ * stops at shutdown) renames staged entries into place, so an update is never half-applied.
+ *
+ * New entries can use automatic boot assessment: they get a counter of tries in their name, which
+ * the boot loader decrements on every boot.  mark_good() removes the counter once the system has
+ * booted successfully, and otherwise the boot loader eventually falls back to an older entry.
+ * See https://systemd.io/AUTOMATIC_BOOT_ASSESSMENT/
+ *
+ * On the first boot of a deployment, the persistent /var is populated from the /var in the image
+ * (which is only a skeleton: the real /var is mounted on top of it).  After that, /var belongs to
+ * the system, and is never touched again, even by later deployments.
+ *
+ * Files extracted from the image keep the SELinux labels that it has in its xattrs, but the
+ * writable state (like the result of merging /etc) can contain files which were created on
+ * another deployment, possibly with another policy.  relabel_state() labels them according to
+ * the policy of the new image.
+ *
+ * Alternatively, the kernel, initramfs and command line can be combined into a Unified Kernel
+ * Image, which can be signed as a whole, extending the chain of trust from Secure Boot to the
+ * content of the root filesystem.
+ *
+ * See https://uapi-group.org/specifications/specs/boot_loader_specification/ and
+ * https://uapi-group.org/specifications/specs/unified_kernel_image/
+ */
+
+use std::{
+    fs::File,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use composefs::dumpfile::Entry;
+
+use crate::{
+    image::{
+        DevicePolicy,
+        extract_subtree,
+        find_entry,
+        write_content,
+    },
+    repository::Repository,
+    tmpdir::TempDir,
+};
+
+// Boot loaders only look at entries ending in .conf
+const STAGED_SUFFIX: &str = ".staged";
+
+// Written to /var once it's been populated
+const VAR_STAMP: &str = ".composefs-populated";
+
+/// Finds the kernel versions which have a vmlinuz in /usr/lib/modules/ in the image.
+fn kernel_versions(dump: &str) -> Result<Vec<String>> {
+    let mut versions = vec![];
+    for line in dump.lines() {
+        let entry = Entry::parse(line)?;
+        let Ok(rest) = entry.path.strip_prefix("/usr/lib/modules") else {
+            continue;
+        };
+        if rest.components().count() == 2 && rest.ends_with("vmlinuz") {
+            if let Some(version) = rest.parent().and_then(Path::to_str) {
+                versions.push(version.to_string());
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Returns the version of the only kernel in the image.
+fn find_kernel(dump: &str) -> Result<String> {
+    match kernel_versions(dump)?.as_slice() {
+        [version] => Ok(version.clone()),
+        [] => bail!("Image doesn't contain a kernel in /usr/lib/modules/"),
+        versions => bail!("Image contains more than one kernel: {}", versions.join(", ")),
+    }
+}
+
+/// Returns PRETTY_NAME from /usr/lib/os-release in the image, if there is one.
+fn pretty_name(repo: &Repository, dump: &str) -> Result<Option<String>> {
+    let Some(entry) = find_entry(dump, Path::new("/usr/lib/os-release"))? else {
+        return Ok(None);
+    };
+
+    let mut content = vec![];
+    write_content(repo, &entry, &mut content)?;
+    Ok(String::from_utf8_lossy(&content).lines().find_map(|line| {
+        let value = line.strip_prefix("PRETTY_NAME=")?;
+        Some(value.trim_matches(['"', '\'']).to_string())
+    }))
+}
+
+/// Copies a regular file from the image to target.
+fn install_file(repo: &Repository, dump: &str, path: &str, target: &Path) -> Result<()> {
+    let Some(entry) = find_entry(dump, Path::new(path))? else {
+        bail!("Image doesn't contain {path}");
+    };
+    let mut file = File::create(target).with_context(|| format!("Cannot create {target:?}"))?;
+    write_content(repo, &entry, &mut file)?;
+    Ok(file.sync_all()?)
+}
+
+/// Installs the kernel and initramfs of the named image to boot (the root of the boot partition)
+/// and writes a boot loader entry for it, with options appended to the kernel command line.  The
+/// image must contain exactly one kernel.  If stage is set, the entry is only activated by the
+/// next call to finalize().  If tries is set, the entry uses boot counting with that many tries.
+/// Returns the path of the entry.
+pub fn deploy(
+    repo: &Repository, name: &str, boot: &Path, options: &str, stage: bool, tries: Option<u32>
+) -> Result<PathBuf> {
+    let digest = hex::encode(repo.image_digest(name)?);
+    let dump = repo.dump_image(name)?;
+    let version = find_kernel(&dump)?;
+
+    // The entry refers to these relative to the root of the boot partition.
+    let linux = format!("/composefs/{digest}/vmlinuz");
+    let initrd = format!("/composefs/{digest}/initramfs.img");
+
+    std::fs::create_dir_all(boot.join(format!("composefs/{digest}")))?;
+    install_file(repo, &dump, &format!("/usr/lib/modules/{version}/vmlinuz"), &boot.join(&linux[1..]))?;
+    install_file(repo, &dump, &format!("/usr/lib/modules/{version}/initramfs.img"), &boot.join(&initrd[1..]))?;
+
+    let title = pretty_name(repo, &dump)?.unwrap_or_else(|| format!("composefs {}", &digest[..12]));
+    let entry = format!(
+        "title {title}\nversion {version}\nlinux {linux}\ninitrd {initrd}\noptions {}\n",
+        format!("composefs={digest} {options}").trim_end()
+    );
+
+    // Write the entry last, and atomically, so that a partial deployment never shows up in the
+    // boot menu.
+    let entries = boot.join("loader/entries");
+    std::fs::create_dir_all(&entries)?;
+    let counter = tries.map(|tries| format!("+{tries}")).unwrap_or_default();
+    let suffix = if stage { STAGED_SUFFIX } else { "" };
+    let path = entries.join(format!("composefs-{digest}{counter}.conf{suffix}"));
+    let tmp = entries.join(format!(".composefs-{digest}.conf.tmp"));
+    std::fs::write(&tmp, entry)?;
+    File::open(&tmp)?.sync_all()?;
+    std::fs::rename(&tmp, &path)?;
+
+    Ok(path)
+}
+
+/// Activates all staged entries on the boot partition.  Returns their new paths.
+pub fn finalize(boot: &Path) -> Result<Vec<PathBuf>> {
+    let entries = boot.join("loader/entries");
+    let mut finalized = vec![];
+
+    for item in std::fs::read_dir(&entries).with_context(|| format!("Cannot read {entries:?}"))? {
+        let staged = item?.path();
+        let Some(name) = staged.to_str().and_then(|name| name.strip_suffix(STAGED_SUFFIX)) else {
+            continue;
+        };
+        let path = PathBuf::from(name);
+        std::fs::rename(&staged, &path)?;
+        finalized.push(path);
+    }
+
+    if !finalized.is_empty() {
+        File::open(&entries)?.sync_all()?;
+    }
+    Ok(finalized)
+}
+
+/// Marks the entry of the running deployment (according to composefs= on the kernel command
+/// line) as good, by removing the boot counter from its name.  Returns the new path of the entry,
+/// or None if it wasn't using boot counting.
+pub fn mark_good(boot: &Path) -> Result<Option<PathBuf>> {
+    let cmdline = std::fs::read_to_string("/proc/cmdline")?;
+    let Some(digest) = cmdline.split_whitespace().find_map(|arg| arg.strip_prefix("composefs=")) else {
+        bail!("Not booted from a composefs image (no composefs= on the kernel command line)");
+    };
+
+    let entries = boot.join("loader/entries");
+    let prefix = format!("composefs-{digest}+");
+    for item in std::fs::read_dir(&entries).with_context(|| format!("Cannot read {entries:?}"))? {
+        let entry = item?.path();
+        let Some(filename) = entry.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // the counter is +LEFT or +LEFT-DONE
+        let Some(counter) = filename.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(".conf")) else {
+            continue;
+        };
+        if !counter.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            continue;
+        }
+
+        let path = entries.join(format!("composefs-{digest}.conf"));
+        std::fs::rename(&entry, &path)?;
+        File::open(&entries)?.sync_all()?;
+        return Ok(Some(path));
+    }
+
+    Ok(None)
+}
+
+/// Populates var (the persistent /var) from the /var in the named image, unless that was done
+/// before.  Returns false if there was nothing to do.
+pub fn populate_var(repo: &Repository, name: &str, var: &Path) -> Result<bool> {
+    let stamp = var.join(VAR_STAMP);
+    if stamp.try_exists()? {
+        return Ok(false);
+    }
+
+    extract_subtree(repo, name, Path::new("/var"), var, DevicePolicy::Create)?;
+    File::create(&stamp)?.sync_all()?;
+    Ok(true)
+}
+
+/// Returns the path of the file_contexts of the SELinux policy in the image.
+fn file_contexts_path(repo: &Repository, dump: &str) -> Result<String> {
+    let Some(entry) = find_entry(dump, Path::new("/etc/selinux/config"))? else {
+        bail!("Image doesn't have an SELinux policy");
+    };
+
+    let mut content = vec![];
+    write_content(repo, &entry, &mut content)?;
+    let Some(policy) = String::from_utf8_lossy(&content).lines().find_map(|line| {
+        Some(line.strip_prefix("SELINUXTYPE=")?.trim().to_string())
+    }) else {
+        bail!("SELINUXTYPE isn't set in /etc/selinux/config in the image");
+    };
+
+    Ok(format!("/etc/selinux/{policy}/contexts/files/file_contexts"))
+}
+
+fn run_setfiles(repo: &Repository, name: &str, dir: &Path, upper: &Path) -> Result<()> {
+    let dump = repo.dump_image(name)?;
+    let file_contexts = file_contexts_path(repo, &dump)?;
+
+    // setfiles also reads the .local and .homedirs files next to file_contexts, if they exist
+    let target = dir.join("file_contexts");
+    install_file(repo, &dump, &file_contexts, &target)?;
+    for suffix in [".local", ".homedirs"] {
+        let path = format!("{file_contexts}{suffix}");
+        if find_entry(&dump, Path::new(&path))?.is_some() {
+            install_file(repo, &dump, &path, &dir.join(format!("file_contexts{suffix}")))?;
+        }
+    }
+
+    // the upper directory corresponds to / in the image, so that's the alternate root
+    let status = Command::new("setfiles")
+        .arg("-F")
+        .arg("-r").arg(upper)
+        .arg(&target)
+        .arg(upper)
+        .status()
+        .context("Failed to run setfiles (is policycoreutils installed?)")?;
+    if !status.success() {
+        bail!("setfiles failed: {status}");
+    }
+    Ok(())
+}
+
+/// Relabels the writable state of the named image according to the SELinux policy in the image.
+/// Returns false if the image has no state.
+pub fn relabel_state(repo: &Repository, name: &str) -> Result<bool> {
+    let Some(upper) = repo.state_upperdir(name)? else {
+        return Ok(false);
+    };
+
+    let tmp = TempDir::new()?;
+    run_setfiles(repo, name, &tmp.path, &upper)?;
+    Ok(true)
+}
+
+fn run_ukify(
+    repo: &Repository, name: &str, dir: &Path, output: &Path, options: &str, signing: Option<(&Path, &Path)>
+) -> Result<()> {
+    let digest = hex::encode(repo.image_digest(name)?);
+    let dump = repo.dump_image(name)?;
+    let version = find_kernel(&dump)?;
+
+    let linux = dir.join("vmlinuz");
+    let initrd = dir.join("initramfs.img");
+    install_file(repo, &dump, &format!("/usr/lib/modules/{version}/vmlinuz"), &linux)?;
+    install_file(repo, &dump, &format!("/usr/lib/modules/{version}/initramfs.img"), &initrd)?;
+
+    let mut ukify = Command::new("ukify");
+    ukify.arg("build")
+        .arg(format!("--linux={}", linux.display()))
+        .arg(format!("--initrd={}", initrd.display()))
+        .arg(format!("--cmdline={}", format!("composefs={digest} {options}").trim_end()))
+        .arg(format!("--uname={version}"))
+        .arg(format!("--output={}", output.display()));
+
+    if find_entry(&dump, Path::new("/usr/lib/os-release"))?.is_some() {
+        let os_release = dir.join("os-release");
+        install_file(repo, &dump, "/usr/lib/os-release", &os_release)?;
+        ukify.arg(format!("--os-release=@{}", os_release.display()));
+    }
+
+    if let Some((key, certificate)) = signing {
+        ukify.arg(format!("--secureboot-private-key={}", key.display()))
+            .arg(format!("--secureboot-certificate={}", certificate.display()));
+    }
+
+    let status = ukify.status().context("Failed to run ukify (is systemd-ukify installed?)")?;
+    if !status.success() {
+        bail!("ukify failed: {status}");
+    }
+    Ok(())
+}
+
+/// Builds a Unified Kernel Image from the kernel and initramfs of the named image, with
+/// composefs= and options on the kernel command line, and writes it to output.  If signing is
+/// given, it's a (key, certificate) pair used for signing the UKI for Secure Boot.  This uses
+/// ukify from systemd.
+pub fn build_uki(
+    repo: &Repository, name: &str, output: &Path, options: &str, signing: Option<(&Path, &Path)>
+) -> Result<()> {
+    let tmp = TempDir::new()?;
+    run_ukify(repo, name, &tmp.path, output, options, signing)
+}
@@ -0,0 +1,114 @@
+/* Content-defined chunking (FastCDC) for storing arbitrary files as Split Streams
+ *
+ * Fixed-size chunks are useless for deduplication as soon as something gets inserted near the
+ * start of a file: every following chunk boundary moves.  Content-defined chunk boundaries
+ * depend only on the bytes just before them, so they survive insertions and deletions, and two
+ * versions of a large file (a disk image, say) end up sharing most of their objects.
+ *
+ * See "FastCDC: a Fast and Efficient Content-Defined Chunking Approach for Data Deduplication"
+ * (Xia et al., USENIX ATC 2016).
+ */
+
+use std::io::{
+    Read,
+    Write,
+};
+
+use anyhow::Result;
+
+use crate::{
+    fsverity::Sha256HashValue,
+    splitstream::SplitStreamWriter,
+};
+
+pub const MIN_SIZE: usize = 32 * 1024;
+pub const AVG_SIZE: usize = 128 * 1024;
+pub const MAX_SIZE: usize = 512 * 1024;
+
+// Chunks smaller than this (which can only happen at the end of the file) are stored inline.
+const INLINE_MAX: usize = 4096;
+
+// Normalized chunking: before reaching the average size we use a mask with more bits set (making
+// a cut less likely) and afterwards one with fewer, which narrows the distribution of chunk sizes.
+// A bit of the gear hash depends on that many of the most recent bytes, so we take the top bits.
+const AVG_BITS: u32 = AVG_SIZE.trailing_zeros();
+const MASK_SMALL: u64 = !(u64::MAX >> (AVG_BITS + 2));
+const MASK_LARGE: u64 = !(u64::MAX >> (AVG_BITS - 2));
+
+// 256 random 64-bit values.  These determine where the chunk boundaries end up, so they can never
+// change: doing so would break deduplication against everything stored before.
+const GEAR: [u64; 256] = {
+    // splitmix64, with a fixed seed
+    let mut table = [0u64; 256];
+    let mut state = 0x636f6d706f736566u64;  // "composef"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Returns the size of the first chunk of data.  Unless data is shorter than MAX_SIZE (ie: this
+/// is the end of the file), the result doesn't depend on the amount of data passed in.
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_SIZE);
+    let normal = end.min(AVG_SIZE);
+    let mut hash = 0u64;
+
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    end
+}
+
+/// Splits the content of input into content-defined chunks and writes it as a Split Stream.  Like
+/// for tar::split(), the store_data function is responsible for ensuring that the chunks are in
+/// the composefs repository and returns their fsverity hash value.
+pub fn split<R: Read, W: Write, F: FnMut(Vec<u8>) -> Result<Sha256HashValue>>(
+    input: &mut R,
+    split_stream: &mut W,
+    mut store_data: F,
+) -> Result<()> {
+    let mut writer = SplitStreamWriter::new(split_stream);
+    let mut buffer = Vec::with_capacity(2 * MAX_SIZE);
+
+    loop {
+        // Top up the buffer so that we can see a full MAX_SIZE ahead, or until the end of input.
+        let wanted = (MAX_SIZE - buffer.len().min(MAX_SIZE)) as u64;
+        input.by_ref().take(wanted).read_to_end(&mut buffer)?;
+
+        if buffer.is_empty() {
+            break;
+        }
+
+        let size = cut_point(&buffer);
+        if size < INLINE_MAX {
+            writer.write_inline(&buffer[..size]);
+            buffer.drain(..size);
+        } else {
+            // Hand over the chunk itself, and keep the rest: that copies the same amount of data
+            // as drain() would, and store_data() doesn't need to make a copy of the chunk.
+            let mut rest = Vec::with_capacity(2 * MAX_SIZE);
+            rest.extend_from_slice(&buffer[size..]);
+            buffer.truncate(size);
+            let reference = store_data(std::mem::replace(&mut buffer, rest))?;
+            writer.write_reference(reference, vec![])?;
+        }
+    }
+
+    writer.done()
+}
@@ -0,0 +1,210 @@
+/* Raw block images protected by dm-verity
+ *
+ * Instead of an erofs image with the file content in the repository (mounted with overlayfs),
+ * some deployments want a single block device with everything in it, protected as a whole by
+ * dm-verity.  We build that by extracting the image, making a filesystem from it (erofs with
+ * mkfs.erofs, or ext4 with mkfs.ext4 -d), and appending a dm-verity hash tree to the result, in
+ * the format that veritysetup writes:
+ *
+ *     [ data | padding to 4096 | superblock (4096) | hash tree, top level first ]
+ *
+ * The hash of a block is sha256(salt || block), and the root hash is the hash of the top level of
+ * the tree.  The image can be opened with:
+ *
+ *     veritysetup open image.raw name image.raw <root hash> --hash-offset=<hash offset>
+ */
+
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        BufReader,
+        Read,
+        Seek,
+        SeekFrom,
+        Write,
+    },
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use rand::RngCore;
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use crate::{
+    image::{
+        self,
+        DevicePolicy,
+    },
+    progress::Progress,
+    repository::Repository,
+    tmpdir::TempDir,
+};
+
+const BLOCK_SIZE: u64 = 4096;
+const HASHES_PER_BLOCK: usize = BLOCK_SIZE as usize / 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    Erofs,
+    Ext4,
+}
+
+/// What's needed to open a block image with veritysetup (or to write a dm-verity table for it).
+#[derive(Clone, Debug)]
+pub struct VerityImage {
+    pub root_hash: [u8; 32],
+    pub salt: [u8; 32],
+    /// The number of 4096 byte data blocks
+    pub data_blocks: u64,
+    /// Where the superblock of the hash tree is, in bytes
+    pub hash_offset: u64,
+}
+
+fn hash_block(salt: &[u8], block: &[u8]) -> [u8; 32] {
+    let mut context = Sha256::new();
+    context.update(salt);
+    context.update(block);
+    context.finalize().into()
+}
+
+// The hashes of the blocks of one level, packed into the (zero-padded) blocks of the next one
+fn pack_level(hashes: &[[u8; 32]]) -> Vec<u8> {
+    let n_blocks = hashes.len().div_ceil(HASHES_PER_BLOCK);
+    let mut level = hashes.concat();
+    level.resize(n_blocks * BLOCK_SIZE as usize, 0);
+    level
+}
+
+fn superblock(salt: &[u8], uuid: &[u8; 16], data_blocks: u64) -> Vec<u8> {
+    let mut sb = Vec::with_capacity(BLOCK_SIZE as usize);
+    sb.extend_from_slice(b"verity\0\0");
+    sb.extend_from_slice(&1u32.to_le_bytes());  // version
+    sb.extend_from_slice(&1u32.to_le_bytes());  // hash type: salt first, like veritysetup
+    sb.extend_from_slice(uuid);
+    let mut algorithm = [0u8; 32];
+    algorithm[..6].copy_from_slice(b"sha256");
+    sb.extend_from_slice(&algorithm);
+    sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());  // data block size
+    sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());  // hash block size
+    sb.extend_from_slice(&data_blocks.to_le_bytes());
+    sb.extend_from_slice(&(salt.len() as u16).to_le_bytes());
+    sb.extend_from_slice(&[0; 6]);
+    let mut salt_field = [0u8; 256];
+    salt_field[..salt.len()].copy_from_slice(salt);
+    sb.extend_from_slice(&salt_field);
+    sb.resize(BLOCK_SIZE as usize, 0);
+    sb
+}
+
+/// Appends a dm-verity hash tree (with a superblock) to the file, after padding it to a multiple
+/// of the block size.
+pub fn append_hash_tree(file: &mut File) -> Result<VerityImage> {
+    let mut salt = [0u8; 32];
+    let mut uuid = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut uuid);
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;  // a version 4 UUID
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    let size = file.metadata()?.size();
+    if size == 0 {
+        bail!("Can't protect an empty image with dm-verity");
+    }
+    let data_blocks = size.div_ceil(BLOCK_SIZE);
+    let hash_offset = data_blocks * BLOCK_SIZE;
+    file.set_len(hash_offset)?;
+
+    let mut progress = Progress::new("Hashing", "blocks", Some(data_blocks));
+    let mut hashes = Vec::with_capacity(data_blocks as usize);
+    let mut reader = BufReader::new(&*file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut block = vec![0u8; BLOCK_SIZE as usize];
+    for _ in 0..data_blocks {
+        reader.read_exact(&mut block)?;
+        hashes.push(hash_block(&salt, &block));
+        progress.inc(1);
+    }
+    drop(reader);
+    drop(progress);
+
+    // Build the levels from the bottom up, until one fits in a single block
+    let mut levels = vec![pack_level(&hashes)];
+    while levels.last().unwrap().len() > BLOCK_SIZE as usize {
+        let level = levels.last().unwrap();
+        let hashes: Vec<_> = level.chunks(BLOCK_SIZE as usize).map(|block| hash_block(&salt, block)).collect();
+        levels.push(pack_level(&hashes));
+    }
+    let root_hash = hash_block(&salt, levels.last().unwrap());
+
+    file.seek(SeekFrom::Start(hash_offset))?;
+    file.write_all(&superblock(&salt, &uuid, data_blocks))?;
+    for level in levels.iter().rev() {
+        file.write_all(level)?;
+    }
+
+    Ok(VerityImage { root_hash, salt, data_blocks, hash_offset })
+}
+
+// Room for the content, with some slack for the metadata of ext4
+fn ext4_size(root: &Path) -> Result<u64> {
+    fn walk(path: &Path) -> Result<u64> {
+        let mut total = BLOCK_SIZE;
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            total += if metadata.is_dir() {
+                walk(&entry.path())?
+            } else {
+                metadata.len().next_multiple_of(BLOCK_SIZE) + BLOCK_SIZE
+            };
+        }
+        Ok(total)
+    }
+    Ok(walk(root)? * 5 / 4 + (32 << 20))
+}
+
+fn run_mkfs(format: Filesystem, root: &Path, output: &Path) -> Result<()> {
+    let mut command = match format {
+        Filesystem::Erofs => {
+            let mut command = Command::new("mkfs.erofs");
+            command.arg(output).arg(root);
+            command
+        },
+        Filesystem::Ext4 => {
+            File::create(output)?.set_len(ext4_size(root)?)?;
+            let mut command = Command::new("mkfs.ext4");
+            command.args(["-q", "-F", "-b", "4096", "-d"]).arg(root).arg(output);
+            command
+        },
+    };
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command.status().with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        bail!("{program} failed: {status}");
+    }
+    Ok(())
+}
+
+/// Writes the content of the named image to output as a filesystem of the given type, followed
+/// by a dm-verity hash tree.
+pub fn export(repo: &Repository, name: &str, format: Filesystem, output: &Path) -> Result<VerityImage> {
+    let tmp = TempDir::new()?;
+    let root = tmp.path.join("root");
+
+    std::fs::create_dir(&root)?;
+    image::extract(repo, name, &root, DevicePolicy::Create)?;
+    run_mkfs(format, &root, output)?;
+    append_hash_tree(&mut OpenOptions::new().read(true).write(true).open(output)?)
+}
@@ -0,0 +1,55 @@
+/* Errors which callers might want to handle
+ *
+ * The library returns anyhow::Result throughout, which is what we want for the messages: every
+ * layer adds its bit of context.  Some failures need to be told apart by programs, though: an
+ * update daemon will want to fetch an object that's missing, but not one which is corrupted.
+ * Those failures carry one of the errors below, which can be found from the anyhow::Error with
+ * downcast_ref(), no matter how much context was added to it:
+ *
+ *     match err.downcast_ref::<composefs_experiments::Error>() {
+ *         Some(Error::NotFound(..)) => ...,
+ *         ...
+ *     }
+ *
+ * Errors from system calls can be found in the same way, as rustix::io::Errno or std::io::Error.
+ */
+
+use std::fmt;
+
+use crate::fsverity::Sha256HashValue;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// An object, stream or image which isn't in the repository (the path of what was looked up)
+    NotFound(String),
+    /// Content with the wrong fs-verity digest: corrupted, or not what it's claimed to be
+    DigestMismatch {
+        what: String,
+        expected: Sha256HashValue,
+        measured: Sha256HashValue,
+    },
+    /// The filesystem doesn't support fs-verity, and the repository isn't in insecure mode
+    VerityUnsupported,
+    /// A stream or an archive which can't be read (the details of what's wrong with it)
+    InvalidFormat(String),
+    /// An image without a valid signature by any of the trusted keys
+    Untrusted(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound(path) => write!(f, "{path} doesn't exist in the repository"),
+            Error::DigestMismatch { what, expected, measured } => write!(
+                f, "{what} has fs-verity digest {} but {} was expected", hex::encode(measured), hex::encode(expected)
+            ),
+            Error::VerityUnsupported => write!(
+                f, "Filesystem doesn't support fs-verity (is the verity feature enabled? see also --insecure)"
+            ),
+            Error::InvalidFormat(details) => write!(f, "{details}"),
+            Error::Untrusted(what) => write!(f, "{what} isn't signed by any of the trusted keys"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
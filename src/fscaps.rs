@@ -0,0 +1,150 @@
+/* File capabilities (the security.capability xattr)
+ *
+ * The xattr is a little-endian struct vfs_cap_data (see linux/capability.h): a magic number with
+ * the revision and the effective flag, followed by the permitted and inheritable sets, as one
+ * 32-bit word each (revision 1) or two (revisions 2 and 3).  Revision 3 adds a rootid: the
+ * capabilities only apply in user namespaces whose root is that uid.  The kernel writes those when
+ * a process in a user namespace sets capabilities, which is how they end up in layers built by
+ * rootless container tools.
+ *
+ * We store the xattr exactly as it is in the layer, so that getcap on a mounted image shows what
+ * it shows in the container the layer came from.  This is for checking that it's well-formed
+ * (mkcomposefs and the kernel would take a bogus value as it is, and then the kernel would ignore
+ * it), and for rewriting the rootid when the IDs of a tree are remapped (see idmap.rs).
+ */
+
+use anyhow::{
+    Result,
+    bail,
+};
+
+pub const XATTR_NAME: &str = "security.capability";
+
+const REVISION_MASK: u32 = 0xff000000;
+const FLAG_EFFECTIVE: u32 = 0x000001;
+const REVISION_1: u32 = 0x01000000;
+const REVISION_2: u32 = 0x02000000;
+const REVISION_3: u32 = 0x03000000;
+
+/// The content of a security.capability xattr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileCaps {
+    /// Whether the permitted capabilities are raised in the effective set on execve()
+    pub effective: bool,
+    pub permitted: u64,
+    pub inheritable: u64,
+    /// For revision 3: the uid which is root in the user namespaces the capabilities apply in
+    pub rootid: Option<u32>,
+    /// Whether this was a revision 1 xattr, which only has the lower 32 capabilities
+    pub v1: bool,
+}
+
+impl FileCaps {
+    pub fn parse(value: &[u8]) -> Result<FileCaps> {
+        let word = |i: usize| u32::from_le_bytes(value[i * 4..i * 4 + 4].try_into().unwrap());
+        if value.len() < 4 {
+            bail!("security.capability is too short ({} bytes)", value.len());
+        }
+        let magic = word(0);
+        if magic & !REVISION_MASK & !FLAG_EFFECTIVE != 0 {
+            bail!("security.capability has unknown flags ({magic:#010x})");
+        }
+
+        let revision = magic & REVISION_MASK;
+        let expected_len = match revision {
+            REVISION_1 => 12,
+            REVISION_2 => 20,
+            REVISION_3 => 24,
+            _ => bail!("security.capability has unknown revision {}", revision >> 24),
+        };
+        if value.len() != expected_len {
+            bail!("security.capability has the wrong size for revision {} ({} bytes)", revision >> 24, value.len());
+        }
+
+        let effective = magic & FLAG_EFFECTIVE != 0;
+        let v1 = revision == REVISION_1;
+        let rootid = (revision == REVISION_3).then(|| word(5));
+        let (permitted, inheritable) = if v1 {
+            (word(1) as u64, word(2) as u64)
+        } else {
+            (word(1) as u64 | (word(3) as u64) << 32, word(2) as u64 | (word(4) as u64) << 32)
+        };
+        Ok(FileCaps { effective, permitted, inheritable, rootid, v1 })
+    }
+
+    /// Encodes the capabilities as a security.capability xattr, in the same revision as it was
+    /// parsed from, unless that can't represent them: revision 3 if a rootid was set, and revision
+    /// 2 if a revision 1 value got capabilities above 31.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let fits_v1 = (self.permitted | self.inheritable) >> 32 == 0;
+        let revision = match (self.rootid, self.v1) {
+            (Some(..), _) => REVISION_3,
+            (None, true) if fits_v1 => REVISION_1,
+            (None, _) => REVISION_2,
+        };
+        let magic = revision | if self.effective { FLAG_EFFECTIVE } else { 0 };
+
+        let mut words = vec![magic, self.permitted as u32, self.inheritable as u32];
+        if revision != REVISION_1 {
+            words.extend([(self.permitted >> 32) as u32, (self.inheritable >> 32) as u32]);
+        }
+        words.extend(self.rootid);
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        // What setcap writes for cap_net_raw+ep (revision 2), and the same in the older format,
+        // and with a rootid, like a process in a user namespace gets
+        for value in [
+            words(&[0x02000001, 1 << 13, 0, 0, 0]),
+            words(&[0x01000001, 1 << 13, 0]),
+            words(&[0x03000001, 1 << 13, 0, 0, 0, 100000]),
+            words(&[0x02000000, 0xffffffff, 0x1, 0x1ff, 0x100]),
+            words(&[0x03000000, 0, 1 << 2, 1 << 8, 0, 0]),
+        ] {
+            assert_eq!(FileCaps::parse(&value).unwrap().to_bytes(), value);
+        }
+
+        let caps = FileCaps::parse(&words(&[0x03000000, 1, 2, 3, 4, 5])).unwrap();
+        assert_eq!(caps, FileCaps { effective: false, permitted: 3 << 32 | 1, inheritable: 4 << 32 | 2, rootid: Some(5), v1: false });
+    }
+
+    #[test]
+    fn wrong_size() {
+        assert!(FileCaps::parse(&[]).is_err());
+        assert!(FileCaps::parse(&[1, 0, 0]).is_err());
+        assert!(FileCaps::parse(&words(&[0x01000000, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x01000000, 0, 0, 0, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x02000000, 0, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x02000000, 0, 0, 0, 0, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x03000000, 0, 0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(FileCaps::parse(&words(&[0x00000000, 0, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x04000000, 0, 0, 0, 0, 0])).is_err());
+        assert!(FileCaps::parse(&words(&[0x02000002, 0, 0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn v1_upgrade() {
+        // Revision 1 can't hold capabilities above 31 (like cap_bpf, 39)
+        let mut caps = FileCaps::parse(&words(&[0x01000001, 1 << 13, 0])).unwrap();
+        caps.permitted |= 1 << 39;
+        assert_eq!(caps.to_bytes(), words(&[0x02000001, 1 << 13, 0, 1 << 7, 0]));
+        caps.permitted = 0;
+        caps.inheritable = 1 << 39;
+        assert_eq!(caps.to_bytes(), words(&[0x02000001, 0, 0, 0, 1 << 7]));
+    }
+}
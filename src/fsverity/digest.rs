@@ -1,17 +1,28 @@
-use super::Sha256HashValue;
-use sha2::{Sha256, Digest};
-use std::cmp::min;
+use super::FsVerityHashValue;
+use anyhow::Result;
+use rustix::io::pread;
+use sha2::{
+    Digest,
+    digest::core_api::BlockSizeUser,
+};
+use std::{
+    cmp::min,
+    os::fd::AsFd,
+};
 
-// TODO: support Sha512
+// Buffers smaller than this are hashed on the calling thread: it's not worth the overhead.
+const PARALLEL_THRESHOLD: usize = 1 << 20;
 
-struct FsVerityLayer {
-    context: Sha256,
+struct FsVerityLayer<H: FsVerityHashValue> {
+    context: H::Digest,
+    initial: H::Digest,
     remaining: usize,
 }
 
-impl FsVerityLayer {
-    fn new() -> FsVerityLayer {
-        FsVerityLayer { context: Sha256::new(), remaining: 4096 }
+impl<H: FsVerityHashValue> FsVerityLayer<H> {
+    /// initial is the hash context that each block starts from: it has the salt already added.
+    fn new(initial: &H::Digest) -> FsVerityLayer<H> {
+        FsVerityLayer { context: initial.clone(), initial: initial.clone(), remaining: 4096 }
     }
 
     fn add_data(&mut self, data: &[u8]) {
@@ -19,56 +30,158 @@ impl FsVerityLayer {
         self.remaining -= data.len();
     }
 
-    fn complete(&mut self) -> Sha256HashValue {
+    fn complete(&mut self) -> H {
         self.context.update(&[0u8; 4096][..self.remaining]);
         self.remaining = 4096;
-        self.context.finalize_reset().into()
+        let context = std::mem::replace(&mut self.context, self.initial.clone());
+        let mut value = H::EMPTY;
+        value.as_mut().copy_from_slice(&context.finalize());
+        value
     }
 }
 
-pub struct FsVerityHasher {
-    layers: Vec<FsVerityLayer>,
-    value: Option<Sha256HashValue>,
+pub struct FsVerityHasher<H: FsVerityHashValue> {
+    layers: Vec<FsVerityLayer<H>>,
+    value: Option<H>,
     n_bytes: u64,
+    salt: Vec<u8>,
+    initial: H::Digest,
+    pending: Vec<u8>,
 }
 
-impl FsVerityHasher {
-    pub fn hash(buffer: &[u8]) -> Sha256HashValue {
-        let mut hasher = FsVerityHasher::new();
+impl<H: FsVerityHashValue> FsVerityHasher<H> {
+    pub fn hash(buffer: &[u8]) -> H {
+        FsVerityHasher::hash_with_salt(buffer, &[])
+    }
+
+    pub fn hash_with_salt(buffer: &[u8], salt: &[u8]) -> H {
+        let mut hasher = FsVerityHasher::<H>::new_with_salt(salt);
+
+        // The hashes of the data blocks are independent of each other, so for large buffers we
+        // compute them on several threads.  The (much smaller) upper layers of the tree are built
+        // from those afterwards.
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if n_threads > 1 && buffer.len() >= PARALLEL_THRESHOLD {
+            let n_blocks = buffer.len().div_ceil(4096);
+            let chunk_size = n_blocks.div_ceil(n_threads) * 4096;
 
-        let mut start = 0;
-        while start < buffer.len() {
-            let end = min(start + 4096, buffer.len());
-            hasher.add_data(&buffer[start..end]);
-            start = end;
+            let block_hashes: Vec<Vec<H>> = std::thread::scope(|scope| {
+                let threads: Vec<_> = buffer.chunks(chunk_size).map(|chunk| {
+                    let initial = hasher.initial.clone();
+                    scope.spawn(move || chunk.chunks(4096).map(|block| FsVerityHasher::hash_block(&initial, block)).collect())
+                }).collect();
+                threads.into_iter().map(|thread| thread.join().expect("hashing thread panicked")).collect()
+            });
+
+            let mut remaining = buffer.len();
+            for value in block_hashes.into_iter().flatten() {
+                let size = min(remaining, 4096);
+                hasher.add_block_hash(value, size);
+                remaining -= size;
+            }
+        } else {
+            let mut start = 0;
+            while start < buffer.len() {
+                let end = min(start + 4096, buffer.len());
+                hasher.add_data(&buffer[start..end]);
+                start = end;
+            }
         }
 
         hasher.digest()
     }
 
-    pub fn new() -> FsVerityHasher {
-        FsVerityHasher { layers: vec![], value: None, n_bytes: 0 }
+    /// Computes the fs-verity digest of the content of a file in userspace, for filesystems which
+    /// don't support fs-verity.  The file offset isn't changed.
+    pub fn hash_fd<F: AsFd>(fd: F, salt: &[u8]) -> Result<H> {
+        let mut hasher = FsVerityHasher::<H>::new_with_salt(salt);
+        let mut buffer = [0u8; 65536];
+        let mut offset = 0;
+
+        loop {
+            match pread(&fd, &mut buffer, offset)? {
+                0 => break,
+                n => {
+                    hasher.update(&buffer[..n]);
+                    offset += n as u64;
+                }
+            }
+        }
+
+        Ok(hasher.digest())
+    }
+
+    pub fn new() -> FsVerityHasher<H> {
+        FsVerityHasher::new_with_salt(&[])
+    }
+
+    /// The salt is at most 32 bytes.  It's prepended to every block that gets hashed, padded to
+    /// the block size of the hash function, and recorded in the descriptor.
+    pub fn new_with_salt(salt: &[u8]) -> FsVerityHasher<H> {
+        assert!(salt.len() <= 32, "fs-verity salt can be at most 32 bytes");
+
+        let mut initial = H::Digest::new();
+        if !salt.is_empty() {
+            let padded_size = salt.len().next_multiple_of(H::Digest::block_size());
+            initial.update(salt);
+            initial.update(vec![0u8; padded_size - salt.len()]);
+        }
+
+        FsVerityHasher {
+            layers: vec![], value: None, n_bytes: 0, salt: salt.to_vec(), initial, pending: vec![]
+        }
+    }
+
+    /// Adds data of any size to the hash.  Incomplete blocks are kept until more data arrives or
+    /// the digest is requested.  Don't mix this with add_data().
+    pub fn update(&mut self, mut data: &[u8]) {
+        if !self.pending.is_empty() {
+            let wanted = min(4096 - self.pending.len(), data.len());
+            self.pending.extend_from_slice(&data[..wanted]);
+            data = &data[wanted..];
+
+            if self.pending.len() < 4096 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.add_data(&block);
+        }
+
+        let mut blocks = data.chunks_exact(4096);
+        for block in blocks.by_ref() {
+            self.add_data(block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
     }
 
+    fn hash_block(initial: &H::Digest, data: &[u8]) -> H {
+        let mut context = FsVerityLayer::<H>::new(initial);
+        context.add_data(data);
+        context.complete()
+    }
+
+    /// Adds the next data block, which must be 4096 bytes, except for the last block.
+    /// See update() for adding data in arbitrary amounts.
     pub fn add_data(&mut self, data: &[u8]) {
+        self.add_block_hash(FsVerityHasher::hash_block(&self.initial, data), data.len());
+    }
+
+    /// Adds the hash of the next data block (of the given size) to the tree.
+    fn add_block_hash(&mut self, mut value: H, size: usize) {
         if let Some(value) = self.value {
             // We had a complete value, but now we're adding new data.
             // This means that we need to add a new hash layer...
-            let mut new_layer = FsVerityLayer::new();
-            new_layer.add_data(&value);
+            let mut new_layer = FsVerityLayer::new(&self.initial);
+            new_layer.add_data(value.as_ref());
             self.layers.push(new_layer);
             self.value = None;
         }
 
-        // Get the value of this block
-        let mut context = FsVerityLayer::new();
-        context.add_data(data);
-        let mut value = context.complete();
-        self.n_bytes += data.len() as u64;
+        self.n_bytes += size as u64;
 
         for layer in self.layers.iter_mut() {
             // We have a layer we need to hash this value into
-            layer.add_data(&value);
+            layer.add_data(value.as_ref());
             if layer.remaining != 0 {
                 return;
             }
@@ -80,22 +193,27 @@ impl FsVerityHasher {
         self.value = Some(value);
     }
 
-    pub fn root_hash(&mut self) -> Sha256HashValue {
+    pub fn root_hash(&mut self) -> H {
+        if !self.pending.is_empty() {
+            let block = std::mem::take(&mut self.pending);
+            self.add_data(&block);
+        }
+
         if let Some(value) = self.value {
             value
         } else {
-            let mut value = [0u8; 32];
+            let mut value = H::EMPTY;
 
             for layer in self.layers.iter_mut() {
                 // We have a layer we need to hash this value into
-                if value != [0u8; 32] {
-                    layer.add_data(&value);
+                if value != H::EMPTY {
+                    layer.add_data(value.as_ref());
                 }
                 if layer.remaining != 4096 {
                 // ...but now this layer itself is complete, so get the value of *it*.
                     value = layer.complete();
                 } else {
-                    value = [0u8; 32];
+                    value = H::EMPTY;
                 }
             }
 
@@ -105,7 +223,7 @@ impl FsVerityHasher {
         }
     }
 
-    pub fn digest(&mut self) -> Sha256HashValue {
+    pub fn digest(&mut self) -> H {
         /*
         let descriptor = FsVerityDescriptor {
             version: 1,
@@ -124,17 +242,29 @@ impl FsVerityHasher {
         return context.finalize().into();
         */
 
-        let mut context = Sha256::new();
+        let root_hash = self.root_hash();
+
+        let mut context = H::Digest::new();
         context.update(1u8.to_le_bytes()); /* version */
-        context.update(1u8.to_le_bytes()); /* hash_algorithm */
+        context.update(H::ALGORITHM.to_le_bytes()); /* hash_algorithm */
         context.update(12u8.to_le_bytes()); /* log_blocksize */
-        context.update(0u8.to_le_bytes()); /* salt_size */
+        context.update((self.salt.len() as u8).to_le_bytes()); /* salt_size */
         context.update([0; 4]); /* reserved */
         context.update(self.n_bytes.to_le_bytes());
-        context.update(self.root_hash());
-        context.update([0; 32]);
-        context.update([0; 32]); /* salt */
+        context.update(root_hash);
+        context.update(&[0; 64][root_hash.as_ref().len()..]); /* root_hash is 64 bytes */
+        context.update(&self.salt);
+        context.update(&[0; 32][self.salt.len()..]); /* salt is 32 bytes */
         context.update([0; 144]); /* reserved */
-        context.finalize().into()
+
+        let mut value = H::EMPTY;
+        value.as_mut().copy_from_slice(&context.finalize());
+        value
+    }
+}
+
+impl<H: FsVerityHashValue> Default for FsVerityHasher<H> {
+    fn default() -> Self {
+        FsVerityHasher::new()
     }
 }
@@ -1,9 +1,19 @@
-use std::os::fd::AsFd;
+use std::{
+    ffi::c_void,
+    os::fd::AsFd,
+};
 
-use anyhow::Result;
-use rustix::ioctl;
+use anyhow::{
+    Context,
+    Result,
+};
+use rustix::{
+    io::Errno,
+    ioctl,
+};
 
 use super::FsVerityHashValue;
+use crate::error::Error;
 
 // See /usr/include/linux/fsverity.h
 #[repr(C)]
@@ -22,22 +32,106 @@ pub struct FsVerityEnableArg {
 // #define FS_IOC_ENABLE_VERITY    _IOW('f', 133, struct fsverity_enable_arg)
 type FsIocEnableVerity = ioctl::WriteOpcode<b'f', 133, FsVerityEnableArg>;
 
-pub fn fs_ioc_enable_verity<F: AsFd, H: FsVerityHashValue>(fd: F) -> Result<()> {
-    unsafe {
-        ioctl::ioctl(fd, ioctl::Setter::<FsIocEnableVerity, FsVerityEnableArg>::new(FsVerityEnableArg {
+/// Enables fs-verity on the file, using the given salt (which may be empty).  If a signature is
+/// given, it's a PKCS#7 signature of the fs-verity digest of the file, which the kernel will check
+/// against the .fs-verity keyring and store along with the other verity metadata of the file.
+///
+/// If verity is already enabled on the file, this succeeds without doing anything, unless a
+/// signature is given: it can only be added when enabling verity.  The caller should measure the
+/// file to make sure that it has the expected digest.  The file must not be open for writing
+/// anywhere, including by the caller.
+pub fn fs_ioc_enable_verity<F: AsFd, H: FsVerityHashValue>(
+    fd: F, salt: &[u8], signature: Option<&[u8]>
+) -> Result<()> {
+    let (salt_size, salt_ptr) = match salt {
+        [] => (0, 0),
+        salt => (salt.len() as u32, salt.as_ptr() as u64),
+    };
+    let (sig_size, sig_ptr) = match signature {
+        Some(sig) => (sig.len() as u32, sig.as_ptr() as u64),
+        None => (0, 0),
+    };
+
+    let result = unsafe {
+        ioctl::ioctl(&fd, ioctl::Setter::<FsIocEnableVerity, FsVerityEnableArg>::new(FsVerityEnableArg {
             version: 1,
             hash_algorithm: H::ALGORITHM as u32,
             block_size: 4096,
-            salt_size: 0,
-            salt_ptr: 0,
-            sig_size: 0,
+            salt_size,
+            salt_ptr,
+            sig_size,
             __reserved1: 0,
-            sig_ptr: 0,
+            sig_ptr,
             __reserved2: [0; 11],
-        }))?;
+        }))
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(Errno::EXIST) if signature.is_none() => Ok(()),
+        Err(err @ Errno::EXIST) => Err(err).context("fs-verity is already enabled, without that signature"),
+        Err(err @ Errno::TXTBSY) => Err(err).context("File is still open for writing"),
+        Err(err @ (Errno::NOTSUP | Errno::NOTTY)) => Err(err).context(Error::VerityUnsupported),
+        Err(err @ Errno::NOKEY) => Err(err).context("The key for the signature isn't in the .fs-verity keyring"),
+        Err(err @ (Errno::KEYREJECTED | Errno::BADMSG)) => Err(err).context("The fs-verity signature was rejected"),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[repr(C)]
+pub struct FsVerityReadMetadataArg {
+    metadata_type: u64,
+    offset: u64,
+    length: u64,
+    buf_ptr: u64,
+    __reserved: u64,
+}
+
+const FS_VERITY_METADATA_TYPE_SIGNATURE: u64 = 3;
+
+// The kernel refuses signatures which don't fit in a 16k descriptor along with the rest of it
+const MAX_SIGNATURE_SIZE: usize = 16384;
+
+// #define FS_IOC_READ_VERITY_METADATA _IOWR('f', 135, struct fsverity_read_metadata_arg)
+// Unlike the other ioctls, this returns something: the number of bytes read.
+struct FsIocReadVerityMetadata<'a>(&'a mut FsVerityReadMetadataArg);
+
+unsafe impl ioctl::Ioctl for FsIocReadVerityMetadata<'_> {
+    type Output = usize;
+
+    const IS_MUTATING: bool = true;
+    const OPCODE: ioctl::Opcode = ioctl::Opcode::read_write::<FsVerityReadMetadataArg>(b'f', 135);
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        (self.0 as *mut FsVerityReadMetadataArg).cast()
     }
 
-    Ok(())
+    unsafe fn output_from_ptr(output: ioctl::IoctlOutput, _: *mut c_void) -> rustix::io::Result<usize> {
+        Ok(output as usize)
+    }
+}
+
+/// Reads the built-in signature that fs-verity was enabled on the file with.  Returns None if verity
+/// was enabled without one, or isn't enabled on the file at all.
+pub fn fs_ioc_read_verity_signature<F: AsFd>(fd: F) -> Result<Option<Vec<u8>>> {
+    let mut signature = vec![0u8; MAX_SIGNATURE_SIZE];
+    let mut arg = FsVerityReadMetadataArg {
+        metadata_type: FS_VERITY_METADATA_TYPE_SIGNATURE,
+        offset: 0,
+        length: signature.len() as u64,
+        buf_ptr: signature.as_mut_ptr() as u64,
+        __reserved: 0,
+    };
+
+    match unsafe { ioctl::ioctl(fd, FsIocReadVerityMetadata(&mut arg)) } {
+        Ok(size) => {
+            signature.truncate(size);
+            Ok(Some(signature))
+        },
+        Err(Errno::NODATA) => Ok(None),
+        Err(err @ (Errno::NOTSUP | Errno::NOTTY)) => Err(err).context(Error::VerityUnsupported),
+        Err(err) => Err(err.into()),
+    }
 }
 
 #[repr(C)]
@@ -56,8 +150,13 @@ pub fn fs_ioc_measure_verity<F: AsFd, H: FsVerityHashValue>(fd: F) -> Result<H>
 
     let mut digest = FsVerityDigest::<H> { digest_algorithm, digest_size, digest: H::EMPTY };
 
-    unsafe {
-        ioctl::ioctl(fd, ioctl::Updater::<FsIocMeasureVerity, FsVerityDigest<H>>::new(&mut digest))?;
+    let result = unsafe {
+        ioctl::ioctl(fd, ioctl::Updater::<FsIocMeasureVerity, FsVerityDigest<H>>::new(&mut digest))
+    };
+    match result {
+        Ok(()) => {},
+        Err(err @ (Errno::NOTSUP | Errno::NOTTY)) => return Err(err).context(Error::VerityUnsupported),
+        Err(err) => return Err(err.into()),
     }
 
     if digest.digest_algorithm != digest_algorithm || digest.digest_size != digest_size {
@@ -1,7 +1,15 @@
 pub mod digest;
 pub mod ioctl;
 
-pub trait FsVerityHashValue {
+use sha2::{
+    Digest,
+    Sha256,
+    Sha512,
+    digest::core_api::BlockSizeUser,
+};
+
+pub trait FsVerityHashValue where Self: Copy + Eq + Send + AsRef<[u8]> + AsMut<[u8]> {
+    type Digest: Digest + BlockSizeUser + Clone + Send;
     const ALGORITHM: u8;
     const EMPTY: Self;
 }
@@ -9,6 +17,7 @@ pub trait FsVerityHashValue {
 pub type Sha256HashValue = [u8; 32];
 
 impl FsVerityHashValue for Sha256HashValue {
+    type Digest = Sha256;
     const ALGORITHM: u8 = 1;
     const EMPTY: Self = [0; 32];
 }
@@ -16,6 +25,7 @@ impl FsVerityHashValue for Sha256HashValue {
 pub type Sha512HashValue = [u8; 64];
 
 impl FsVerityHashValue for Sha512HashValue {
+    type Digest = Sha512;
     const ALGORITHM: u8 = 2;
     const EMPTY: Self = [0; 64];
 }
@@ -0,0 +1,185 @@
+/* Shifting the owners of files, for user namespaces
+ *
+ * A container which runs in a user namespace sees the files of its image with the IDs of the
+ * namespace, so an image for it needs to be built with the IDs shifted to the ones outside: with
+ * a uid map of "0:100000:65536", a file owned by root in the layer is owned by 100000 in the
+ * image.  The maps have the same format as /proc/<pid>/uid_map and podman's --uidmap:
+ * "inside:outside:count" ranges.
+ *
+ * File capabilities need the same treatment.  Revision 3 capabilities only apply in namespaces
+ * whose root is their rootid, and revision 1 and 2 capabilities are the same as a rootid of 0, so
+ * the rootid gets shifted like the uid of the file (and capabilities without one get one).
+ */
+
+use std::{
+    borrow::Cow,
+    fmt,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use composefs::dumpfile::Entry;
+
+use crate::fscaps::{
+    self,
+    FileCaps,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Range {
+    inside: u32,
+    outside: u32,
+    count: u32,
+}
+
+/// A mapping of IDs inside a user namespace to IDs outside of it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdMap(Vec<Range>);
+
+impl IdMap {
+    /// Parses a map of "inside:outside:count" ranges, separated by commas.
+    pub fn parse(spec: &str) -> Result<IdMap> {
+        let mut ranges = vec![];
+        for range in spec.split(',') {
+            let fields = range.split(':')
+                .map(|field| field.parse::<u32>().with_context(|| format!("Invalid ID map {spec:?}")))
+                .collect::<Result<Vec<_>>>()?;
+            let [inside, outside, count] = fields[..] else {
+                bail!("Invalid ID map {spec:?}: expected inside:outside:count");
+            };
+            if count == 0 || inside.checked_add(count - 1).is_none() || outside.checked_add(count - 1).is_none() {
+                bail!("Invalid ID map {spec:?}: range {range:?} is out of bounds");
+            }
+            ranges.push(Range { inside, outside, count });
+        }
+        Ok(IdMap(ranges))
+    }
+
+    /// The ID outside for an ID inside, if it's mapped.
+    pub fn map(&self, id: u32) -> Option<u32> {
+        self.0.iter()
+            .find(|range| id >= range.inside && id - range.inside < range.count)
+            .map(|range| range.outside + (id - range.inside))
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            let separator = if i > 0 { "," } else { "" };
+            write!(f, "{separator}{}:{}:{}", range.inside, range.outside, range.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The uid and gid maps for a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMapping {
+    pub uids: IdMap,
+    pub gids: IdMap,
+}
+
+impl IdMapping {
+    /// Shifts the owner of the entry, and the rootid of its file capabilities.  Fails if any of
+    /// them isn't mapped: the file would be owned by nobody in the namespace.
+    pub fn remap_entry(&self, entry: &mut Entry) -> Result<()> {
+        let uid = self.uids.map(entry.uid)
+            .with_context(|| format!("uid {} of {:?} isn't in the uid map ({})", entry.uid, entry.path, self.uids))?;
+        let gid = self.gids.map(entry.gid)
+            .with_context(|| format!("gid {} of {:?} isn't in the gid map ({})", entry.gid, entry.path, self.gids))?;
+        (entry.uid, entry.gid) = (uid, gid);
+
+        for xattr in entry.xattrs.iter_mut().filter(|xattr| *xattr.key == *fscaps::XATTR_NAME) {
+            let mut caps = FileCaps::parse(&xattr.value).with_context(|| format!("{:?}", entry.path))?;
+            let rootid = caps.rootid.unwrap_or(0);
+            let mapped = self.uids.map(rootid)
+                .with_context(|| format!("The rootid {rootid} of the capabilities of {:?} isn't in the uid map", entry.path))?;
+            // Leave the value as it was if nothing changes
+            if mapped != rootid {
+                caps.rootid = Some(mapped);
+                xattr.value = Cow::Owned(caps.to_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use composefs::dumpfile::{
+        Item,
+        Mtime,
+        Xattr,
+    };
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let map = IdMap::parse("0:100000:1000,1000:1000:1").unwrap();
+        assert_eq!(map.map(0), Some(100000));
+        assert_eq!(map.map(999), Some(100999));
+        assert_eq!(map.map(1000), Some(1000));
+        assert_eq!(map.map(1001), None);
+        assert_eq!(map.to_string(), "0:100000:1000,1000:1000:1");
+
+        assert!(IdMap::parse("0:100000").is_err());
+        assert!(IdMap::parse("0:100000:0").is_err());
+        assert!(IdMap::parse("0:4294967295:2").is_err());
+        assert!(IdMap::parse("a:b:c").is_err());
+    }
+
+    fn entry(uid: u32, caps: Option<FileCaps>) -> Entry<'static> {
+        Entry {
+            path: Cow::Borrowed(Path::new("/usr/bin/ping")),
+            uid,
+            gid: uid,
+            mode: 0o100755,
+            mtime: Mtime { sec: 0, nsec: 0 },
+            item: Item::Regular { fsverity_digest: None, inline_content: None, nlink: 1, size: 0 },
+            xattrs: caps.iter().map(|caps| Xattr {
+                key: Cow::Borrowed(fscaps::XATTR_NAME.as_ref()),
+                value: Cow::Owned(caps.to_bytes()),
+            }).collect(),
+        }
+    }
+
+    fn caps(entry: &Entry) -> FileCaps {
+        FileCaps::parse(&entry.xattrs[0].value).unwrap()
+    }
+
+    #[test]
+    fn remap() {
+        let mapping = IdMapping { uids: IdMap::parse("0:100000:65536").unwrap(), gids: IdMap::parse("0:200000:65536").unwrap() };
+        let net_raw = FileCaps { effective: true, permitted: 1 << 13, inheritable: 0, rootid: None, v1: false };
+
+        // A revision 2 capability applies to root (0) inside, which becomes 100000
+        let mut ping = entry(0, Some(net_raw));
+        mapping.remap_entry(&mut ping).unwrap();
+        assert_eq!((ping.uid, ping.gid), (100000, 200000));
+        assert_eq!(caps(&ping), FileCaps { rootid: Some(100000), ..net_raw });
+
+        // A revision 3 capability has its rootid shifted
+        let mut ping = entry(1000, Some(FileCaps { rootid: Some(1000), ..net_raw }));
+        mapping.remap_entry(&mut ping).unwrap();
+        assert_eq!(ping.uid, 101000);
+        assert_eq!(caps(&ping).rootid, Some(101000));
+
+        // Unmapped IDs are refused
+        assert!(mapping.remap_entry(&mut entry(70000, None)).is_err());
+        assert!(mapping.remap_entry(&mut entry(0, Some(FileCaps { rootid: Some(70000), ..net_raw }))).is_err());
+
+        // With an identity mapping, the value stays the same, byte for byte
+        let identity = IdMapping { uids: IdMap::parse("0:0:65536").unwrap(), gids: IdMap::parse("0:0:65536").unwrap() };
+        let v1 = FileCaps { v1: true, ..net_raw };
+        let mut ping = entry(0, Some(v1));
+        identity.remap_entry(&mut ping).unwrap();
+        assert_eq!(ping.xattrs[0].value, v1.to_bytes());
+    }
+}
@@ -0,0 +1,59 @@
+/* security.ima xattrs for IMA appraisal
+ *
+ * A system which appraises files with IMA (ima_appraise_tcb, or a custom policy) checks the
+ * content of each file that's opened against its security.ima xattr.  Files in composefs images
+ * get their xattrs from the image, so we can add security.ima to the entries of a dumpfile before
+ * it's given to mkcomposefs.
+ *
+ * We only write digests (the ima-ng format: type 0x04, the hash algorithm, and the sha256 of the
+ * content).  Signatures (type 0x03) need an RSA or ECDSA key in the kernel's .ima keyring; files
+ * which already have a security.ima xattr in the layer, signed by evmctl for example, keep it.
+ */
+
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+};
+
+use anyhow::Result;
+use composefs::dumpfile::{
+    Entry,
+    Item,
+    Xattr,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use crate::{
+    image,
+    repository::Repository,
+};
+
+pub const XATTR_NAME: &str = "security.ima";
+
+// From the kernel's include/uapi/linux/hash_info.h and security/integrity/integrity.h
+const IMA_XATTR_DIGEST_NG: u8 = 0x04;
+const HASH_ALGO_SHA256: u8 = 4;
+
+/// The value of a security.ima xattr with the given sha256 digest of the content of a file.
+pub fn digest_xattr(sha256: &[u8; 32]) -> Vec<u8> {
+    [&[IMA_XATTR_DIGEST_NG, HASH_ALGO_SHA256][..], sha256].concat()
+}
+
+/// Adds a security.ima xattr with the digest of the content to a regular file entry, unless it
+/// has one already.  The content is read from the repository.  Other entries are left alone.
+pub fn add_digest_xattr(repo: &Repository, entry: &mut Entry) -> Result<()> {
+    if !matches!(entry.item, Item::Regular { .. }) || entry.xattrs.iter().any(|xattr| *xattr.key == *XATTR_NAME) {
+        return Ok(());
+    }
+
+    let mut hasher = Sha256::new();
+    image::write_content(repo, entry, &mut hasher)?;
+    entry.xattrs.push(Xattr {
+        key: Cow::Owned(OsString::from(XATTR_NAME)),
+        value: Cow::Owned(digest_xattr(&hasher.finalize().into())),
+    });
+    Ok(())
+}
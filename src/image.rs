@@ -0,0 +1,497 @@
+use std::{
+    collections::{
+        BTreeMap,
+        VecDeque,
+    },
+    ffi::{
+        OsStr,
+        OsString,
+    },
+    fs::File,
+    io::{
+        ErrorKind,
+        Read,
+        Write,
+    },
+    os::fd::OwnedFd,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use composefs::dumpfile::{
+    Entry,
+    Item,
+};
+use rustix::fs::{
+    AtFlags,
+    FileType,
+    Mode,
+    OFlags,
+    Timespec,
+    Timestamps,
+    XattrFlags,
+    chmodat,
+    ioctl_ficlone,
+    linkat,
+    lsetxattr,
+    mkdirat,
+    mknodat,
+    open,
+    openat,
+    symlinkat,
+    utimensat,
+};
+use rustix::io::Errno;
+
+use crate::{
+    fsverity::{
+        FsVerityHashValue,
+        Sha256HashValue,
+    },
+    progress::Progress,
+    repository::Repository,
+};
+
+/// Converts the absolute path of an entry in an image to a path relative to root, which is a
+/// directory in the image, usually "/".  The root directory itself is ".".
+fn relative_path(path: &Path, root: &Path) -> Result<PathBuf> {
+    let Ok(relative) = path.strip_prefix(root) else {
+        bail!("Path {:?} is outside of {:?}", path, root);
+    };
+
+    if relative == Path::new("") {
+        Ok(PathBuf::from("."))
+    } else {
+        Ok(relative.to_path_buf())
+    }
+}
+
+fn extract_object(repo: &Repository, digest: &str, dirfd: &OwnedFd, path: &Path) -> Result<()> {
+    let mut id = Sha256HashValue::EMPTY;
+    hex::decode_to_slice(digest, &mut id)?;
+
+    // We copy instead of hardlinking: changing the mode or the owner of the extracted file would
+    // otherwise change the object in the repository.  On filesystems with reflinks (btrfs, XFS)
+    // the copy shares the extents of the object, which is almost free.
+    let mut object = File::from(repo.open_object(id)?);
+    let mut file = File::from(openat(dirfd, path, OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o600.into())?);
+    match ioctl_ficlone(&file, &object) {
+        Ok(()) => {},
+        // not supported by the filesystem, or the target is on another one
+        Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::INVAL) => {
+            std::io::copy(&mut object, &mut file)?;
+        },
+        Err(err) => Err(err)?,
+    }
+    Ok(())
+}
+
+/// What extract() does with character and block devices.  Creating them requires privileges.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DevicePolicy {
+    Create,
+    Skip,
+    /// Create an empty regular file in place of the device
+    Empty,
+}
+
+fn extract_entry(
+    repo: &Repository, entry: &Entry, root: &Path, dirfd: &OwnedFd, target: &Path, devices: DevicePolicy
+) -> Result<()> {
+    let path = relative_path(&entry.path, root)?;
+
+    match &entry.item {
+        Item::Directory { .. } => {
+            match mkdirat(dirfd, &path, 0o700.into()) {
+                Ok(()) => {},
+                Err(err) if err.kind() == ErrorKind::AlreadyExists && path == Path::new(".") => {},
+                Err(err) => Err(err)?,
+            }
+        },
+        Item::Regular { fsverity_digest: Some(digest), .. } => {
+            extract_object(repo, digest, dirfd, &path)?;
+        },
+        Item::Regular { inline_content, .. } => {
+            let fd = openat(dirfd, &path, OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o600.into())?;
+            if let Some(content) = inline_content {
+                File::from(fd).write_all(content)?;
+            }
+        },
+        Item::Hardlink { target: link_target } => {
+            // no metadata to apply: it's shared with the link target
+            return Ok(linkat(dirfd, relative_path(link_target, root)?, dirfd, &path, AtFlags::empty())?);
+        },
+        Item::Symlink { target: link_target, .. } => {
+            symlinkat(link_target.as_ref(), dirfd, &path)?;
+        },
+        Item::Device { rdev, .. } => match devices {
+            DevicePolicy::Create => {
+                let filetype = FileType::from_raw_mode(entry.mode);
+                mknodat(dirfd, &path, filetype, Mode::from_raw_mode(entry.mode), *rdev)
+                    .context("Failed to create device (see --skip-devices and --devices-as-empty)")?;
+            },
+            DevicePolicy::Skip => return Ok(()),
+            DevicePolicy::Empty => {
+                openat(dirfd, &path, OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o600.into())?;
+            },
+        },
+        Item::Fifo { .. } => {
+            // Fifos and sockets are nothing but an inode with a type: take it from the mode
+            let filetype = match FileType::from_raw_mode(entry.mode) {
+                FileType::Socket => FileType::Socket,
+                _ => FileType::Fifo,
+            };
+            mknodat(dirfd, &path, filetype, Mode::from_raw_mode(entry.mode), 0)?;
+        },
+    }
+
+    // chown() removes security.capability, so the xattrs go after it
+    let full_path = target.join(&path);
+    if rustix::process::getuid().is_root() {
+        std::os::unix::fs::lchown(&full_path, Some(entry.uid), Some(entry.gid))?;
+    }
+
+    for xattr in entry.xattrs.iter() {
+        lsetxattr(&full_path, &*xattr.key, &xattr.value, XattrFlags::empty())
+            .with_context(|| format!("Failed to set xattr {:?} on {:?}", xattr.key, entry.path))?;
+    }
+
+    // Directories get their mode and mtime set after their contents have been extracted.
+    if !matches!(entry.item, Item::Symlink { .. } | Item::Directory { .. }) {
+        chmodat(dirfd, &path, Mode::from_raw_mode(entry.mode & 0o7777), AtFlags::empty())?;
+    }
+    if !matches!(entry.item, Item::Directory { .. }) {
+        set_mtime(dirfd, &path, entry)?;
+    }
+
+    Ok(())
+}
+
+fn set_mtime(dirfd: &OwnedFd, path: &Path, entry: &Entry) -> Result<()> {
+    let mtime = Timespec { tv_sec: entry.mtime.sec as i64, tv_nsec: entry.mtime.nsec as i64 };
+    let times = Timestamps { last_access: mtime, last_modification: mtime };
+    Ok(utimensat(dirfd, path, &times, AtFlags::SYMLINK_NOFOLLOW)?)
+}
+
+/// Extracts the named image from the repository into the target directory, which must already
+/// exist.  The content of regular files is copied from the objects in the repository.  Devices
+/// are handled according to the given policy.
+pub fn extract(repo: &Repository, name: &str, target: &Path, devices: DevicePolicy) -> Result<()> {
+    extract_subtree(repo, name, Path::new("/"), target, devices)
+}
+
+/// Like extract(), but only extracts the content of the directory root in the image.
+pub fn extract_subtree(
+    repo: &Repository, name: &str, root: &Path, target: &Path, devices: DevicePolicy
+) -> Result<()> {
+    let dump = repo.dump_image(name)?;
+    let dirfd = open(target, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())
+        .with_context(|| format!("Cannot open target directory {:?}", target))?;
+
+    let mut directories = vec![];
+    let mut progress = Progress::new("Extracting", "entries", Some(dump.lines().count() as u64));
+    for line in dump.lines() {
+        progress.inc(1);
+        let entry = Entry::parse(line)?;
+        if !entry.path.starts_with(root) {
+            continue;
+        }
+        extract_entry(repo, &entry, root, &dirfd, target, devices)
+            .with_context(|| format!("Failed to extract {:?}", entry.path))?;
+        if matches!(entry.item, Item::Directory { .. }) {
+            directories.push(entry);
+        }
+    }
+    drop(progress);
+
+    // Deepest directories first, so that setting the mtime of a directory isn't undone by
+    // changing the metadata of its subdirectories.
+    for entry in directories.iter().rev() {
+        let path = relative_path(&entry.path, root)?;
+        chmodat(&dirfd, &path, Mode::from_raw_mode(entry.mode & 0o7777), AtFlags::empty())?;
+        set_mtime(&dirfd, &path, entry)?;
+    }
+
+    Ok(())
+}
+
+/// Finds the entry for the given absolute path in a dump of an image (see
+/// Repository::dump_image()).  Hardlinks are resolved to the entry that they point to.
+pub fn find_entry<'d>(dump: &'d str, path: &Path) -> Result<Option<Entry<'d>>> {
+    for line in dump.lines() {
+        let entry = Entry::parse(line)?;
+        if entry.path == path {
+            return match &entry.item {
+                Item::Hardlink { target } => find_entry(dump, target),
+                _ => Ok(Some(entry)),
+            };
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the content of a regular file in an image to writer.
+pub fn write_content<W: Write>(repo: &Repository, entry: &Entry, writer: &mut W) -> Result<()> {
+    match &entry.item {
+        Item::Regular { fsverity_digest: Some(digest), .. } => {
+            let mut id = Sha256HashValue::EMPTY;
+            hex::decode_to_slice(digest, &mut id)?;
+            std::io::copy(&mut File::from(repo.open_object(id)?), writer)?;
+        },
+        Item::Regular { inline_content, .. } => {
+            if let Some(content) = inline_content {
+                writer.write_all(content)?;
+            }
+        },
+        _ => bail!("{:?} isn't a regular file", entry.path),
+    }
+    Ok(())
+}
+
+/// Parses a dump of an image into a map from paths to entries.
+pub fn parse_entries(dump: &str) -> Result<BTreeMap<PathBuf, Entry<'_>>> {
+    let mut entries = BTreeMap::new();
+    for line in dump.lines() {
+        let entry = Entry::parse(line)?;
+        entries.insert(entry.path.to_path_buf(), entry);
+    }
+    Ok(entries)
+}
+
+// Like the kernel's MAXSYMLINKS
+const MAX_SYMLINKS: usize = 40;
+
+/// Finds the entry for path in the image, following symlinks (also in the parent directories)
+/// and hardlinks like open() would.
+fn lookup<'e, 'd>(entries: &'e BTreeMap<PathBuf, Entry<'d>>, path: &Path) -> Result<&'e Entry<'d>> {
+    let mut resolved = PathBuf::from("/");
+    let mut remaining: VecDeque<OsString> = path.iter().map(OsStr::to_os_string).collect();
+    let mut symlinks = 0;
+
+    while let Some(component) = remaining.pop_front() {
+        if component == "/" || component == "." {
+            continue;
+        } else if component == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+        match entries.get(&candidate).map(|entry| &entry.item) {
+            None => bail!("{path:?} doesn't exist in the image"),
+            Some(Item::Symlink { target, .. }) => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    bail!("Too many levels of symbolic links in {path:?}");
+                }
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                for part in target.iter().rev() {
+                    remaining.push_front(part.to_os_string());
+                }
+            },
+            Some(Item::Hardlink { target }) => resolved = target.to_path_buf(),
+            Some(_) => resolved = candidate,
+        }
+    }
+
+    entries.get(&resolved).with_context(|| format!("{path:?} doesn't exist in the image"))
+}
+
+/// Writes the content of the file at path in the named image to writer.
+pub fn cat_file<W: Write>(repo: &Repository, name: &str, path: &Path, writer: &mut W) -> Result<()> {
+    let dump = repo.dump_image(name)?;
+    let entries = parse_entries(&dump)?;
+    write_content(repo, lookup(&entries, path)?, writer)
+}
+
+/// Checks if the content of a regular file which is stored inline is the same as the content of
+/// an object.  Images store small files inline, but layer streams don't.
+fn same_as_object(repo: &Repository, content: &[u8], digest: &str) -> Result<bool> {
+    let mut id = Sha256HashValue::EMPTY;
+    hex::decode_to_slice(digest, &mut id)?;
+
+    let mut object = vec![];
+    File::from(repo.open_object(id)?).read_to_end(&mut object)?;
+    Ok(object == content)
+}
+
+/// Returns a short description of the ways in which two entries for the same path differ.
+fn entry_changes(repo: &Repository, old: &Entry, new: &Entry) -> Result<Vec<&'static str>> {
+    let mut changes = vec![];
+
+    let same_content = match (&old.item, &new.item) {
+        (Item::Directory { .. }, Item::Directory { .. }) => true,
+        (Item::Fifo { .. }, Item::Fifo { .. }) => true,
+        (
+            Item::Regular { size: a_size, inline_content: a_inline, fsverity_digest: a_digest, .. },
+            Item::Regular { size: b_size, inline_content: b_inline, fsverity_digest: b_digest, .. }
+        ) => a_size == b_size && match (a_inline, a_digest, b_inline, b_digest) {
+            (Some(content), None, None, Some(digest)) | (None, Some(digest), Some(content), None) => {
+                same_as_object(repo, content, digest)?
+            },
+            _ => a_inline == b_inline && a_digest == b_digest,
+        },
+        (Item::Symlink { target: a, .. }, Item::Symlink { target: b, .. }) => a == b,
+        (Item::Hardlink { target: a }, Item::Hardlink { target: b }) => a == b,
+        (Item::Device { rdev: a, .. }, Item::Device { rdev: b, .. }) => a == b,
+        _ => false,
+    };
+    if !same_content {
+        changes.push("content");
+    }
+    if old.mode != new.mode {
+        changes.push("mode");
+    }
+    if (old.uid, old.gid) != (new.uid, new.gid) {
+        changes.push("owner");
+    }
+    if old.xattrs.len() != new.xattrs.len() || !old.xattrs.iter().zip(new.xattrs.iter()).all(
+        |(a, b)| a.key == b.key && a.value == b.value
+    ) {
+        changes.push("xattrs");
+    }
+
+    Ok(changes)
+}
+
+/// A path which differs between two images (or layers).
+pub enum Change {
+    Added(PathBuf),
+    Removed(PathBuf),
+    /// With the ways in which the entry changed: "content", "mode", "owner" and/or "xattrs".
+    Modified(PathBuf, Vec<&'static str>),
+}
+
+/// Compares two images in the repository and returns the paths which were added, removed or
+/// modified, in order.
+pub fn diff(repo: &Repository, old: &str, new: &str) -> Result<Vec<Change>> {
+    let old_dump = repo.dump_image(old)?;
+    let new_dump = repo.dump_image(new)?;
+    diff_entries(repo, &parse_entries(&old_dump)?, &parse_entries(&new_dump)?)
+}
+
+/// Like diff(), but for entries from any source (images or layer streams), indexed by path.
+pub fn diff_entries(
+    repo: &Repository, old_entries: &BTreeMap<PathBuf, Entry>, new_entries: &BTreeMap<PathBuf, Entry>
+) -> Result<Vec<Change>> {
+    let mut paths: Vec<_> = old_entries.keys().chain(new_entries.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut changes = vec![];
+    for path in paths {
+        match (old_entries.get(path), new_entries.get(path)) {
+            (Some(_), None) => changes.push(Change::Removed(path.clone())),
+            (None, Some(_)) => changes.push(Change::Added(path.clone())),
+            (Some(old_entry), Some(new_entry)) => {
+                let entry_changes = entry_changes(repo, old_entry, new_entry)?;
+                if !entry_changes.is_empty() {
+                    changes.push(Change::Modified(path.clone(), entry_changes));
+                }
+            },
+            (None, None) => unreachable!(),
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Formats a mode like ls -l does.
+pub fn mode_string(mode: u32) -> String {
+    let kind = match FileType::from_raw_mode(mode) {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::CharacterDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+        _ => '-',
+    };
+
+    let mut string = String::from(kind);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (mode >> shift) & 0o7;
+        string.push(if bits & 4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 2 != 0 { 'w' } else { '-' });
+        string.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    string
+}
+
+/// An entry in an image, as returned by ls().
+pub struct ListEntry {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// the digest of the object with the content of a regular file, if it's not inline
+    pub digest: Option<String>,
+    pub symlink_target: Option<PathBuf>,
+    pub hardlink_target: Option<PathBuf>,
+}
+
+/// Returns the entry for path in the named image, or its children if it's a directory (all of
+/// its descendants if recursive is set).
+pub fn ls(repo: &Repository, name: &str, path: &Path, recursive: bool) -> Result<Vec<ListEntry>> {
+    let dump = repo.dump_image(name)?;
+    let path = Path::new("/").join(path);
+    let mut found = false;
+    let mut result = vec![];
+
+    for line in dump.lines() {
+        let entry = Entry::parse(line)?;
+        let show = if entry.path == path {
+            found = true;
+            !matches!(entry.item, Item::Directory { .. })
+        } else if recursive {
+            entry.path.starts_with(&path)
+        } else {
+            entry.path.parent() == Some(&path)
+        };
+        if !show {
+            continue;
+        }
+
+        let mut item = ListEntry {
+            path: entry.path.to_path_buf(),
+            mode: entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            size: 0,
+            digest: None,
+            symlink_target: None,
+            hardlink_target: None,
+        };
+        match &entry.item {
+            Item::Regular { size, fsverity_digest, .. } => {
+                item.size = *size;
+                item.digest = fsverity_digest.clone();
+            },
+            Item::Directory { size, .. } => item.size = *size,
+            Item::Symlink { target, .. } => item.symlink_target = Some(target.to_path_buf()),
+            Item::Hardlink { target } => item.hardlink_target = Some(target.to_path_buf()),
+            Item::Device { .. } | Item::Fifo { .. } => {},
+        }
+        result.push(item);
+    }
+
+    if !found {
+        bail!("{path:?} doesn't exist in image {name}");
+    }
+    Ok(result)
+}
@@ -0,0 +1,72 @@
+/* Minimal JSON output
+ *
+ * cfsctl can print its results (and log messages) as JSON for consumption by other programs.
+ * We only ever write JSON, and only simple values, so this avoids a dependency on serde.
+ */
+
+use std::fmt;
+
+/// A JSON value.  This is just enough JSON for printing results and log messages.
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+    /// An object with keys which aren't known up front, like digests
+    Map(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn string<S: ToString>(value: S) -> Json {
+        Json::String(value.to_string())
+    }
+
+    pub fn optional<S: ToString>(value: Option<S>) -> Json {
+        value.map_or(Json::Null, Json::string)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            Json::Number(value) => write!(f, "{value}"),
+            Json::String(value) => {
+                write!(f, "\"")?;
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{item}", if i > 0 { "," } else { "" })?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{value}", if i > 0 { "," } else { "" }, Json::string(key))?;
+                }
+                write!(f, "}}")
+            },
+            Json::Map(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{value}", if i > 0 { "," } else { "" }, Json::string(key))?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
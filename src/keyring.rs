@@ -0,0 +1,150 @@
+/* The .fs-verity keyring of the kernel
+ *
+ * With CONFIG_FS_VERITY_BUILTIN_SIGNATURES, fs-verity can be enabled on a file along with a
+ * PKCS#7 signature of its digest (`cfsctl import-image --signature`), which the kernel checks
+ * against the X.509 certificates in the .fs-verity keyring: when verity is enabled, and again
+ * every time the file is opened.  Once the fs.verity.require_signatures sysctl is set, files
+ * without a signature can't be opened at all.
+ *
+ * The keyring starts out empty, so the certificate needs to be loaded before anything from the
+ * repository is mounted, which means from the initramfs.  It has to come from the initramfs too
+ * (which is covered by the signature of the UKI), since nothing on the root filesystem has been
+ * checked yet at that point.  This is the same as:
+ *
+ *     keyctl padd asymmetric '' %keyring:.fs-verity < cert.der
+ *     sysctl fs.verity.require_signatures=1
+ *
+ * Certificates are in DER format, which is the only one that the kernel understands.
+ */
+
+use std::{
+    ffi::CStr,
+    io,
+    ptr,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+
+// See /usr/include/linux/keyctl.h
+const KEY_SPEC_PROCESS_KEYRING: i32 = -2;
+const KEYCTL_DESCRIBE: libc::c_int = 6;
+const KEYCTL_READ: libc::c_int = 11;
+const KEYCTL_INVALIDATE: libc::c_int = 21;
+
+const KEYRING_NAME: &str = ".fs-verity";
+const REQUIRE_SIGNATURES: &str = "/proc/sys/fs/verity/require_signatures";
+
+/// The serial number of a key (or keyring) in the kernel.
+pub type KeySerial = i32;
+
+fn add_key(key_type: &CStr, description: Option<&CStr>, payload: &[u8], keyring: KeySerial) -> io::Result<KeySerial> {
+    let description = description.map_or(ptr::null(), CStr::as_ptr);
+    let result = unsafe {
+        libc::syscall(libc::SYS_add_key, key_type.as_ptr(), description, payload.as_ptr(), payload.len(), keyring)
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        serial => Ok(serial as KeySerial),
+    }
+}
+
+fn keyctl(operation: libc::c_int, key: KeySerial, buffer: &mut [u8]) -> io::Result<usize> {
+    let result = unsafe { libc::syscall(libc::SYS_keyctl, operation, key, buffer.as_mut_ptr(), buffer.len()) };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        size => Ok(size as usize),
+    }
+}
+
+// KEYCTL_READ and KEYCTL_DESCRIBE return the size they need, whether the buffer was big enough or not
+fn keyctl_read(operation: libc::c_int, key: KeySerial) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![];
+    loop {
+        let size = keyctl(operation, key, &mut buffer)?;
+        if size <= buffer.len() {
+            buffer.truncate(size);
+            return Ok(buffer);
+        }
+        buffer.resize(size, 0);
+    }
+}
+
+// The "type;uid;gid;perm;description" of a key, without the uid, gid and perm
+fn describe(key: KeySerial) -> io::Result<(String, String)> {
+    let data = keyctl_read(KEYCTL_DESCRIBE, key)?;
+    let data = String::from_utf8_lossy(data.strip_suffix(b"\0").unwrap_or(&data)).into_owned();
+    let mut fields = data.splitn(5, ';');
+    let key_type = fields.next().unwrap_or_default().to_string();
+    let description = fields.nth(3).unwrap_or_default().to_string();
+    Ok((key_type, description))
+}
+
+/// Finds the .fs-verity keyring.  It only exists if the kernel supports fs-verity signatures.
+pub fn fsverity_keyring() -> Result<KeySerial> {
+    // There's no way to look up a keyring by name unless it's linked from one of ours, so do what
+    // keyctl does for %keyring:name, and look for it in /proc/keys.
+    let keys = std::fs::read_to_string("/proc/keys").context("Failed to read /proc/keys")?;
+    for line in keys.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if let [serial, _, _, _, _, _, _, "keyring", description, ..] = fields[..] {
+            if description.strip_suffix(':') == Some(KEYRING_NAME) {
+                return KeySerial::from_str_radix(serial, 16)
+                    .with_context(|| format!("Invalid serial of the {KEYRING_NAME} keyring: {serial}"));
+            }
+        }
+    }
+    bail!("There's no {KEYRING_NAME} keyring (the kernel needs CONFIG_FS_VERITY_BUILTIN_SIGNATURES)");
+}
+
+/// Loads an X.509 certificate into the .fs-verity keyring.  Loading a certificate which is there
+/// already replaces it.
+pub fn load_certificate(certificate: &[u8]) -> Result<KeySerial> {
+    let keyring = fsverity_keyring()?;
+    add_key(c"asymmetric", None, certificate, keyring)
+        .with_context(|| format!("Failed to load the certificate into the {KEYRING_NAME} keyring"))
+}
+
+// The kernel names keys after the subject and the key identifier of the certificate.  Rather than
+// parsing the certificate ourselves, have the kernel load it into a keyring of our own, and ask.
+fn certificate_description(certificate: &[u8]) -> Result<String> {
+    let keyring = add_key(c"keyring", Some(c"composefs"), &[], KEY_SPEC_PROCESS_KEYRING)
+        .context("Failed to create a temporary keyring")?;
+    let result = add_key(c"asymmetric", None, certificate, keyring).and_then(describe);
+    // Clean up before looking at the result, but an error from that is the less interesting one
+    let invalidated = keyctl(KEYCTL_INVALIDATE, keyring, &mut []);
+    let (_, description) = result.context("Failed to load the certificate")?;
+    invalidated.context("Failed to remove the temporary keyring")?;
+    Ok(description)
+}
+
+/// Checks if the .fs-verity keyring contains (a key with the same name as) the certificate.
+pub fn contains_certificate(certificate: &[u8]) -> Result<bool> {
+    let expected = certificate_description(certificate)?;
+    let keyring = fsverity_keyring()?;
+    let keys = keyctl_read(KEYCTL_READ, keyring)
+        .with_context(|| format!("Failed to list the keys in the {KEYRING_NAME} keyring"))?;
+    for key in keys.chunks_exact(std::mem::size_of::<KeySerial>()) {
+        let key = KeySerial::from_ne_bytes(key.try_into().unwrap());
+        // Keys which we can't see can't be the one we're looking for
+        if let Ok((key_type, description)) = describe(key) {
+            if key_type == "asymmetric" && description == expected {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Makes the kernel refuse to open files without a valid fs-verity signature, after checking that
+/// the .fs-verity keyring contains the certificate that they're signed with.  Without that, every
+/// file in the repository would become unreadable.
+pub fn require_signatures(certificate: &[u8]) -> Result<()> {
+    if !contains_certificate(certificate)? {
+        bail!("The certificate isn't in the {KEYRING_NAME} keyring");
+    }
+    std::fs::write(REQUIRE_SIGNATURES, "1").with_context(|| format!("Failed to write {REQUIRE_SIGNATURES}"))
+}
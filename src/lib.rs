@@ -1,7 +1,70 @@
+//! Tools for storing and mounting composefs images.
+//!
+//! The center of this is [`Repository`]: a content-addressed store of fs-verity objects, plus
+//! the images (erofs, as built by mkcomposefs) and [split streams](splitstream) which refer to
+//! them.  Everything that `cfsctl` does is available from here, so that other programs (update
+//! daemons, for example) can use a repository directly instead of running `cfsctl`.
+//!
+//! All fallible operations return [`anyhow::Result`].  Failures which callers might want to handle
+//! (a missing object, a digest mismatch, ...) carry an [`Error`], which can be found with
+//! [`anyhow::Error::downcast_ref`].
+
 mod util;
+
+/// Async variants of the stream operations, for tokio-based programs.
+#[cfg(feature = "tokio")]
+pub mod asyncio;
+/// The on-disk repository of objects, images and streams.  See doc/repository.md.
 pub mod repository;
+/// Installing images as boot entries (BLS and UKI), with boot assessment.
+pub mod boot;
+/// Content-defined chunking, for storing arbitrary files as split streams.
+pub mod cdc;
+/// Raw filesystem images protected by dm-verity.
+pub mod dmverity;
+/// Errors which callers can tell apart.
+pub mod error;
+/// File capabilities, as stored in the security.capability xattr.
+pub mod fscaps;
+/// Computing and measuring fs-verity digests.
 pub mod fsverity;
+/// Shifting the owners of files (and file capabilities) for user namespaces.
+pub mod idmap;
+/// security.ima xattrs, for IMA appraisal of the files in images.
+pub mod ima;
+/// Loading certificates for fs-verity signatures into the kernel keyring.
+pub mod keyring;
+/// Reading the content of images: listing, comparing and extracting files.
+pub mod image;
+/// Minimal JSON output, for machine-readable results and log messages.
+pub mod json;
+/// Mounting composefs images, with the new mount API.
 pub mod mount;
+/// Importing, listing, comparing and merging OCI (tar) layers.
 pub mod oci;
+/// Exporting images as ostree commits.
+pub mod ostree;
+/// Reporting progress and log messages from long operations.
+pub mod progress;
+/// SELinux labels from the file_contexts of a policy.
+pub mod selinux;
+/// Detached Ed25519 signatures of images.
+pub mod signing;
+/// The split stream file format.  See doc/splitstream.md.
 pub mod splitstream;
+/// Building system extension images for systemd-sysext.
+pub mod sysext;
+/// Generating systemd mount units for images.
+pub mod systemd;
+/// Temporary directories which are removed when dropped.
 pub mod tmpdir;
+/// Measuring images into a TPM PCR, for remote attestation.
+pub mod tpm;
+#[cfg(feature = "io-uring")]
+mod uring;
+/// Sharing images with virtual machines, via virtiofsd.
+pub mod virtiofs;
+
+pub use error::Error;
+pub use fsverity::Sha256HashValue;
+pub use repository::Repository;
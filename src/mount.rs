@@ -1,11 +1,25 @@
-use std::os::fd::{
-    OwnedFd,
-    BorrowedFd,
-    AsFd,
-    AsRawFd
+use std::{
+    io::ErrorKind,
+    os::fd::{
+        OwnedFd,
+        BorrowedFd,
+        AsFd,
+        AsRawFd
+    },
+    path::Path,
 };
 
-use anyhow::Result;
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use rustix::fs::{
+    Mode,
+    OFlags,
+    open,
+};
+use rustix::io::Errno;
 use rustix::mount::{
     FsMountFlags,
     FsOpenFlags,
@@ -13,6 +27,7 @@ use rustix::mount::{
     MoveMountFlags,
     UnmountFlags,
     fsconfig_create,
+    fsconfig_set_fd,
     fsconfig_set_string,
     fsmount,
     fsopen,
@@ -21,8 +36,12 @@ use rustix::mount::{
 };
 
 use crate::{
+    error::Error,
     fsverity,
+    progress,
+    signing::PublicKey,
     tmpdir,
+    tpm,
 };
 
 struct FsHandle {
@@ -33,11 +52,17 @@ impl FsHandle {
     pub fn open(name: &str) -> Result<FsHandle> {
         Ok(FsHandle { fd: fsopen(name, FsOpenFlags::FSOPEN_CLOEXEC)? })
     }
+
+    /// Drops the messages logged so far, for errors which we handled.
+    fn discard_messages(&self) {
+        let mut buffer = [0u8; 1024];
+        while let Ok(1..) = rustix::io::read(&self.fd, &mut buffer) {}
+    }
 }
 
 impl AsFd for FsHandle {
-    fn as_fd(&self) -> BorrowedFd {
-        return self.fd.as_fd();
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
     }
 }
 
@@ -48,7 +73,7 @@ impl Drop for FsHandle {
             match rustix::io::read(&self.fd, &mut buffer) {
                 Err(_) => return, // ENODATA, among others?
                 Ok(0) => return,
-                Ok(size) => eprintln!("{}", String::from_utf8(buffer[0..size].to_vec()).unwrap()),
+                Ok(size) => progress::warn(String::from_utf8_lossy(&buffer[0..size])),
             }
         }
     }
@@ -59,10 +84,9 @@ struct TmpMount {
 }
 
 impl TmpMount {
-    pub fn mount(fs: BorrowedFd) -> Result<TmpMount> {
+    pub fn mount(mnt: BorrowedFd) -> Result<TmpMount> {
         let tmp = tmpdir::TempDir::new()?;
-        let mnt = fsmount(fs, FsMountFlags::FSMOUNT_CLOEXEC, MountAttrFlags::empty())?;
-        move_mount(mnt.as_fd(), "", rustix::fs::CWD, &tmp.path, MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH)?;
+        move_mount(mnt, "", rustix::fs::CWD, &tmp.path, MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH)?;
         Ok(TmpMount { dir: tmp })
     }
 }
@@ -78,19 +102,66 @@ fn proc_self_fd<A: AsFd>(fd: A) -> String {
     format!("/proc/self/fd/{}", fd.as_fd().as_raw_fd())
 }
 
-pub fn mount_fd<F: AsFd>(image: F, basedir: &str, mountpoint: &str) -> Result<()> {
+/// Adds a layer to the overlayfs.  Since Linux 6.13, layers can be given as file descriptors,
+/// which avoids races with changes to the filesystem tree.  Older kernels need a path, so we
+/// fall back to mounting the layer on a temporary directory, which must then stay mounted until
+/// the overlayfs is created.
+fn add_overlay_layer(overlayfs: &FsHandle, key: &str, layer: BorrowedFd) -> Result<Option<TmpMount>> {
+    match fsconfig_set_fd(overlayfs.as_fd(), key, layer) {
+        Ok(()) => Ok(None),
+        Err(Errno::INVAL) => {
+            overlayfs.discard_messages();
+            let tmp = TmpMount::mount(layer)?;
+            fsconfig_set_string(overlayfs.as_fd(), key, &tmp.dir.path)?;
+            Ok(Some(tmp))
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Sets a directory option (like datadir+ or upperdir) on the overlayfs, by file descriptor if the
+/// kernel supports it, and by path otherwise.
+fn set_overlay_dir(overlayfs: &FsHandle, key: &str, path: &str) -> Result<()> {
+    let dir = open(path, OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())
+        .with_context(|| format!("Cannot open {path}"))?;
+    match fsconfig_set_fd(overlayfs.as_fd(), key, dir.as_fd()) {
+        Ok(()) => Ok(()),
+        Err(Errno::INVAL) => {
+            overlayfs.discard_messages();
+            Ok(fsconfig_set_string(overlayfs.as_fd(), key, path)?)
+        },
+        Err(err) => Err(err).with_context(|| format!("Failed to set {key} to {path}")),
+    }
+}
+
+/// Mounts the composefs image on mountpoint, with its objects in basedir.  If writable is given,
+/// it's a pair of (upperdir, workdir) for the overlayfs, which makes the mount writable.  Those
+/// must be on the same filesystem.  Otherwise the mount is read-only.  If require_verity is set,
+/// overlayfs refuses to open any file whose object doesn't have the fs-verity digest recorded for
+/// it in the image.  That only protects the content of the files: the caller needs to verify
+/// the image itself.
+pub fn mount_fd<F: AsFd>(
+    image: F, basedir: &str, writable: Option<(&str, &str)>, require_verity: bool, mountpoint: &str
+) -> Result<()> {
         let erofs = FsHandle::open("erofs")?;
         fsconfig_set_string(erofs.as_fd(), "source", proc_self_fd(&image))?;
         fsconfig_create(erofs.as_fd())?;
+        let erofs_mnt = fsmount(erofs.as_fd(), FsMountFlags::FSMOUNT_CLOEXEC, MountAttrFlags::empty())?;
 
         let overlayfs = FsHandle::open("overlay")?;
         fsconfig_set_string(overlayfs.as_fd(), "metacopy", "on")?;
         fsconfig_set_string(overlayfs.as_fd(), "redirect_dir", "on")?;
+        if require_verity {
+            fsconfig_set_string(overlayfs.as_fd(), "verity", "require")?;
+        }
 
-        // unfortunately we can't do this via the fd: we need a tmpdir mountpoint
-        let tmp = TmpMount::mount(erofs.as_fd())?;  // NB: must live until the "create" operation
-        fsconfig_set_string(overlayfs.as_fd(), "lowerdir+", &tmp.dir.path)?;
-        fsconfig_set_string(overlayfs.as_fd(), "datadir+", basedir)?;
+        // NB: these must live until the "create" operation
+        let _lower = add_overlay_layer(&overlayfs, "lowerdir+", erofs_mnt.as_fd())?;
+        set_overlay_dir(&overlayfs, "datadir+", basedir)?;
+        if let Some((upperdir, workdir)) = writable {
+            set_overlay_dir(&overlayfs, "upperdir", upperdir)?;
+            set_overlay_dir(&overlayfs, "workdir", workdir)?;
+        }
         fsconfig_create(overlayfs.as_fd())?;
 
         let mnt = fsmount(overlayfs.as_fd(), FsMountFlags::FSMOUNT_CLOEXEC, MountAttrFlags::empty())?;
@@ -99,16 +170,51 @@ pub fn mount_fd<F: AsFd>(image: F, basedir: &str, mountpoint: &str) -> Result<()
         Ok(())
 }
 
+/// Unmounts a composefs mounted with mount_fd().  Only the overlayfs is mounted in the
+/// filesystem tree: the erofs underneath it goes away along with it.  If lazy is set, the
+/// filesystem is detached immediately but only cleaned up when it's no longer busy.
+pub fn unmount_composefs(mountpoint: &str, lazy: bool) -> Result<()> {
+    let flags = if lazy { UnmountFlags::DETACH } else { UnmountFlags::empty() };
+    unmount(mountpoint, flags).with_context(|| format!("Failed to unmount {mountpoint}"))?;
+    Ok(())
+}
+
+/// Checks that the image with the given (measured) digest has a valid signature by one of the
+/// keys in trusted_keys, in the same layout as the repository: signatures/<digest>/<key id>.
+fn check_signature(image: &str, digest: &fsverity::Sha256HashValue, trusted_keys: &str, signatures: &str) -> Result<()> {
+    let mut keys = vec![];
+    for entry in std::fs::read_dir(trusted_keys).with_context(|| format!("Failed to read {trusted_keys}"))? {
+        keys.push(PublicKey::load(&entry?.path())?);
+    }
+    // An empty directory is more likely a mistake than a wish to trust everything
+    if keys.is_empty() {
+        bail!("No trusted keys in {trusted_keys}");
+    }
+
+    for key in keys {
+        let path = Path::new(signatures).join(hex::encode(digest)).join(key.id());
+        match std::fs::read(&path) {
+            Ok(signature) if key.verify_image(digest, &signature) => return Ok(()),
+            Ok(..) => progress::warn(format!("Invalid signature of {image} by {}", key.id())),
+            Err(err) if err.kind() == ErrorKind::NotFound => {},
+            Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}"))?,
+        }
+    }
+    bail!(Error::Untrusted(format!("Image {image}")))
+}
+
 pub struct MountOptions<'a> {
     image: &'a str,
     basedir: &'a str,
     digest: Option<&'a str>,
     verity: bool,
+    pcr: Option<u32>,
+    trust: Option<(&'a str, &'a str)>,
 }
 
 impl<'a> MountOptions<'a> {
     pub fn new(image: &'a str, basedir: &'a str) -> MountOptions<'a> {
-        MountOptions { image, basedir, digest: None, verity: false }
+        MountOptions { image, basedir, digest: None, verity: false, pcr: None, trust: None }
     }
 
     pub fn set_require_verity(&mut self) {
@@ -119,16 +225,40 @@ impl<'a> MountOptions<'a> {
         self.digest = Some(digest);
     }
 
+    /// Extends the TPM PCR with the (measured) digest of the image before mounting it.
+    pub fn set_measure_pcr(&mut self, pcr: u32) {
+        self.pcr = Some(pcr);
+    }
+
+    /// Only mounts the image if it's signed by one of the public keys (PEM files) in the
+    /// trusted_keys directory, with the signatures in the signatures directory.  For a repository,
+    /// those are its trusted-keys/ and signatures/ (see doc/repository.md).
+    pub fn set_trust_policy(&mut self, trusted_keys: &'a str, signatures: &'a str) {
+        self.trust = Some((trusted_keys, signatures));
+    }
+
     pub fn mount(self, mountpoint: &str) -> Result<()> {
         let image = std::fs::File::open(self.image)?;
 
-        if let Some(expected) = self.digest {
-            let measured: fsverity::Sha256HashValue = fsverity::ioctl::fs_ioc_measure_verity(&image)?;
-            if expected != hex::encode(measured) {
-                panic!("expected {:?} measured {:?}", expected, measured);
+        if self.verity || self.digest.is_some() || self.pcr.is_some() || self.trust.is_some() {
+            let measured: fsverity::Sha256HashValue = fsverity::ioctl::fs_ioc_measure_verity(&image)
+                .with_context(|| format!("Failed to measure fs-verity digest of {}", self.image))?;
+            if let Some(digest) = self.digest {
+                let mut expected: fsverity::Sha256HashValue = [0; 32];
+                hex::decode_to_slice(digest, &mut expected)
+                    .with_context(|| format!("Invalid digest {digest}"))?;
+                if expected != measured {
+                    bail!(Error::DigestMismatch { what: self.image.to_string(), expected, measured });
+                }
+            }
+            if let Some((trusted_keys, signatures)) = self.trust {
+                check_signature(self.image, &measured, trusted_keys, signatures)?;
+            }
+            if let Some(pcr) = self.pcr {
+                tpm::measure_image(pcr, &measured)?;
             }
         }
 
-        mount_fd(image, self.basedir, mountpoint)
+        mount_fd(image, self.basedir, None, self.verity, mountpoint)
     }
 }
@@ -0,0 +1,128 @@
+/* OCI runtime bundles, for running an image with crun or runc
+ *
+ * A bundle is a directory with the root filesystem of the container in rootfs/ and its
+ * configuration in config.json (see the OCI runtime spec).  The rootfs is a (read-only) composefs
+ * mount of the image, or a copy of its content.
+ *
+ * The repository doesn't keep the OCI config of images (only their layers), so the process to
+ * run is given by the caller.  The rest of config.json is what `runc spec` writes: the usual
+ * namespaces, mounts of /proc, /dev and /sys, and the default capabilities.
+ */
+
+use std::path::Path;
+
+use anyhow::{
+    Context,
+    Result,
+};
+
+use crate::{
+    image::{
+        self,
+        DevicePolicy,
+    },
+    json::Json,
+    repository::Repository,
+};
+
+const DEFAULT_PATH: &str = "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+const CAPABILITIES: [&str; 3] = ["CAP_AUDIT_WRITE", "CAP_KILL", "CAP_NET_BIND_SERVICE"];
+
+const MASKED_PATHS: [&str; 9] = [
+    "/proc/acpi", "/proc/asound", "/proc/kcore", "/proc/keys", "/proc/latency_stats",
+    "/proc/timer_list", "/proc/timer_stats", "/proc/sched_debug", "/sys/firmware",
+];
+
+const READONLY_PATHS: [&str; 6] = [
+    "/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger", "/proc/scsi",
+];
+
+/// The process to run in the container.
+pub struct Process {
+    pub args: Vec<String>,
+    /// Environment variables (as NAME=value), in addition to a default PATH
+    pub env: Vec<String>,
+    pub cwd: String,
+    /// Whether to allocate a pseudo-terminal for the process
+    pub terminal: bool,
+}
+
+fn strings(values: &[&str]) -> Json {
+    Json::Array(values.iter().map(Json::string).collect())
+}
+
+fn mount(destination: &str, fstype: &str, source: &str, options: &[&str]) -> Json {
+    Json::Object(vec![
+        ("destination", Json::string(destination)),
+        ("type", Json::string(fstype)),
+        ("source", Json::string(source)),
+        ("options", strings(options)),
+    ])
+}
+
+fn config(process: &Process, readonly: bool) -> Json {
+    let mut env = vec![Json::string(DEFAULT_PATH)];
+    env.extend(process.env.iter().map(Json::string));
+
+    Json::Object(vec![
+        ("ociVersion", Json::string("1.0.2")),
+        ("process", Json::Object(vec![
+            ("terminal", Json::Bool(process.terminal)),
+            ("user", Json::Object(vec![("uid", Json::Number(0)), ("gid", Json::Number(0))])),
+            ("args", Json::Array(process.args.iter().map(Json::string).collect())),
+            ("env", Json::Array(env)),
+            ("cwd", Json::string(&process.cwd)),
+            ("capabilities", Json::Object(vec![
+                ("bounding", strings(&CAPABILITIES)),
+                ("effective", strings(&CAPABILITIES)),
+                ("permitted", strings(&CAPABILITIES)),
+            ])),
+            ("rlimits", Json::Array(vec![Json::Object(vec![
+                ("type", Json::string("RLIMIT_NOFILE")),
+                ("hard", Json::Number(1024)),
+                ("soft", Json::Number(1024)),
+            ])])),
+            ("noNewPrivileges", Json::Bool(true)),
+        ])),
+        ("root", Json::Object(vec![
+            ("path", Json::string("rootfs")),
+            ("readonly", Json::Bool(readonly)),
+        ])),
+        ("hostname", Json::string("composefs")),
+        ("mounts", Json::Array(vec![
+            mount("/proc", "proc", "proc", &[]),
+            mount("/dev", "tmpfs", "tmpfs", &["nosuid", "strictatime", "mode=755", "size=65536k"]),
+            mount("/dev/pts", "devpts", "devpts",
+                  &["nosuid", "noexec", "newinstance", "ptmxmode=0666", "mode=0620", "gid=5"]),
+            mount("/dev/shm", "tmpfs", "shm", &["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"]),
+            mount("/dev/mqueue", "mqueue", "mqueue", &["nosuid", "noexec", "nodev"]),
+            mount("/sys", "sysfs", "sysfs", &["nosuid", "noexec", "nodev", "ro"]),
+            mount("/sys/fs/cgroup", "cgroup", "cgroup", &["nosuid", "noexec", "nodev", "relatime", "ro"]),
+        ])),
+        ("linux", Json::Object(vec![
+            ("namespaces", Json::Array(["pid", "network", "ipc", "uts", "mount", "cgroup"].iter().map(|ns| {
+                Json::Object(vec![("type", Json::string(ns))])
+            }).collect())),
+            ("maskedPaths", strings(&MASKED_PATHS)),
+            ("readonlyPaths", strings(&READONLY_PATHS)),
+        ])),
+    ])
+}
+
+/// Creates a bundle for the named image in dir, which is created if it doesn't exist.  The image
+/// is mounted on rootfs/ (unmount it with mount::unmount_composefs() when done), or, if extract is
+/// set, its content is copied there, in which case the container can write to it.
+pub fn create(repo: Repository, name: &str, dir: &Path, process: &Process, extract: bool) -> Result<()> {
+    let rootfs = dir.join("rootfs");
+    std::fs::create_dir_all(&rootfs).with_context(|| format!("Failed to create {rootfs:?}"))?;
+
+    if extract {
+        image::extract(&repo, name, &rootfs, DevicePolicy::Create)?;
+    } else {
+        repo.mount(name, rootfs.to_str().context("Bundle path isn't valid UTF-8")?, false, false)?;
+    }
+
+    std::fs::write(dir.join("config.json"), format!("{}\n", config(process, !extract)))?;
+    Ok(())
+}
@@ -1,29 +1,101 @@
+pub mod bundle;
+pub mod podman;
 pub mod tar;
 
 use std::io::Read;
 
 use anyhow::Result;
+use tracing::{
+    info,
+    instrument,
+};
 
 use crate::{
     fsverity::Sha256HashValue,
-    repository::Repository
+    idmap::IdMapping,
+    ima,
+    image,
+    progress::Progress,
+    repository::Repository,
+    selinux::{
+        self,
+        FileContexts,
+    },
+    util::ReadAhead,
 };
 
-pub fn import_layer<R: Read>(repo: &Repository, name: &str, tar_stream: &mut R) -> Result<Sha256HashValue> {
-    let mut split_stream = zstd::stream::write::Encoder::new(vec![], 0)?;
+/// Stores a tar stream in the repository.  Reading the input, splitting it, and writing the
+/// objects all happen on separate threads.
+#[instrument(skip(repo, tar_stream))]
+pub fn import_layer<R: Read + Send>(repo: &Repository, name: &str, tar_stream: &mut R) -> Result<Sha256HashValue> {
+    let mut split_stream = repo.create_stream()?;
+    let mut progress = Progress::new("Importing layer", "objects", None);
 
-    tar::split(
-        tar_stream,
-        &mut split_stream,
-        |data: &[u8]| -> Result<Sha256HashValue> {
-            repo.ensure_object(data)
-        }
-    )?;
+    std::thread::scope(|scope| {
+        let mut reader = ReadAhead::spawn(scope, tar_stream);
+        repo.with_object_writers(|writers| tar::split(
+            &mut reader,
+            &mut split_stream,
+            |data: Vec<u8>| -> Result<Sha256HashValue> {
+                progress.inc(1);
+                writers.ensure_object(data)
+            }
+        ))
+    })?;
+    drop(progress);
+
+    let object_id = repo.ensure_object(&split_stream.finish()?)?;
+    info!(stream = hex::encode(object_id), "imported layer");
+    repo.link_ref(name, "streams", object_id)
+}
+
+#[instrument(skip(repo))]
+pub fn squash(repo: &Repository, name: &str, layers: &[String]) -> Result<Sha256HashValue> {
+    let mut layer_streams = layers.iter()
+        .map(|layer| repo.open_stream(layer))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut split_stream = repo.create_stream()?;
+    tar::squash(&mut layer_streams, &mut split_stream)?;
 
     let object_id = repo.ensure_object(&split_stream.finish()?)?;
     repo.link_ref(name, "streams", object_id)
 }
 
-pub fn ls_layer(repo: &Repository, name: &str) -> Result<()> {
-    tar::ls(&mut repo.open_stream(name)?)
+/// Prints the entries of a layer stream in dumpfile format.  If ima is set, regular files get a
+/// security.ima xattr with the digest of their content (see ima.rs).  If file contexts are given,
+/// entries get a security.selinux xattr with their label (see selinux.rs).  If an ID mapping is
+/// given, the owners of the entries are shifted according to it (see idmap.rs).
+pub fn ls_layer(
+    repo: &Repository, name: &str, limits: &tar::EntryLimits, ima: bool, selinux: Option<&FileContexts>,
+    idmap: Option<&IdMapping>,
+) -> Result<()> {
+    tar::ls(&mut repo.open_stream(name)?, limits, |entry| {
+        if let Some(mapping) = idmap {
+            mapping.remap_entry(entry)?;
+        }
+        if let Some(contexts) = selinux {
+            selinux::add_label_xattr(contexts, entry);
+        }
+        match ima {
+            true => ima::add_digest_xattr(repo, entry),
+            false => Ok(()),
+        }
+    })
+}
+
+/// Compares two layer streams, or a layer stream (old) and an image (new, if new_is_image is
+/// set), and returns the paths which were added, removed or modified, like image::diff().
+pub fn diff_layer(
+    repo: &Repository, old: &str, new: &str, new_is_image: bool, limits: &tar::EntryLimits
+) -> Result<Vec<image::Change>> {
+    let old_entries = tar::entries(&mut repo.open_stream(old)?, limits)?;
+
+    if new_is_image {
+        let dump = repo.dump_image(new)?;
+        image::diff_entries(repo, &old_entries, &image::parse_entries(&dump)?)
+    } else {
+        let new_entries = tar::entries(&mut repo.open_stream(new)?, limits)?;
+        image::diff_entries(repo, &old_entries, &new_entries)
+    }
 }
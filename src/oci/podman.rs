@@ -0,0 +1,446 @@
+/* Exporting layers as an image in a podman (containers/storage) image store
+ *
+ * podman can use read-only "additional image stores" (see additionalimagestores in
+ * storage.conf), which are laid out like its own storage with the overlay driver:
+ *
+ *   overlay/<layer id>/diff     the extracted content of the layer, with overlayfs whiteouts
+ *   overlay/<layer id>/link     a short name for the layer, which is a symlink in overlay/l/
+ *   overlay/<layer id>/lower    the short names of the layers below it
+ *   overlay-layers/layers.json  the list of layers
+ *   overlay-images/images.json  the list of images
+ *   overlay-images/<image id>/  the "big data" of an image: its manifest and its config
+ *
+ * The layer ids are the chain ids of the layers, and the image id is the digest of its config,
+ * like podman computes them when pulling an image, so layers are shared with other images in
+ * the store.  The config is a minimal one, with all timestamps at the epoch: exporting the same
+ * layers gives the same image id.
+ *
+ * The existing entries in layers.json and images.json are kept as they are, except for entries
+ * with the id of one we're writing.  Each file is updated with its lock file held, the same way
+ * that containers/storage locks it, so that podman doesn't see it half-way.
+ */
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{
+        ErrorKind,
+        Read,
+        Write,
+    },
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::FileExt,
+        },
+    },
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use rand::{
+    Rng,
+    distributions::Uniform,
+};
+use rustix::{
+    fs::{
+        FileType,
+        FlockOperation,
+        Mode,
+        OFlags,
+        ResolveFlags,
+        XattrFlags,
+        fcntl_lock,
+        fsetxattr,
+        mkdirat,
+        mknodat,
+        open,
+        openat,
+        openat2,
+    },
+    io::Errno,
+};
+use serde_json::{
+    Map,
+    Value,
+    json,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use crate::{
+    error::Error,
+    json::Json,
+    progress,
+    repository::Repository,
+    util::base64_encode,
+};
+
+// The config and the layers are uncompressed
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+const EPOCH: &str = "1970-01-01T00:00:00Z";
+
+/// Computes the digest and the size of what's written to it.
+#[derive(Default)]
+struct DigestWriter {
+    context: Sha256,
+    size: u64,
+}
+
+impl Write for DigestWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.context.update(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The file name that containers/storage uses for a big data item of an image.
+fn big_data_filename(key: &str) -> String {
+    if key.bytes().all(|c| c == b'.' || c.is_ascii_digit() || c.is_ascii_lowercase()) {
+        key.to_string()
+    } else {
+        format!("={}", base64_encode(key.as_bytes()))
+    }
+}
+
+/// The OCI name of the architecture we're running on.
+fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        arch => arch,
+    }
+}
+
+/// Runs f with the lock file at path held, and then marks the files that it protects as modified.
+fn with_store_lock<T>(path: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    // containers/storage uses POSIX locks, which don't interact with flock()
+    let lock = File::options().read(true).write(true).create(true).truncate(false).open(path)?;
+    fcntl_lock(&lock, FlockOperation::LockExclusive).with_context(|| format!("Failed to lock {path:?}"))?;
+    let result = f()?;
+    // podman only re-reads the files after the content of the lock file (the "last writer")
+    // changes, so write a new random one
+    let mut last_writer = [0u8; 64];
+    rand::thread_rng().fill(&mut last_writer[..]);
+    lock.write_all_at(&last_writer, 0)?;
+    Ok(result)
+}
+
+/// Adds entries to a JSON array of objects in the file at path, replacing existing entries with
+/// the same ids.  The lock file (at lock_path) is held while doing so.
+fn update_json_list(path: &Path, lock_path: &Path, entries: Vec<Value>) -> Result<()> {
+    with_store_lock(lock_path, || {
+        let mut elements: Vec<Value> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Failed to read {path:?}"))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => vec![],
+            Err(err) => Err(err)?,
+        };
+
+        let id = |entry: &Value| entry.get("id").and_then(Value::as_str).map(str::to_string);
+        let new_ids: Vec<_> = entries.iter().filter_map(id).collect();
+        elements.retain(|element| !id(element).is_some_and(|old| new_ids.contains(&old)));
+        elements.extend(entries);
+
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&elements)?)?;
+        Ok(std::fs::rename(&tmp, path)?)
+    })
+}
+
+/// A random name for a layer in overlay/l/, like containers/storage makes them.
+fn link_name() -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    rand::thread_rng().sample_iter(Uniform::from(0..ALPHABET.len()))
+        .take(26)
+        .map(|idx| ALPHABET[idx] as char)
+        .collect()
+}
+
+/// The path of a layer entry, relative to the top of the layer.  Paths which are absolute or
+/// contain '..' are refused, since they'd point outside of it.
+fn relative_path(path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {},
+            _ => bail!(Error::InvalidFormat(format!("Layer entry with an absolute path or '..': {path:?}"))),
+        }
+    }
+    Ok(relative)
+}
+
+/// Opens the directory at path (relative, see relative_path()) beneath dirfd, creating it and its
+/// parents if needed.  Symlinks aren't followed: in an untrusted layer, they could point anywhere.
+fn open_dir_beneath(dirfd: &OwnedFd, path: &Path) -> Result<OwnedFd> {
+    let mut current: Option<OwnedFd> = None;
+    for name in path.iter() {
+        let parent = current.as_ref().unwrap_or(dirfd);
+        match mkdirat(parent, name, 0o755.into()) {
+            Ok(()) | Err(Errno::EXIST) => {},
+            Err(err) => Err(err).with_context(|| format!("Failed to create {path:?}"))?,
+        }
+        let fd = openat2(
+            parent, name, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC, Mode::empty(),
+            ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS
+        ).with_context(|| format!("Failed to open {path:?} (without following symlinks)"))?;
+        current = Some(fd);
+    }
+    match current {
+        Some(fd) => Ok(fd),
+        None => Ok(openat(dirfd, ".", OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())?),
+    }
+}
+
+/// Extracts a tar layer into dir, turning OCI whiteouts into overlayfs ones.
+fn extract_layer<R: Read>(layer: R, dir: &Path) -> Result<()> {
+    let dirfd = open(dir, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())?;
+    let mut archive = ::tar::Archive::new(layer);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(rustix::process::getuid().is_root());
+    archive.set_unpack_xattrs(true);
+    archive.set_overwrite(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        // The whiteouts are created by us rather than by unpack_in(), which would check the path
+        let path = relative_path(&entry.path()?)?;
+        let (parent, filename) = (path.parent().unwrap_or(Path::new("")), path.file_name().unwrap_or_default());
+
+        if filename == ".wh..wh..opq" {
+            let parent_fd = open_dir_beneath(&dirfd, parent)?;
+            fsetxattr(&parent_fd, "trusted.overlay.opaque", b"y", XattrFlags::empty())
+                .with_context(|| format!("Failed to mark {parent:?} as opaque"))?;
+        } else if let Some(name) = filename.as_bytes().strip_prefix(b".wh.") {
+            let parent_fd = open_dir_beneath(&dirfd, parent)?;
+            mknodat(&parent_fd, OsStr::from_bytes(name), FileType::CharacterDevice, Mode::empty(), 0)
+                .with_context(|| format!("Failed to create whiteout for {:?}", parent.join(OsStr::from_bytes(name))))?;
+        } else if !entry.unpack_in(dir)? {
+            progress::warn(format!("Skipped {path:?}, which is outside of the layer"));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the layer streams (lowest layer first) as an image in the podman image store at
+/// store, with the given names (like "localhost/myimage:latest").  Returns the image id.
+pub fn export(repo: &Repository, store: &Path, layers: &[String], names: &[String]) -> Result<String> {
+    for dir in ["overlay/l", "overlay-layers", "overlay-images"] {
+        std::fs::create_dir_all(store.join(dir))?;
+    }
+
+    let mut layer_entries = vec![];
+    let mut diff_ids = vec![];
+    let mut manifest_layers = vec![];
+    let mut chain: Option<String> = None;
+    let mut lower: Vec<String> = vec![];
+
+    for layer in layers {
+        let mut digest = DigestWriter::default();
+        repo.merge_splitstream(layer, &mut digest)?;
+        let diff_id = hex::encode(digest.context.finalize());
+
+        let id = match &chain {
+            None => diff_id.clone(),
+            Some(parent) => sha256_hex(format!("sha256:{parent} sha256:{diff_id}").as_bytes()),
+        };
+
+        let layer_dir = store.join("overlay").join(&id);
+        let link = match std::fs::read_to_string(layer_dir.join("link")) {
+            Ok(link) => link.trim().to_string(),  // we have this layer already
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                // Extract to a temporary directory first, so that an interrupted export doesn't
+                // leave a partial layer behind.
+                let tmp_dir = store.join("overlay").join(format!("{id}.tmp"));
+                if tmp_dir.exists() {
+                    std::fs::remove_dir_all(&tmp_dir)?;
+                }
+                std::fs::create_dir_all(tmp_dir.join("diff"))?;
+                extract_layer(repo.read_splitstream(layer)?, &tmp_dir.join("diff"))
+                    .with_context(|| format!("Failed to extract layer {layer}"))?;
+
+                let link = link_name();
+                std::fs::write(tmp_dir.join("link"), &link)?;
+                if !lower.is_empty() {
+                    std::fs::write(tmp_dir.join("lower"), lower.join(":"))?;
+                }
+                std::os::unix::fs::symlink(format!("../{id}/diff"), store.join("overlay/l").join(&link))?;
+                std::fs::rename(&tmp_dir, &layer_dir)?;
+                link
+            },
+            Err(err) => Err(err)?,
+        };
+        lower.insert(0, format!("l/{link}"));
+
+        let mut entry = json!({
+            "id": id,
+            "created": EPOCH,
+            "compressed-diff-digest": format!("sha256:{diff_id}"),
+            "compressed-size": digest.size,
+            "diff-digest": format!("sha256:{diff_id}"),
+            "diff-size": digest.size,
+            "compression": 0,
+        });
+        if let Some(parent) = &chain {
+            entry["parent"] = json!(parent);
+        }
+        layer_entries.push(entry);
+
+        manifest_layers.push(Json::Object(vec![
+            ("mediaType", Json::string(LAYER_MEDIA_TYPE)),
+            ("digest", Json::string(format!("sha256:{diff_id}"))),
+            ("size", Json::Number(digest.size)),
+        ]));
+        diff_ids.push(Json::string(format!("sha256:{diff_id}")));
+        chain = Some(id);
+    }
+    let top_layer = chain.context("An image needs at least one layer")?;
+
+    let config = Json::Object(vec![
+        ("created", Json::string(EPOCH)),
+        ("architecture", Json::string(architecture())),
+        ("os", Json::string("linux")),
+        ("config", Json::Object(vec![])),
+        ("rootfs", Json::Object(vec![
+            ("type", Json::string("layers")),
+            ("diff_ids", Json::Array(diff_ids)),
+        ])),
+    ]).to_string();
+    let image_id = sha256_hex(config.as_bytes());
+
+    let manifest = Json::Object(vec![
+        ("schemaVersion", Json::Number(2)),
+        ("mediaType", Json::string(MANIFEST_MEDIA_TYPE)),
+        ("config", Json::Object(vec![
+            ("mediaType", Json::string(CONFIG_MEDIA_TYPE)),
+            ("digest", Json::string(format!("sha256:{image_id}"))),
+            ("size", Json::Number(config.len() as u64)),
+        ])),
+        ("layers", Json::Array(manifest_layers)),
+    ]).to_string();
+    let manifest_digest = format!("sha256:{}", sha256_hex(manifest.as_bytes()));
+
+    let big_data = [
+        (format!("sha256:{image_id}"), &config),
+        (format!("manifest-{manifest_digest}"), &manifest),
+        ("manifest".to_string(), &manifest),
+    ];
+    let image_dir = store.join("overlay-images").join(&image_id);
+    std::fs::create_dir_all(&image_dir)?;
+    for (key, data) in big_data.iter() {
+        std::fs::write(image_dir.join(big_data_filename(key)), data)?;
+    }
+
+    let image = json!({
+        "id": image_id,
+        "digest": manifest_digest,
+        "names": names,
+        "layer": top_layer,
+        "metadata": "{}",
+        "big-data-names": big_data.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+        "big-data-sizes": big_data.iter().map(|(key, data)| (key.clone(), json!(data.len()))).collect::<Map<_, _>>(),
+        "big-data-digests": big_data.iter().map(|(key, data)| {
+            (key.clone(), json!(format!("sha256:{}", sha256_hex(data.as_bytes()))))
+        }).collect::<Map<_, _>>(),
+        "created": EPOCH,
+    });
+
+    // Layers first: an image which refers to layers that aren't there would break the store
+    update_json_list(
+        &store.join("overlay-layers/layers.json"), &store.join("overlay-layers/layers.lock"), layer_entries
+    )?;
+    update_json_list(
+        &store.join("overlay-images/images.json"), &store.join("overlay-images/images.lock"),
+        vec![image]
+    )?;
+
+    Ok(image_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::*;
+
+    #[test]
+    fn update() {
+        let dir = std::env::temp_dir().join(format!("composefs-podman-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, lock) = (dir.join("layers.json"), dir.join("layers.lock"));
+        std::fs::write(&path, r#"[{"id":"a","parent":"b"},{"id":"b"},{"id":"c","parent":"a"}]"#).unwrap();
+        update_json_list(&path, &lock, vec![json!({ "id": "a" })]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            r#"[{"id":"b"},{"id":"c","parent":"a"},{"id":"a"}]"#
+        );
+        assert_eq!(std::fs::read(&lock).unwrap().len(), 64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn layer(entries: &[(&[u8], ::tar::EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = ::tar::Builder::new(vec![]);
+        for (name, entry_type, link) in entries {
+            // Set the names directly: Header::set_path() would refuse the malicious ones
+            let mut header = ::tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name);
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_cksum();
+            builder.append(&header, std::io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn malicious_whiteouts() {
+        let base = std::env::temp_dir().join(format!("composefs-podman-layer-{}", std::process::id()));
+        let (dir, outside) = (base.join("layer"), base.join("outside"));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        let regular = ::tar::EntryType::Regular;
+
+        assert!(extract_layer(&layer(&[(b"../outside/.wh.x", regular, b"")])[..], &dir).is_err());
+        assert!(extract_layer(&layer(&[(b"a/../../outside/.wh..wh..opq", regular, b"")])[..], &dir).is_err());
+        let absolute = format!("{}/.wh.x", outside.display());
+        assert!(extract_layer(&layer(&[(absolute.as_bytes(), regular, b"")])[..], &dir).is_err());
+
+        // A symlink in the layer mustn't be followed to create the whiteout somewhere else
+        let target = outside.as_os_str().as_bytes();
+        let symlink = layer(&[(b"link", ::tar::EntryType::Symlink, target), (b"link/.wh.x", regular, b"")]);
+        assert!(extract_layer(&symlink[..], &dir).is_err());
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+
+        // ...but regular whiteouts still work
+        extract_layer(&layer(&[(b"./a/b/.wh.c", regular, b""), (b"d/.wh.e", regular, b"")])[..], &dir).unwrap();
+        assert!(std::fs::symlink_metadata(dir.join("a/b/c")).unwrap().file_type().is_char_device());
+        assert!(std::fs::symlink_metadata(dir.join("d/e")).unwrap().file_type().is_char_device());
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     ffi::{
         OsStr,
         OsString,
@@ -9,6 +10,7 @@ use std::{
         OsStringExt,
     },
     path::{
+        Component,
         Path,
         PathBuf,
     },
@@ -39,52 +41,141 @@ use tar::{
 };
 
 use crate::{
+    error::Error,
+    fscaps::{
+        self,
+        FileCaps,
+    },
     fsverity::Sha256HashValue,
     splitstream::{
         SplitStreamData,
         SplitStreamReader,
         SplitStreamWriter,
     },
-    util::read_exactish,
 };
 
-fn read_header<R: Read>(reader: &mut R) -> Result<Option<Header>> {
-    let mut header = Header::new_gnu();
-    if read_exactish(reader, header.as_mut_bytes())? {
-        Ok(Some(header))
-    } else {
-        Ok(None)
+// The longest file name that can be stored in an erofs (and NAME_MAX)
+const MAX_NAME_LEN: usize = 255;
+// The longest symlink target that the kernel will follow (PATH_MAX, without the NUL)
+const MAX_SYMLINK_LEN: usize = 4095;
+// Anything stored inline in a splitstream gets loaded into memory when reading it back, and so
+// does the data of extension headers during splitting: don't let a bogus size exhaust it.
+const MAX_INLINE_SIZE: usize = 16 << 20;
+
+/// Limits on the entries which we accept from tar layers, so that a malicious layer can't produce
+/// an image which can't be mounted.
+pub struct EntryLimits {
+    /// the maximum number of components in a path
+    pub max_depth: usize,
+}
+
+impl Default for EntryLimits {
+    fn default() -> Self {
+        EntryLimits { max_depth: 256 }
+    }
+}
+
+/// Returns the size from the "size" record of a PAX extended header, if it has one.  This
+/// overrides the size in the header of the following entry, which can't represent sizes of 8GiB
+/// or more in ustar format.
+///
+/// GNU.sparse.* records make the following entry a sparse file, whose data isn't its content:
+/// those are refused, see sparse_error().
+fn pax_entry_size(content: &[u8]) -> Result<Option<u64>> {
+    let mut size = None;
+    for item in PaxExtensions::new(content) {
+        let extension = item?;
+        if extension.key_bytes() == b"size" {
+            size = Some(extension.value()?.parse()?);
+        } else if extension.key_bytes().starts_with(b"GNU.sparse.") {
+            bail!(sparse_error(&format!("PAX record {}", String::from_utf8_lossy(extension.key_bytes()))));
+        }
     }
- }
+    Ok(size)
+}
+
+/// GNU tar has several formats for sparse files: type 'S' entries, whose sparse map can continue
+/// in extra blocks between the header and the data, and regular entries with GNU.sparse.* PAX
+/// records, where the map can be at the start of the data.  Either way, the data of the entry
+/// isn't the content of the file, and we'd get it wrong (or lose our place in the stream) if we
+/// read it as such, so they're refused.
+fn sparse_error(what: &str) -> Error {
+    Error::InvalidFormat(format!("Sparse files aren't supported ({what})"))
+}
+
+/// The size of an entry's data in the tar stream, which is padded to a whole number of blocks.
+fn padded_size(size: usize) -> Result<usize> {
+    match size.checked_add(511) {
+        Some(size) => Ok(size & !511),
+        None => bail!(Error::InvalidFormat(format!("Tar entry is too large ({size} bytes)"))),
+    }
+}
 
 /// Splits the tar file from tar_stream into a Split Stream.  The store_data function is
 /// responsible for ensuring that "external data" is in the composefs repository and returns the
 /// fsverity hash value of that data.
-pub fn split<R: Read, W: Write, F: FnMut(&[u8]) -> Result<Sha256HashValue>>(
+///
+/// Merging the Split Stream gives back exactly the original tar file, including extension headers,
+/// padding and anything after the end-of-archive marker.  Sparse files are refused.
+pub fn split<R: Read, W: Write, F: FnMut(Vec<u8>) -> Result<Sha256HashValue>>(
     tar_stream: &mut R,
     split_stream: &mut W,
     mut store_data: F,
 ) -> Result<()> {
     let mut writer = SplitStreamWriter::new(split_stream);
+    let mut pax_size = None;
+
+    loop {
+        let mut block = vec![];
+        tar_stream.by_ref().take(512).read_to_end(&mut block)?;
 
-    while let Some(header) = read_header(tar_stream)? {
         // the header always gets stored as inline data
-        writer.write_inline(header.as_bytes());
+        writer.write_inline(&block);
 
-        if header.as_bytes() == &[0u8; 512] {
-            continue;
+        if block.len() < 512 {
+            // end of input, possibly with a partial block of trailing garbage
+            break;
+        }
+
+        if block == [0u8; 512] {
+            // The end-of-archive marker.  What follows is usually just more zeros (padding to the
+            // record size), but we don't interpret it: it's stored as-is.
+            let mut trailer = vec![];
+            tar_stream.read_to_end(&mut trailer)?;
+            writer.write_inline(&trailer);
+            break;
         }
 
         // read the corresponding data, if there is any
-        let actual_size = header.entry_size()? as usize;
-        let storage_size = (actual_size + 511) & !511;
-        let mut buffer = vec![0u8; storage_size];
-        tar_stream.read_exact(&mut buffer)?;
+        let header = Header::from_byte_slice(&block);
+        let actual_size = match pax_size.take() {
+            Some(size) => size,
+            None => header.entry_size()?,
+        } as usize;
+        let storage_size = padded_size(actual_size)?;
+        if header.entry_type() == EntryType::GNUSparse {
+            bail!(sparse_error(&format!("{:?}", header.path()?)));
+        }
+        let is_file = matches!(header.entry_type(), EntryType::Regular | EntryType::Continuous);
+        if !is_file && actual_size > MAX_INLINE_SIZE {
+            bail!(Error::InvalidFormat(format!("Tar entry of type {:?} is too large ({actual_size} bytes)", header.entry_type())));
+        }
+        // Don't trust the size in the header with an allocation: the buffer only grows as far as
+        // the data that's actually there.
+        let mut buffer = vec![];
+        tar_stream.by_ref().take(storage_size as u64).read_to_end(&mut buffer)?;
+        if buffer.len() < storage_size {
+            bail!(Error::InvalidFormat(format!("Tar entry is truncated: {:?}", header.path()?)));
+        }
+
+        if header.entry_type() == EntryType::XHeader {
+            pax_size = pax_entry_size(&buffer[..actual_size])?;
+        }
 
-        if header.entry_type() == EntryType::Regular && storage_size > 0 {
+        if is_file && storage_size > 0 {
             // non-empty regular file: store the data in the object store
             let padding = buffer.split_off(actual_size);
-            let reference = store_data(&buffer)?;
+            let reference = store_data(buffer)?;
             writer.write_reference(reference, padding)?;
         } else {
             // else: store the data inline in the split stream
@@ -96,13 +187,19 @@ pub fn split<R: Read, W: Write, F: FnMut(&[u8]) -> Result<Sha256HashValue>>(
     writer.done()
 }
 
+/// The content of GNU long name and long link entries is NUL-terminated.
+fn gnu_name(content: &[u8]) -> &[u8] {
+    let end = content.iter().position(|c| *c == 0).unwrap_or(content.len());
+    &content[..end]
+}
+
 fn path_from_tar(pax: Option<Vec<u8>>, gnu: Vec<u8>, short: &[u8]) -> PathBuf {
     // Prepend leading /
     let mut path = vec![b'/'];
     if let Some(name) = pax {
         path.extend(name);
     } else if !gnu.is_empty() {
-        path.extend(gnu);
+        path.extend(gnu_name(&gnu));
     } else {
         path.extend(short);
     }
@@ -121,21 +218,300 @@ fn symlink_target_from_tar(pax: Option<Vec<u8>>, gnu: Vec<u8>, short: &[u8]) ->
     if let Some(name) = pax {
         PathBuf::from(OsString::from_vec(name))
     } else if !gnu.is_empty() {
-        PathBuf::from(OsString::from_vec(gnu))
+        PathBuf::from(OsStr::from_bytes(gnu_name(&gnu)))
     } else {
         PathBuf::from(OsStr::from_bytes(short))
     }
 }
 
-fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<'static>>> {
+/// A single entry from a tar stream in its raw form: all of the header blocks (any GNU long
+/// name/link or PAX extended headers which precede it, each with its content, and then the header
+/// of the entry itself) and its content, which might be a reference to an external object.
+struct RawEntry {
+    path: PathBuf,
+    is_dir: bool,
+    link_target: Option<PathBuf>,
+    headers: Vec<Vec<u8>>,
+    size: usize,
+    data: SplitStreamData,
+    padding: usize,
+    // assigned when the entry is added to the merged tree
+    serial: usize,
+    linked: Option<LinkedContent>,
+}
+
+/// The content of the file a hardlink pointed to at the time that the hardlink was added to the
+/// merged tree.  serial identifies the target entry.
+#[derive(Clone)]
+struct LinkedContent {
+    serial: usize,
+    size: usize,
+    data: SplitStreamData,
+    padding: usize,
+}
+
+fn get_raw_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<RawEntry>> {
+    let mut headers = vec![];
     let mut gnu_longlink: Vec<u8> = vec![];
     let mut gnu_longname: Vec<u8> = vec![];
     let mut pax_longlink: Option<Vec<u8>> = None;
     let mut pax_longname: Option<Vec<u8>> = None;
-    let mut xattrs = vec![];
+    let mut pax_size: Option<u64> = None;
+
+    loop {
+        let mut buf = [0u8; 512];
+        if !reader.read_inline_exact(&mut buf)? || buf == [0u8; 512] {
+            return Ok(None);
+        }
+
+        let header = tar::Header::from_byte_slice(&buf);
+        if header.entry_type() == EntryType::GNUSparse {
+            bail!(sparse_error(&format!("{:?}", header.path()?)));
+        }
+        let size = match pax_size.take() {
+            Some(size) => size,
+            None => header.entry_size()?,
+        } as usize;
+        let stored_size = padded_size(size)?;
+        let data = reader.read_exact(size, stored_size)?;
 
-    // no root entry in the tar
-    println!("{}", Entry {
+        if let SplitStreamData::Inline(content) = &data {
+            match header.entry_type() {
+                EntryType::GNULongName | EntryType::GNULongLink | EntryType::XHeader | EntryType::XGlobalHeader => {
+                    if header.entry_type() == EntryType::GNULongName {
+                        gnu_longname.extend(content);
+                    } else if header.entry_type() == EntryType::GNULongLink {
+                        gnu_longlink.extend(content);
+                    } else if header.entry_type() == EntryType::XHeader {
+                        for item in PaxExtensions::new(content) {
+                            let extension = item?;
+                            if extension.key_bytes() == b"path" {
+                                pax_longname = Some(Vec::from(extension.value_bytes()));
+                            } else if extension.key_bytes() == b"linkpath" {
+                                pax_longlink = Some(Vec::from(extension.value_bytes()));
+                            }
+                        }
+                        pax_size = pax_entry_size(content)?;
+                    }
+                    // these become part of the header of the next entry
+                    let mut extension = buf.to_vec();
+                    extension.extend(content);
+                    extension.resize(512 + stored_size, 0);
+                    headers.push(extension);
+                    continue;
+                },
+                _ => {}
+            }
+        }
+
+        let link_target = match (header.entry_type(), header.link_name_bytes()) {
+            (EntryType::Link, Some(link_name)) => Some(path_from_tar(pax_longlink, gnu_longlink, &link_name)),
+            _ => None,
+        };
+
+        headers.push(buf.to_vec());
+        return Ok(Some(RawEntry {
+            path: path_from_tar(pax_longname, gnu_longname, &header.path_bytes()),
+            is_dir: header.entry_type() == EntryType::Directory,
+            link_target,
+            headers,
+            size,
+            data,
+            padding: stored_size - size,
+            serial: 0,
+            linked: None,
+        }));
+    }
+}
+
+/// Removes the given path, and anything underneath it, from the merged tree.
+fn remove_subtree(tree: &mut BTreeMap<PathBuf, RawEntry>, path: &Path, keep_self: bool) {
+    let doomed: Vec<PathBuf> = tree.range(path.to_path_buf()..)
+        .map(|(name, _)| name)
+        .take_while(|name| name.starts_with(path))
+        .filter(|name| !keep_self || name.as_path() != path)
+        .cloned()
+        .collect();
+
+    for name in doomed {
+        tree.remove(&name);
+    }
+}
+
+/// Records what a hardlink which is about to be added to the tree points to.  If the target is
+/// itself a hardlink, we take the content that it pointed to.
+fn linked_content(tree: &BTreeMap<PathBuf, RawEntry>, target: &Path) -> Option<LinkedContent> {
+    let entry = tree.get(target)?;
+    match &entry.linked {
+        Some(linked) => Some(LinkedContent { serial: entry.serial, ..linked.clone() }),
+        None if entry.link_target.is_none() => Some(LinkedContent {
+            serial: entry.serial,
+            size: entry.size,
+            data: entry.data.clone(),
+            padding: entry.padding,
+        }),
+        None => None,
+    }
+}
+
+/// Removes the records with the given keys from the content of a PAX extended header.  The others
+/// are kept exactly as they were.
+fn strip_pax_records(content: &[u8], keys: &[&[u8]]) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidFormat("Invalid PAX extended header".to_string());
+    let mut kept = vec![];
+    let mut rest = content;
+    while !rest.is_empty() {
+        // "<length> <key>=<value>\n", where the length includes itself
+        let space = rest.iter().position(|c| *c == b' ').ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok().and_then(|len| len.parse().ok()).ok_or_else(invalid)?;
+        let record = rest.get(..len).filter(|_| len > space).ok_or_else(invalid)?;
+        let key = record[space + 1..].split(|c| *c == b'=').next().unwrap_or_default();
+        if !keys.contains(&key) {
+            kept.extend(record);
+        }
+        rest = &rest[len..];
+    }
+    Ok(kept)
+}
+
+/// Turns a hardlink entry into a regular file with the given content, by rewriting its header.
+/// The link name goes away from the extension headers too: GNU long link entries are dropped, and
+/// so are the linkpath (and size) records of PAX headers, along with the header if that was all
+/// that it had.
+fn materialize_link(entry: &mut RawEntry, content: &LinkedContent) -> Result<()> {
+    let rewrite = |block: &[u8], update: &dyn Fn(&mut Header)| {
+        let mut header = Header::new_old();
+        header.as_mut_bytes().copy_from_slice(&block[..512]);
+        update(&mut header);
+        header.set_cksum();
+        header.as_bytes().to_vec()
+    };
+
+    let mut headers = vec![];
+    let (last, extensions) = entry.headers.split_last().expect("an entry has a header");
+    for extension in extensions {
+        let header = Header::from_byte_slice(&extension[..512]);
+        match header.entry_type() {
+            EntryType::GNULongLink => {},
+            EntryType::XHeader => {
+                let content = extension.get(512..512 + header.entry_size()? as usize)
+                    .ok_or_else(|| Error::InvalidFormat("Invalid PAX extended header".to_string()))?;
+                let records = strip_pax_records(content, &[b"linkpath", b"size"])?;
+                if !records.is_empty() {
+                    let mut block = rewrite(extension, &|header| header.set_size(records.len() as u64));
+                    block.extend(&records);
+                    block.resize(512 + padded_size(records.len())?, 0);
+                    headers.push(block);
+                }
+            },
+            _ => headers.push(extension.clone()),
+        }
+    }
+    headers.push(rewrite(last, &|header| {
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(content.size as u64);
+        header.as_old_mut().linkname = [0; 100];
+    }));
+
+    entry.headers = headers;
+    entry.link_target = None;
+    entry.size = content.size;
+    entry.data = content.data.clone();
+    entry.padding = content.padding;
+    Ok(())
+}
+
+/// Merges a number of tar layers (given as splitstreams, lowest layer first) into a single tar
+/// stream, applying the overlayfs-style whiteouts found in the upper layers.  The result is
+/// written as a new splitstream.  No file content is read: the new stream refers to the same
+/// external objects as the layers it was created from.
+pub fn squash<R: Read, W: Write>(layers: &mut [R], split_stream: &mut W) -> Result<()> {
+    let mut tree = BTreeMap::<PathBuf, RawEntry>::new();
+    let mut serial = 0;
+
+    for layer in layers.iter_mut() {
+        let mut reader = SplitStreamReader::new(layer);
+
+        // Whiteouts only apply to the layers below, so read the entire layer before merging it.
+        let mut entries = vec![];
+        while let Some(entry) = get_raw_entry(&mut reader)? {
+            entries.push(entry);
+        }
+
+        for entry in entries.iter() {
+            let (Some(parent), Some(filename)) = (entry.path.parent(), entry.path.file_name()) else {
+                continue;
+            };
+            if filename == ".wh..wh..opq" {
+                remove_subtree(&mut tree, parent, true);
+            } else if let Some(name) = filename.as_bytes().strip_prefix(b".wh.") {
+                remove_subtree(&mut tree, &parent.join(OsStr::from_bytes(name)), false);
+            }
+        }
+
+        for mut entry in entries {
+            if entry.path.file_name().is_some_and(|name| name.as_bytes().starts_with(b".wh.")) {
+                continue;
+            }
+            serial += 1;
+            entry.serial = serial;
+            if let Some(target) = &entry.link_target {
+                entry.linked = linked_content(&tree, target);
+            }
+            // A directory replacing a directory merges with it, but anything else hides the old
+            // content entirely.
+            if !entry.is_dir || tree.get(&entry.path).is_some_and(|old| !old.is_dir) {
+                remove_subtree(&mut tree, &entry.path, false);
+            }
+            tree.insert(entry.path.clone(), entry);
+        }
+    }
+
+    // A hardlink still refers to the right file if its target hasn't been replaced (in a later
+    // layer, or later in the same layer) since the link was added.  Otherwise, the link gets the
+    // content that its target had at the time, just like it would in an overlayfs.
+    let mut links = vec![];
+    for (path, entry) in tree.iter() {
+        if let (Some(target), Some(linked)) = (&entry.link_target, &entry.linked) {
+            if tree.get(target).is_none_or(|t| t.serial != linked.serial || t.link_target.is_some()) {
+                links.push(path.clone());
+            }
+        }
+    }
+    for path in links {
+        let entry = tree.get_mut(&path).expect("path came from the tree");
+        let content = entry.linked.take().expect("link has content");
+        materialize_link(entry, &content)?;
+    }
+
+    // The remaining hardlinks go last, after their targets, since tar can only link to files that
+    // were already extracted.
+    let (links, others): (Vec<_>, Vec<_>) = tree.into_values().partition(|entry| entry.link_target.is_some());
+
+    let mut writer = SplitStreamWriter::new(split_stream);
+    for entry in others.into_iter().chain(links) {
+        for header in &entry.headers {
+            writer.write_inline(header);
+        }
+        match entry.data {
+            SplitStreamData::Inline(content) => {
+                writer.write_inline(&content);
+                writer.write_inline(&vec![0u8; entry.padding]);
+            },
+            SplitStreamData::External(id) => {
+                writer.write_reference(id, vec![0u8; entry.padding])?;
+            }
+        }
+    }
+
+    // end-of-archive marker
+    writer.write_inline(&[0u8; 1024]);
+    writer.done()
+}
+
+/// Tar layers have no entry for the root directory, so we make one up.
+fn root_entry() -> Entry<'static> {
+    Entry {
         path: Cow::Borrowed(Path::new("/")),
         uid: 0,
         gid: 0,
@@ -143,7 +519,16 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
         mtime: Mtime { sec: 0, nsec: 0 },
         item: Item::Directory { size: 0, nlink: 1 },
         xattrs: vec![]
-    });
+    }
+}
+
+fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<'static>>> {
+    let mut gnu_longlink: Vec<u8> = vec![];
+    let mut gnu_longname: Vec<u8> = vec![];
+    let mut pax_longlink: Option<Vec<u8>> = None;
+    let mut pax_longname: Option<Vec<u8>> = None;
+    let mut pax_size: Option<u64> = None;
+    let mut xattrs = vec![];
 
     loop {
         let mut buf = [0u8; 512];
@@ -152,19 +537,24 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
         }
 
         let header = tar::Header::from_byte_slice(&buf);
-        assert!(header.as_ustar().is_some());
+        if header.entry_type() == EntryType::GNUSparse {
+            bail!(sparse_error(&format!("{:?}", header.path()?)));
+        }
 
         let nlink = 1;
-        let size = header.entry_size()?;
+        let size = match pax_size.take() {
+            Some(size) => size,
+            None => header.entry_size()?,
+        };
 
-        let item = match reader.read_exact(size as usize, ((size + 511) & !511) as usize)? {
+        let item = match reader.read_exact(size as usize, padded_size(size as usize)?)? {
             SplitStreamData::External(id) => match header.entry_type() {
                 EntryType::Regular | EntryType::Continuous => Item::Regular {
                     fsverity_digest: Some(hex::encode(id)),
                     inline_content: None,
                     nlink, size
                 },
-                _ => bail!("Unsupported external-chunked entry {:?} {}", header, hex::encode(id)),
+                _ => bail!(Error::InvalidFormat(format!("Unsupported external-chunked entry {:?} {}", header, hex::encode(id)))),
             },
             SplitStreamData::Inline(content) => match header.entry_type() {
                 EntryType::GNULongLink => {
@@ -176,21 +566,25 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
                     continue;
                 },
                 EntryType::XGlobalHeader => {
-                    todo!();
+                    // Typically just a comment (like the commit id from git archive).
+                    continue;
                 },
                 EntryType::XHeader => {
+                    pax_size = pax_entry_size(&content)?;
                     for item in PaxExtensions::new(&content) {
                         let extension = item?;
-                        let key = extension.key()?;
+                        // xattr names are bytes, and so are the keys of their records: don't
+                        // require them to be UTF-8.
+                        let key = extension.key_bytes();
                         let value = Vec::from(extension.value_bytes());
 
-                        if key == "path" {
+                        if key == b"path" {
                             pax_longname = Some(value);
-                        } else if key == "linkpath" {
+                        } else if key == b"linkpath" {
                             pax_longlink = Some(value);
-                        } else if let Some(xattr) = key.strip_prefix("SCHILY.xattr.") {
+                        } else if let Some(xattr) = key.strip_prefix(b"SCHILY.xattr.") {
                             xattrs.push(Xattr {
-                                key: Cow::Owned(OsString::from(xattr)),
+                                key: Cow::Owned(OsString::from_vec(xattr.to_vec())),
                                 value: Cow::Owned(value)
                             });
                         }
@@ -205,13 +599,13 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
                 },
                 EntryType::Link => Item::Hardlink {
                     target: {
-                        let Some(link_name) = header.link_name_bytes() else { bail!("link without a name?") };
+                        let Some(link_name) = header.link_name_bytes() else { bail!(Error::InvalidFormat("link without a name?".to_string())) };
                         Cow::Owned(path_from_tar(pax_longlink, gnu_longlink, &link_name))
                     }
                 },
                 EntryType::Symlink => Item::Symlink {
                     target: {
-                        let Some(link_name) = header.link_name_bytes() else { bail!("symlink without a name?") };
+                        let Some(link_name) = header.link_name_bytes() else { bail!(Error::InvalidFormat("symlink without a name?".to_string())) };
                         Cow::Owned(symlink_target_from_tar(pax_longlink, gnu_longlink, &link_name))
                     },
                     nlink
@@ -219,14 +613,14 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
                 EntryType::Block | EntryType::Char => Item::Device {
                     rdev: match (header.device_major()?, header.device_minor()?) {
                         (Some(major), Some(minor)) => makedev(major, minor),
-                        _ => bail!("Device entry without device numbers?"),
+                        _ => bail!(Error::InvalidFormat("Device entry without device numbers?".to_string())),
                     },
                     nlink
                 },
+                // A fifo is only an inode: any content would have to be thrown away
+                EntryType::Fifo if !content.is_empty() => bail!(Error::InvalidFormat(format!("Fifo with content: {:?}", header.path()?))),
                 EntryType::Fifo => Item::Fifo { nlink },
-                _ => {
-                    todo!("Unsupported entry {:?} {:?}", header, content);
-                }
+                entry_type => bail!(Error::InvalidFormat(format!("Unsupported entry type {entry_type:?}: {:?}", header.path()?))),
             }
         };
 
@@ -259,10 +653,418 @@ fn get_entry<R: Read>(reader: &mut SplitStreamReader<R>) -> Result<Option<Entry<
     }
 }
 
-pub fn ls<R: Read>(split_stream: &mut R) -> Result<()> {
+/// Checks an entry against the limits: file names which are too long to store, symlinks which
+/// can't be followed, and paths which are nested too deeply or contain '..' are refused, and so
+/// are malformed file capabilities.
+fn check_entry(entry: &Entry, limits: &EntryLimits) -> Result<()> {
+    let mut depth = 0;
+    for component in entry.path.components() {
+        match component {
+            Component::Normal(name) if name.len() > MAX_NAME_LEN => {
+                bail!(Error::InvalidFormat(format!("File name too long ({} bytes) in {:?}", name.len(), entry.path)));
+            },
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => bail!(Error::InvalidFormat(format!("Path contains '..': {:?}", entry.path))),
+            _ => {},
+        }
+    }
+    if depth > limits.max_depth {
+        bail!(Error::InvalidFormat(format!("Path is nested too deeply ({depth} levels): {:?}", entry.path)));
+    }
+
+    if let Item::Symlink { target, .. } = &entry.item {
+        match target.as_os_str().len() {
+            0 => bail!(Error::InvalidFormat(format!("Symlink with empty target: {:?}", entry.path))),
+            n if n > MAX_SYMLINK_LEN => bail!(Error::InvalidFormat(format!("Symlink target too long ({n} bytes): {:?}", entry.path))),
+            _ => {},
+        }
+    }
+
+    for xattr in entry.xattrs.iter().filter(|xattr| *xattr.key == *fscaps::XATTR_NAME) {
+        if let Err(err) = FileCaps::parse(&xattr.value) {
+            bail!(Error::InvalidFormat(format!("{err}: {:?}", entry.path)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the entries of a layer in dumpfile format, after passing each of them to transform.
+pub fn ls<R: Read, F: FnMut(&mut Entry) -> Result<()>>(
+    split_stream: &mut R, limits: &EntryLimits, mut transform: F
+) -> Result<()> {
+    let mut root = root_entry();
+    transform(&mut root)?;
+    println!("{}", root);
+
     let mut reader = SplitStreamReader::new(split_stream);
-    while let Some(entry) = get_entry(&mut reader)? {
+    while let Some(mut entry) = get_entry(&mut reader)? {
+        check_entry(&entry, limits)?;
+        transform(&mut entry)?;
         println!("{}", entry);
     }
     Ok(())
 }
+
+/// Returns the entries of a layer, indexed by (normalized) path.  If the layer contains the same
+/// path more than once, the last entry wins, like when extracting it.
+pub fn entries<R: Read>(split_stream: &mut R, limits: &EntryLimits) -> Result<BTreeMap<PathBuf, Entry<'static>>> {
+    let mut entries = BTreeMap::from([(PathBuf::from("/"), root_entry())]);
+
+    let mut reader = SplitStreamReader::new(split_stream);
+    while let Some(entry) = get_entry(&mut reader)? {
+        check_entry(&entry, limits)?;
+        // drop the '.' components of paths like "./etc"
+        entries.insert(entry.path.components().collect(), entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use sha2::{
+        Digest,
+        Sha256,
+    };
+
+    use super::*;
+
+    // A layer with one entry of each type that tar can represent.  Sockets aren't among them:
+    // tar implementations skip them when creating archives.
+    fn header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    fn fixture() -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut add = |path: &str, entry_type: EntryType, mode: u32, data: &[u8], setup: &dyn Fn(&mut Header)| {
+            let mut header = header(entry_type, mode, data.len() as u64);
+            setup(&mut header);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        add("dir/", EntryType::Directory, 0o755, b"", &|_| {});
+        add("dir/file", EntryType::Regular, 0o644, b"hello", &|_| {});
+        add("dir/symlink", EntryType::Symlink, 0o777, b"", &|h| h.set_link_name("file").unwrap());
+        add("dir/hardlink", EntryType::Link, 0o644, b"", &|h| h.set_link_name("dir/file").unwrap());
+        add("dir/null", EntryType::Char, 0o666, b"", &|h| {
+            h.set_device_major(1).unwrap();
+            h.set_device_minor(3).unwrap();
+        });
+        add("dir/loop0", EntryType::Block, 0o660, b"", &|h| {
+            h.set_device_major(7).unwrap();
+            h.set_device_minor(0).unwrap();
+        });
+        add("dir/fifo", EntryType::Fifo, 0o600, b"", &|_| {});
+        builder.into_inner().unwrap()
+    }
+
+    // The layer as a splitstream which has everything inline, which is all that get_entry() needs
+    fn inline_stream(tar: &[u8]) -> Vec<u8> {
+        let mut stream = vec![];
+        let mut writer = SplitStreamWriter::new(&mut stream);
+        writer.write_inline(tar);
+        writer.done().unwrap();
+        stream
+    }
+
+    fn read_entries(stream: &[u8]) -> Result<Vec<Entry<'static>>> {
+        let mut stream = stream;
+        let mut reader = SplitStreamReader::new(&mut stream);
+        let mut entries = vec![];
+        while let Some(entry) = get_entry(&mut reader)? {
+            check_entry(&entry, &EntryLimits::default())?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn entry_types() {
+        let entries = read_entries(&inline_stream(&fixture())).unwrap();
+        let types: Vec<_> = entries.iter().map(|e| (e.path.to_str().unwrap(), FileType::from_raw_mode(e.mode))).collect();
+        assert_eq!(types, [
+            ("/dir", FileType::Directory),
+            ("/dir/file", FileType::RegularFile),
+            ("/dir/symlink", FileType::Symlink),
+            ("/dir/hardlink", FileType::RegularFile),
+            ("/dir/null", FileType::CharacterDevice),
+            ("/dir/loop0", FileType::BlockDevice),
+            ("/dir/fifo", FileType::Fifo),
+        ]);
+
+        assert!(matches!(entries[1].item, Item::Regular { size: 5, .. }));
+        assert!(matches!(&entries[2].item, Item::Symlink { target, .. } if target.as_ref() == Path::new("file")));
+        assert!(matches!(&entries[3].item, Item::Hardlink { target } if target.as_ref() == Path::new("/dir/file")));
+        assert!(matches!(entries[4].item, Item::Device { rdev, .. } if rdev == makedev(1, 3)));
+        assert!(matches!(entries[5].item, Item::Device { rdev, .. } if rdev == makedev(7, 0)));
+        assert!(matches!(entries[6].item, Item::Fifo { nlink: 1 }));
+        assert_eq!(entries[6].mode & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn fifo_with_content() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = header(EntryType::Fifo, 0o600, 3);
+        builder.append_data(&mut header, "fifo", &b"abc"[..]).unwrap();
+        assert!(read_entries(&inline_stream(&builder.into_inner().unwrap())).is_err());
+    }
+
+    #[test]
+    fn unsupported_entry_type() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = header(EntryType::new(b'V'), 0o644, 0);  // a GNU volume label
+        builder.append_data(&mut header, "label", &b""[..]).unwrap();
+        let err = read_entries(&inline_stream(&builder.into_inner().unwrap())).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::InvalidFormat(..))));
+    }
+
+    fn split_tar(tar: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = vec![];
+        split(&mut &tar[..], &mut stream, |_| Ok([0; 32]))?;
+        Ok(stream)
+    }
+
+    fn is_invalid_format(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref(), Some(Error::InvalidFormat(..)))
+    }
+
+    #[test]
+    fn oversized_entries() {
+        // A PAX size which doesn't leave room for the padding
+        let record = format!("29 size={}\n", u64::MAX);
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::XHeader, 0o644, record.len() as u64), "pax", record.as_bytes()).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 0), "file", &b""[..]).unwrap();
+        let tar = builder.into_inner().unwrap();
+        assert!(is_invalid_format(&split_tar(&tar).unwrap_err()));
+        assert!(is_invalid_format(&read_entries(&inline_stream(&tar)).unwrap_err()));
+
+        // A size that's much bigger than the data which follows
+        let mut block = header(EntryType::Regular, 0o644, 1 << 32);
+        block.set_path("file").unwrap();
+        block.set_cksum();
+        let mut tar = block.as_bytes().to_vec();
+        tar.extend([0u8; 512]);
+        assert!(is_invalid_format(&split_tar(&tar).unwrap_err()));
+        assert!(is_invalid_format(&read_entries(&inline_stream(&tar)).unwrap_err()));
+    }
+
+    // A PAX extended header with the given records
+    fn pax(records: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut content = vec![];
+        for (key, value) in records {
+            // the length includes itself
+            let len = key.len() + value.len() + 3;
+            let len = len + (len + 1).to_string().len();
+            content.extend(format!("{len} {key}=").as_bytes());
+            content.extend(*value);
+            content.push(b'\n');
+        }
+        content
+    }
+
+    // Splits the tar and merges it again, with objects kept in memory
+    fn round_trip(tar: &[u8]) -> Vec<u8> {
+        let mut objects = HashMap::new();
+        let mut stream = vec![];
+        split(&mut &tar[..], &mut stream, |data| {
+            let id: Sha256HashValue = Sha256::digest(&data).into();
+            objects.insert(id, data);
+            Ok(id)
+        }).unwrap();
+
+        let mut merged = vec![];
+        crate::splitstream::splitstream_merge(&mut &stream[..], &mut merged, |id| Ok(objects[&id].clone())).unwrap();
+        merged
+    }
+
+    fn extension_fixture() -> Vec<u8> {
+        let long_name = format!("dir/{}/file", "x".repeat(150));
+        let long_target = format!("/{}", "y".repeat(200));
+
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Directory, 0o755, 0), "dir/", &b""[..]).unwrap();
+
+        // A PAX path and linkpath, and one with other records that we don't use
+        let records = pax(&[("path", long_name.as_bytes()), ("mtime", b"1700000000.5")]);
+        builder.append_data(&mut header(EntryType::XHeader, 0o644, records.len() as u64), "pax", &records[..]).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 11), "short", &b"hello world"[..]).unwrap();
+        let records = pax(&[("linkpath", long_target.as_bytes())]);
+        builder.append_data(&mut header(EntryType::XHeader, 0o644, records.len() as u64), "pax", &records[..]).unwrap();
+        let mut symlink = header(EntryType::Symlink, 0o777, 0);
+        symlink.set_link_name("short").unwrap();
+        builder.append_data(&mut symlink, "dir/symlink", &b""[..]).unwrap();
+
+        // The tar crate writes GNU long name and long link entries for what doesn't fit
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 512), format!("gnu/{}", "z".repeat(120)), &[b'a'; 512][..]).unwrap();
+        let mut symlink = header(EntryType::Symlink, 0o777, 0);
+        builder.append_link(&mut symlink, "gnu/symlink", &long_target).unwrap();
+
+        // Empty files, and ones which need padding
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 0), "empty", &b""[..]).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 1000), "big", &[b'b'; 1000][..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn split_merge() {
+        let tar = extension_fixture();
+        assert_eq!(round_trip(&tar), tar);
+
+        let paths: Vec<_> = read_entries(&inline_stream(&tar)).unwrap().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(paths.len(), 7);
+        assert_eq!(paths[1].to_str().unwrap(), format!("/dir/{}/file", "x".repeat(150)));
+        assert_eq!(paths[3].to_str().unwrap(), format!("/gnu/{}", "z".repeat(120)));
+    }
+
+    #[test]
+    fn split_merge_padding() {
+        // Padding which isn't zeros still comes back as it was
+        let mut block = header(EntryType::Regular, 0o644, 5);
+        block.set_path("file").unwrap();
+        block.set_cksum();
+        let mut tar = block.as_bytes().to_vec();
+        tar.extend(b"hello");
+        tar.extend([0xaa; 507]);
+        tar.extend([0; 1024]);
+        assert_eq!(round_trip(&tar), tar);
+    }
+
+    #[test]
+    fn trailer() {
+        let tar = extension_fixture();
+        let entries = read_entries(&inline_stream(&tar)).unwrap().len();
+
+        // Anything after the end-of-archive marker is kept, but not interpreted: not even
+        // something which looks like another entry, like tar -x without --ignore-zeros
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 4), "after", &b"more"[..]).unwrap();
+        let mut with_trailer = tar.clone();
+        with_trailer.extend(builder.into_inner().unwrap());
+        with_trailer.extend(b"garbage");
+        assert_eq!(round_trip(&with_trailer), with_trailer);
+        assert_eq!(read_entries(&inline_stream(&with_trailer)).unwrap().len(), entries);
+
+        // ... and so is record padding, or a partial block without the end-of-archive marker
+        let mut padded = tar.clone();
+        padded.resize(10240, 0);
+        assert_eq!(round_trip(&padded), padded);
+        let mut partial = tar[..tar.len() - 1024].to_vec();
+        partial.extend(b"partial");
+        assert_eq!(round_trip(&partial), partial);
+    }
+
+    fn squash_layers(layers: &[Vec<u8>]) -> Vec<u8> {
+        let streams: Vec<_> = layers.iter().map(|layer| inline_stream(layer)).collect();
+        let mut readers: Vec<&[u8]> = streams.iter().map(|stream| &stream[..]).collect();
+        let mut squashed = vec![];
+        squash(&mut readers, &mut squashed).unwrap();
+        squashed
+    }
+
+    fn raw_entries(stream: &[u8]) -> BTreeMap<PathBuf, RawEntry> {
+        let mut stream = stream;
+        let mut reader = SplitStreamReader::new(&mut stream);
+        let mut entries = BTreeMap::new();
+        while let Some(entry) = get_raw_entry(&mut reader).unwrap() {
+            entries.insert(entry.path.clone(), entry);
+        }
+        entries
+    }
+
+    fn content<'a>(entries: &'a [Entry], path: &str) -> &'a [u8] {
+        match &entries.iter().find(|entry| entry.path.as_ref() == Path::new(path)).unwrap().item {
+            Item::Regular { inline_content: Some(content), .. } => content,
+            item => panic!("{path} is {item:?}"),
+        }
+    }
+
+    #[test]
+    fn link_to_replaced_file() {
+        // The link and its target are in different directories, and the target is replaced later
+        // in the same layer
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 2), "a/file", &b"v1"[..]).unwrap();
+        builder.append_link(&mut header(EntryType::Link, 0o644, 0), "b/link", "a/file").unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 2), "a/file", &b"v2"[..]).unwrap();
+        let squashed = squash_layers(&[builder.into_inner().unwrap()]);
+
+        let entries = read_entries(&squashed).unwrap();
+        assert_eq!(content(&entries, "/a/file"), b"v2");
+        assert_eq!(content(&entries, "/b/link"), b"v1");
+    }
+
+    #[test]
+    fn link_with_long_name() {
+        let dir = format!("a/{}", "x".repeat(150));
+        let target = format!("{dir}/file");
+
+        let mut lower = tar::Builder::new(vec![]);
+        lower.append_data(&mut header(EntryType::Regular, 0o644, 3), &target, &b"old"[..]).unwrap();
+        // a GNU long link entry
+        lower.append_link(&mut header(EntryType::Link, 0o644, 0), "b/gnu", &target).unwrap();
+        // a PAX linkpath, with a record which isn't about the link
+        let records = pax(&[("linkpath", target.as_bytes()), ("size", b"0"), ("mtime", b"1700000000")]);
+        lower.append_data(&mut header(EntryType::XHeader, 0o644, records.len() as u64), "pax", &records[..]).unwrap();
+        // (the header has the link name too, cut short, like tar writes it)
+        let mut link = header(EntryType::Link, 0o644, 0);
+        link.set_link_name(&target[..100]).unwrap();
+        lower.append_data(&mut link, "c/pax", &b""[..]).unwrap();
+        // ... and one with nothing else
+        let records = pax(&[("linkpath", target.as_bytes())]);
+        lower.append_data(&mut header(EntryType::XHeader, 0o644, records.len() as u64), "pax", &records[..]).unwrap();
+        lower.append_data(&mut link, "d/pax", &b""[..]).unwrap();
+
+        let mut upper = tar::Builder::new(vec![]);
+        upper.append_data(&mut header(EntryType::Regular, 0o644, 3), &target, &b"new"[..]).unwrap();
+
+        let squashed = squash_layers(&[lower.into_inner().unwrap(), upper.into_inner().unwrap()]);
+        let entries = read_entries(&squashed).unwrap();
+        assert_eq!(content(&entries, &format!("/{target}")), b"new");
+        for link in ["/b/gnu", "/c/pax", "/d/pax"] {
+            assert_eq!(content(&entries, link), b"old");
+        }
+
+        let raw = raw_entries(&squashed);
+        assert_eq!(raw[Path::new("/b/gnu")].headers.len(), 1);
+        assert_eq!(raw[Path::new("/d/pax")].headers.len(), 1);
+        let pax_headers = &raw[Path::new("/c/pax")].headers;
+        assert_eq!(pax_headers.len(), 2);
+        let records = pax(&[("mtime", b"1700000000")]);
+        assert_eq!(Header::from_byte_slice(&pax_headers[0][..512]).entry_size().unwrap(), records.len() as u64);
+        assert_eq!(&pax_headers[0][512..512 + records.len()], records);
+    }
+
+    #[test]
+    fn sparse() {
+        // An old-style GNU sparse file, with a sparse map which continues in another block
+        let mut block = header(EntryType::GNUSparse, 0o644, 512);
+        block.set_path("sparse").unwrap();
+        block.as_gnu_mut().unwrap().isextended = [1];
+        block.set_cksum();
+        let mut tar = block.as_bytes().to_vec();
+        tar.extend([0u8; 512]);  // the extension block
+        tar.extend([b'x'; 512]);  // the data
+        tar.extend([0u8; 1024]);
+        assert!(is_invalid_format(&split_tar(&tar).unwrap_err()));
+        assert!(is_invalid_format(&read_entries(&inline_stream(&tar)).unwrap_err()));
+
+        // GNU tar's PAX format 1.0, where the map is at the start of the data
+        let records = pax(&[("GNU.sparse.major", b"1"), ("GNU.sparse.minor", b"0"), ("GNU.sparse.name", b"sparse")]);
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_data(&mut header(EntryType::XHeader, 0o644, records.len() as u64), "pax", &records[..]).unwrap();
+        builder.append_data(&mut header(EntryType::Regular, 0o644, 5), "GNUSparseFile.0/sparse", &b"1\n0\n5"[..]).unwrap();
+        let tar = builder.into_inner().unwrap();
+        assert!(is_invalid_format(&split_tar(&tar).unwrap_err()));
+        assert!(is_invalid_format(&read_entries(&inline_stream(&tar)).unwrap_err()));
+    }
+}
@@ -0,0 +1,470 @@
+/* Exporting images as ostree commits
+ *
+ * This writes the content of an image into an existing ostree repository, as a commit on a
+ * branch, so that tools which only know about ostree can deploy images that were built here.
+ * The objects are the same as ostree itself would write: file objects named for the checksum of
+ * their header (ownership, mode, xattrs) and content, and dirtree, dirmeta and commit objects,
+ * which are GVariants named for their checksum.  The GVariant serialization is done by hand
+ * below, for the handful of types that ostree needs.
+ *
+ * Repositories in "bare" mode (which needs root, for the ownership) and "bare-user" mode (which
+ * keeps the metadata in the user.ostreemeta xattr) are supported.  Devices and fifos can't be
+ * stored in ostree, so they're left out, with a warning.
+ */
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    fs::File,
+    io::Write,
+    os::fd::OwnedFd,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use composefs::dumpfile::{
+    Entry,
+    Item,
+};
+use rand::distributions::{
+    Alphanumeric,
+    DistString,
+};
+use rustix::fs::{
+    AtFlags,
+    Mode,
+    OFlags,
+    RenameFlags,
+    Timespec,
+    Timestamps,
+    XattrFlags,
+    fchmod,
+    fsetxattr,
+    lsetxattr,
+    mkdirat,
+    open,
+    openat,
+    renameat,
+    renameat_with,
+    symlinkat,
+    syncfs,
+    unlinkat,
+    utimensat,
+};
+use rustix::io::Errno;
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use crate::{
+    image,
+    progress::{
+        self,
+        Progress,
+    },
+    repository::Repository,
+};
+
+/// A serialized GVariant, with what a container needs to know to serialize it as a member.
+struct Variant {
+    data: Vec<u8>,
+    alignment: usize,
+    fixed_size: bool,
+}
+
+impl Variant {
+    /// A 'u', which ostree always stores in big-endian byte order.
+    fn u32_be(value: u32) -> Variant {
+        Variant { data: value.to_be_bytes().to_vec(), alignment: 4, fixed_size: true }
+    }
+
+    /// A 't', in big-endian byte order, like u32_be().
+    fn u64_be(value: u64) -> Variant {
+        Variant { data: value.to_be_bytes().to_vec(), alignment: 8, fixed_size: true }
+    }
+
+    /// An 'ay'.
+    fn bytes(value: &[u8]) -> Variant {
+        Variant { data: value.to_vec(), alignment: 1, fixed_size: false }
+    }
+
+    /// An 's'.
+    fn string(value: &str) -> Variant {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        Variant { data, alignment: 1, fixed_size: false }
+    }
+
+    /// A tuple of the given members.
+    fn tuple(members: Vec<Variant>) -> Variant {
+        let alignment = members.iter().map(|member| member.alignment).max().unwrap_or(1);
+        let fixed_size = members.iter().all(|member| member.fixed_size);
+        let last = members.len().saturating_sub(1);
+
+        let mut data = vec![];
+        let mut offsets = vec![];
+        for (idx, member) in members.into_iter().enumerate() {
+            pad(&mut data, member.alignment);
+            data.extend(member.data);
+            // The end of each variable-size member is recorded, except for the last one
+            if !member.fixed_size && idx != last {
+                offsets.push(data.len());
+            }
+        }
+
+        if fixed_size {
+            pad(&mut data, alignment);
+        } else {
+            offsets.reverse();
+            append_offsets(&mut data, &offsets);
+        }
+        Variant { data, alignment, fixed_size }
+    }
+
+    /// An array of variable-size elements, which have the given alignment.
+    fn array(elements: Vec<Variant>, alignment: usize) -> Variant {
+        let mut data = vec![];
+        let mut offsets = vec![];
+        for element in elements {
+            pad(&mut data, alignment);
+            data.extend(element.data);
+            offsets.push(data.len());
+        }
+        append_offsets(&mut data, &offsets);
+        Variant { data, alignment, fixed_size: false }
+    }
+}
+
+fn pad(data: &mut Vec<u8>, alignment: usize) {
+    data.resize(data.len().next_multiple_of(alignment), 0);
+}
+
+/// Appends the framing offsets to a container, in the smallest size which can hold them.
+fn append_offsets(data: &mut Vec<u8>, offsets: &[usize]) {
+    let size = data.len();
+    let width = [1, 2, 4].into_iter()
+        .find(|width| size + width * offsets.len() < 1 << (8 * width))
+        .unwrap_or(8);
+    for offset in offsets {
+        data.extend(&offset.to_le_bytes()[..width]);
+    }
+}
+
+/// The xattrs of an entry, as an a(ayay), sorted by name.  The names include their NUL.
+fn xattrs_variant(entry: &Entry) -> Variant {
+    let mut xattrs: Vec<_> = entry.xattrs.iter().map(|xattr| {
+        let mut name = xattr.key.as_encoded_bytes().to_vec();
+        name.push(0);
+        (name, xattr.value.to_vec())
+    }).collect();
+    xattrs.sort();
+
+    Variant::array(xattrs.into_iter().map(|(name, value)| {
+        Variant::tuple(vec![Variant::bytes(&name), Variant::bytes(&value)])
+    }).collect(), 1)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Writes the content of a file to a temporary file while computing its checksum.
+struct HashingWriter {
+    file: File,
+    context: Sha256,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.context.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RepoMode {
+    Bare,
+    BareUser,
+}
+
+struct Exporter<'a> {
+    repo: &'a Repository,
+    entries: &'a BTreeMap<PathBuf, Entry<'a>>,
+    children: HashMap<&'a Path, Vec<&'a Entry<'a>>>,
+    path: &'a Path,
+    target: OwnedFd,
+    mode: RepoMode,
+    /// The checksums of the file objects written so far, by path (for hardlinks)
+    files: HashMap<PathBuf, [u8; 32]>,
+    progress: Progress,
+}
+
+impl Exporter<'_> {
+    /// Moves a temporary file (in tmp/) into place as the object with the given checksum, unless
+    /// that object already exists.
+    fn install_object(&self, tmp: &str, checksum: &[u8; 32], extension: &str) -> Result<()> {
+        let dir = format!("objects/{:02x}", checksum[0]);
+        match mkdirat(&self.target, &dir, 0o755.into()) {
+            Ok(()) | Err(Errno::EXIST) => {},
+            Err(err) => Err(err)?,
+        }
+
+        let filename = format!("{dir}/{}.{extension}", hex::encode(&checksum[1..]));
+        match renameat_with(&self.target, tmp, &self.target, filename, RenameFlags::NOREPLACE) {
+            Ok(()) => Ok(()),
+            Err(Errno::EXIST) => Ok(unlinkat(&self.target, tmp, AtFlags::empty())?),
+            Err(err) => Err(err)?,
+        }
+    }
+
+    fn write_metadata(&self, variant: &Variant, extension: &str) -> Result<[u8; 32]> {
+        let checksum = sha256(&variant.data);
+        let tmp = tmp_name();
+        let fd = openat(&self.target, &tmp, OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o644.into())?;
+        File::from(fd).write_all(&variant.data)?;
+        self.install_object(&tmp, &checksum, extension)?;
+        Ok(checksum)
+    }
+
+    /// Writes the file object for a regular file or a symlink, returning its checksum.
+    fn write_file(&mut self, entry: &Entry) -> Result<[u8; 32]> {
+        if let Some(checksum) = self.files.get(entry.path.as_ref()) {
+            return Ok(*checksum);
+        }
+        if let Item::Hardlink { target } = &entry.item {
+            let target_entry = self.entries.get(target.as_ref())
+                .with_context(|| format!("Hardlink target {target:?} isn't in the image"))?;
+            let checksum = self.write_file(target_entry)?;
+            self.files.insert(entry.path.to_path_buf(), checksum);
+            return Ok(checksum);
+        }
+
+        let symlink_target = match &entry.item {
+            Item::Symlink { target, .. } => target.to_str()
+                .with_context(|| format!("Symlink target of {:?} isn't UTF-8", entry.path))?,
+            _ => "",
+        };
+        let header = Variant::tuple(vec![
+            Variant::u32_be(entry.uid),
+            Variant::u32_be(entry.gid),
+            Variant::u32_be(entry.mode),
+            Variant::u32_be(0),
+            Variant::string(symlink_target),
+            xattrs_variant(entry),
+        ]);
+
+        // The checksum covers the size of the header, padding to 8 bytes, the header, and then
+        // the content of regular files.
+        let mut context = Sha256::new();
+        context.update((header.data.len() as u32).to_be_bytes());
+        context.update([0u8; 4]);
+        context.update(&header.data);
+
+        let tmp = tmp_name();
+        let bare_symlink = self.mode == RepoMode::Bare && matches!(entry.item, Item::Symlink { .. });
+        if bare_symlink {
+            symlinkat(symlink_target, &self.target, &tmp)?;
+        } else {
+            let fd = openat(&self.target, &tmp, OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o600.into())?;
+            let mut writer = HashingWriter { file: File::from(fd), context };
+            match &entry.item {
+                Item::Symlink { .. } => writer.file.write_all(symlink_target.as_bytes())?,  // bare-user
+                _ => image::write_content(self.repo, entry, &mut writer)?,
+            }
+            context = writer.context;
+            self.set_metadata(&writer.file, entry)?;
+        }
+        let checksum: [u8; 32] = context.finalize().into();
+
+        if bare_symlink {
+            let path = self.path.join(&tmp);
+            std::os::unix::fs::lchown(&path, Some(entry.uid), Some(entry.gid))
+                .context("Writing to a bare ostree repository requires root")?;
+            for xattr in entry.xattrs.iter() {
+                lsetxattr(&path, &*xattr.key, &xattr.value, XattrFlags::empty())?;
+            }
+        }
+        // Content objects have an mtime of 0, like ostree does it
+        let epoch = Timespec { tv_sec: 0, tv_nsec: 0 };
+        utimensat(&self.target, &tmp, &Timestamps { last_access: epoch, last_modification: epoch }, AtFlags::SYMLINK_NOFOLLOW)?;
+
+        self.install_object(&tmp, &checksum, "file")?;
+        self.files.insert(entry.path.to_path_buf(), checksum);
+        self.progress.inc(1);
+        Ok(checksum)
+    }
+
+    /// Applies the ownership, mode and xattrs of entry to a file object, as the mode of the
+    /// repository requires.
+    fn set_metadata(&self, file: &File, entry: &Entry) -> Result<()> {
+        match self.mode {
+            RepoMode::Bare => {
+                std::os::unix::fs::fchown(file, Some(entry.uid), Some(entry.gid))
+                    .context("Writing to a bare ostree repository requires root")?;
+                fchmod(file, Mode::from_raw_mode(entry.mode & 0o7777))?;
+                for xattr in entry.xattrs.iter() {
+                    fsetxattr(file, &*xattr.key, &xattr.value, XattrFlags::empty())?;
+                }
+            },
+            RepoMode::BareUser => {
+                let meta = Variant::tuple(vec![
+                    Variant::u32_be(entry.uid),
+                    Variant::u32_be(entry.gid),
+                    Variant::u32_be(entry.mode),
+                    xattrs_variant(entry),
+                ]);
+                fsetxattr(file, "user.ostreemeta", &meta.data, XattrFlags::empty())?;
+                // The actual mode doesn't matter, but setuid files owned by the user would
+                fchmod(file, Mode::from_raw_mode((entry.mode & 0o755) | 0o600))?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Writes the dirtree and dirmeta objects for a directory (and everything in it), returning
+    /// their checksums.
+    fn write_dir(&mut self, dir: &Entry) -> Result<([u8; 32], [u8; 32])> {
+        let mut files = vec![];
+        let mut dirs = vec![];
+
+        let children = self.children.get(dir.path.as_ref()).cloned().unwrap_or_default();
+        for child in children {
+            let Some(name) = child.path.file_name().and_then(|name| name.to_str()) else {
+                bail!("File name of {:?} isn't UTF-8", child.path);
+            };
+            match child.item {
+                Item::Directory { .. } => {
+                    let (tree, meta) = self.write_dir(child)?;
+                    dirs.push((name, tree, meta));
+                },
+                Item::Regular { .. } | Item::Symlink { .. } | Item::Hardlink { .. } => {
+                    files.push((name, self.write_file(child)?));
+                },
+                Item::Device { .. } | Item::Fifo { .. } => {
+                    progress::warn(format!("Leaving out {:?}: ostree can't store devices and fifos", child.path));
+                },
+            }
+        }
+        files.sort();
+        dirs.sort();
+
+        let dirtree = Variant::tuple(vec![
+            Variant::array(files.into_iter().map(|(name, checksum)| Variant::tuple(vec![
+                Variant::string(name), Variant::bytes(&checksum),
+            ])).collect(), 1),
+            Variant::array(dirs.into_iter().map(|(name, tree, meta)| Variant::tuple(vec![
+                Variant::string(name), Variant::bytes(&tree), Variant::bytes(&meta),
+            ])).collect(), 1),
+        ]);
+        let dirmeta = Variant::tuple(vec![
+            Variant::u32_be(dir.uid),
+            Variant::u32_be(dir.gid),
+            Variant::u32_be(dir.mode),
+            xattrs_variant(dir),
+        ]);
+
+        Ok((self.write_metadata(&dirtree, "dirtree")?, self.write_metadata(&dirmeta, "dirmeta")?))
+    }
+}
+
+fn tmp_name() -> String {
+    format!("tmp/cfsctl-export-{}", Alphanumeric.sample_string(&mut rand::thread_rng(), 12))
+}
+
+/// Reads the mode of an ostree repository from its config.
+fn repo_mode(target: &Path) -> Result<RepoMode> {
+    let config = std::fs::read_to_string(target.join("config"))
+        .with_context(|| format!("{target:?} isn't an ostree repository"))?;
+    let mode = config.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "mode")
+        .map_or("bare", |(_, value)| value.trim());
+    match mode {
+        "bare" => Ok(RepoMode::Bare),
+        "bare-user" => Ok(RepoMode::BareUser),
+        _ => bail!("Exporting to ostree repositories in {mode} mode isn't supported (only bare and bare-user)"),
+    }
+}
+
+/// Writes the named image as a commit in the ostree repository at target, with the given
+/// subject, and points branch at it.  The previous commit on the branch (if any) becomes the
+/// parent.  Returns the checksum of the commit.
+pub fn export(repo: &Repository, name: &str, target: &Path, branch: &str, subject: &str) -> Result<[u8; 32]> {
+    let mode = repo_mode(target)?;
+    let dump = repo.dump_image(name)?;
+    let entries = image::parse_entries(&dump)?;
+
+    let mut children: HashMap<&Path, Vec<&Entry>> = HashMap::new();
+    for entry in entries.values() {
+        if let Some(parent) = entry.path.parent() {
+            children.entry(parent).or_default().push(entry);
+        }
+    }
+    let root = entries.get(Path::new("/")).context("Image has no root directory")?;
+
+    let mut exporter = Exporter {
+        repo,
+        entries: &entries,
+        children,
+        path: target,
+        target: open(target, OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC, Mode::empty())?,
+        mode,
+        files: HashMap::new(),
+        progress: Progress::new("Exporting", "files", None),
+    };
+    match mkdirat(&exporter.target, "tmp", 0o755.into()) {
+        Ok(()) | Err(Errno::EXIST) => {},
+        Err(err) => Err(err)?,
+    }
+    let (tree, meta) = exporter.write_dir(root)?;
+
+    let ref_path = format!("refs/heads/{branch}");
+    let parent = match std::fs::read_to_string(target.join(&ref_path)) {
+        Ok(parent) => hex::decode(parent.trim()).with_context(|| format!("Invalid ref {ref_path}"))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(err) => Err(err)?,
+    };
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let commit = Variant::tuple(vec![
+        Variant::array(vec![], 8),  // metadata (a{sv})
+        Variant::bytes(&parent),
+        Variant::array(vec![], 1),  // related objects (a(say))
+        Variant::string(subject),
+        Variant::string(""),  // body
+        Variant::u64_be(timestamp),
+        Variant::bytes(&tree),
+        Variant::bytes(&meta),
+    ]);
+    let checksum = exporter.write_metadata(&commit, "commit")?;
+    drop(exporter.progress);
+    syncfs(&exporter.target)?;
+
+    if let Some(dir) = Path::new(&ref_path).parent() {
+        std::fs::create_dir_all(target.join(dir))?;
+    }
+    let tmp = tmp_name();
+    std::fs::write(target.join(&tmp), format!("{}\n", hex::encode(checksum)))?;
+    renameat(&exporter.target, &tmp, &exporter.target, &ref_path)?;
+
+    Ok(checksum)
+}
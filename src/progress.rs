@@ -0,0 +1,263 @@
+/* Progress and log messages for long operations
+ *
+ * The library reports what it's doing via the functions in this module, and the program decides
+ * once, with set_reporter(), where that goes (before anything gets reported).  A GUI or a daemon implements Reporter to present
+ * it in its own way.  StderrReporter is what cfsctl uses: human-readable messages and progress
+ * bars on stderr (the bars only if stderr is a terminal), JSON lines on stderr for consumption by
+ * other programs, or nothing but warnings.  Without a reporter, only warnings and messages are
+ * shown.
+ *
+ * The messages are also emitted as tracing events (with this module as the target), so a program
+ * which collects those sees them along with the spans of the operations that they're part of.
+ */
+
+use std::{
+    io::{
+        IsTerminal,
+        Write,
+    },
+    sync::OnceLock,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use anyhow::{
+    Result,
+    bail,
+};
+
+use crate::json::Json;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only warnings
+    Quiet,
+    /// Warnings, messages and progress bars
+    Normal,
+    /// Also details, like every object that's written
+    Verbose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Something that's probably wrong, but doesn't stop the operation
+    Warning,
+    /// Something the user will want to know about, like an entry that was written
+    Info,
+    /// Details, like every object that's written
+    Debug,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Warning => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    fn verbosity(self) -> Verbosity {
+        match self {
+            Level::Warning => Verbosity::Quiet,
+            Level::Info => Verbosity::Normal,
+            Level::Debug => Verbosity::Verbose,
+        }
+    }
+}
+
+/// Where a task (like verifying all objects) is at.
+#[derive(Clone, Debug)]
+pub struct TaskState {
+    /// What's being done, like "Verifying objects"
+    pub task: &'static str,
+    /// What's being counted, like "objects" or "bytes"
+    pub unit: &'static str,
+    pub done: u64,
+    /// How many there are in total, if that's known up front
+    pub total: Option<u64>,
+    pub finished: bool,
+}
+
+/// Receives the messages and progress reports of the library.  The methods can be called from
+/// any thread.
+pub trait Reporter: Send + Sync {
+    fn message(&self, level: Level, message: &str);
+
+    /// Called when a task starts (with done at 0), as it goes along, and when it's finished.
+    fn progress(&self, state: &TaskState);
+
+    /// The minimum time between two progress() calls for the same task, except for the first and
+    /// the last one.
+    fn progress_interval(&self) -> Duration {
+        Duration::from_millis(100)
+    }
+}
+
+static REPORTER: OnceLock<Box<dyn Reporter>> = OnceLock::new();
+
+/// Sets where progress and messages are reported to.  Fails if there's a reporter already: if
+/// this was called before, or if something was reported before, which sets up the default one.
+pub fn set_reporter(reporter: Box<dyn Reporter>) -> Result<()> {
+    if REPORTER.set(reporter).is_err() {
+        bail!("The progress reporter is already set");
+    }
+    Ok(())
+}
+
+/// Sets up reporting to stderr, with a StderrReporter.  Fails like set_reporter().
+pub fn init(verbosity: Verbosity, format: Format) -> Result<()> {
+    set_reporter(Box::new(StderrReporter::new(verbosity, format)))
+}
+
+fn reporter() -> &'static dyn Reporter {
+    REPORTER.get_or_init(|| Box::new(StderrReporter::new(Verbosity::Normal, Format::Human))).as_ref()
+}
+
+fn report(level: Level, message: &str) {
+    match level {
+        Level::Warning => tracing::warn!("{message}"),
+        Level::Info => tracing::info!("{message}"),
+        Level::Debug => tracing::debug!("{message}"),
+    }
+    reporter().message(level, message);
+}
+
+/// Something that's probably wrong, but doesn't stop the operation.  Always shown.
+pub fn warn<S: AsRef<str>>(message: S) {
+    report(Level::Warning, message.as_ref());
+}
+
+/// Something the user will want to know about, like an entry that was written.
+pub fn info<S: AsRef<str>>(message: S) {
+    report(Level::Info, message.as_ref());
+}
+
+/// Details which are only shown with Verbosity::Verbose.
+pub fn debug<S: AsRef<str>>(message: S) {
+    report(Level::Debug, message.as_ref());
+}
+
+/// The progress of a single task, like verifying all objects.  Create it when starting the task,
+/// call inc() as it goes along, and drop it when done.
+pub struct Progress {
+    state: TaskState,
+    last_report: Instant,
+}
+
+impl Progress {
+    /// Starts a task, counting things of the given unit ("objects", "bytes", ...).  The total is
+    /// the number of those things, if it's known up front.
+    pub fn new(task: &'static str, unit: &'static str, total: Option<u64>) -> Progress {
+        let state = TaskState { task, unit, done: 0, total, finished: false };
+        reporter().progress(&state);
+        Progress { state, last_report: Instant::now() }
+    }
+
+    pub fn inc(&mut self, n: u64) {
+        self.state.done += n;
+        let reporter = reporter();
+        if self.last_report.elapsed() >= reporter.progress_interval() {
+            self.last_report = Instant::now();
+            reporter.progress(&self.state);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.state.finished = true;
+        reporter().progress(&self.state);
+    }
+}
+
+/// Reports to stderr, in the given format, leaving out what's more detailed than the verbosity.
+pub struct StderrReporter {
+    verbosity: Verbosity,
+    format: Format,
+    terminal: bool,
+}
+
+impl StderrReporter {
+    pub fn new(verbosity: Verbosity, format: Format) -> StderrReporter {
+        StderrReporter { verbosity, format, terminal: std::io::stderr().is_terminal() }
+    }
+}
+
+impl Reporter for StderrReporter {
+    fn message(&self, level: Level, message: &str) {
+        if self.verbosity < level.verbosity() {
+            return;
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = match self.format {
+            Format::Human if self.terminal => writeln!(stderr, "\r\x1b[K{message}"),  // clear any bar
+            Format::Human => writeln!(stderr, "{message}"),
+            Format::Json => writeln!(stderr, "{}", Json::Object(vec![
+                ("level", Json::string(level.name())), ("message", Json::string(message)),
+            ])),
+        };
+    }
+
+    fn progress(&self, state: &TaskState) {
+        if state.done == 0 && !state.finished {
+            self.message(Level::Debug, &format!("{}...", state.task));
+        }
+        if self.verbosity == Verbosity::Quiet || (self.format == Format::Human && !self.terminal) {
+            return;
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = match self.format {
+            Format::Human if state.finished => write!(stderr, "\r\x1b[K"),
+            Format::Human => {
+                let (done, unit) = (state.done, state.unit);
+                match state.total {
+                    Some(total) if total > 0 => write!(
+                        stderr, "\r\x1b[K{}: {done}/{total} {unit} ({}%)", state.task, done * 100 / total
+                    ),
+                    _ => write!(stderr, "\r\x1b[K{}: {done} {unit}", state.task),
+                }
+            },
+            Format::Json => writeln!(stderr, "{}", Json::Object(vec![
+                ("level", Json::string("progress")),
+                ("task", Json::string(state.task)),
+                ("unit", Json::string(state.unit)),
+                ("done", Json::Number(state.done)),
+                ("total", state.total.map_or(Json::Null, Json::Number)),
+                ("finished", Json::Bool(state.finished)),
+            ])),
+        };
+    }
+
+    fn progress_interval(&self) -> Duration {
+        // Often enough to look smooth on a terminal, but not so often that it floods a log
+        match self.format {
+            Format::Human => Duration::from_millis(100),
+            Format::Json => Duration::from_secs(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reporter_only_once() {
+        // Something else in this process might have set up the reporter already
+        let _ = init(Verbosity::Quiet, Format::Human);
+        assert!(init(Verbosity::Verbose, Format::Json).is_err());
+        assert!(set_reporter(Box::new(StderrReporter::new(Verbosity::Normal, Format::Human))).is_err());
+    }
+}
@@ -1,9 +1,15 @@
 use std::{
-    collections::HashSet,
+    collections::{
+        BTreeMap,
+        HashMap,
+        HashSet,
+    },
     ffi::CStr,
     fs::File,
     io::{
+        BufRead,
         BufReader,
+        Cursor,
         ErrorKind,
         Read,
         Write,
@@ -14,6 +20,20 @@ use std::{
         PathBuf,
     },
     process::Command,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        Once,
+        mpsc::{
+            SyncSender,
+            sync_channel,
+        },
+    },
 };
 
 use anyhow::{
@@ -22,6 +42,7 @@ use anyhow::{
     bail,
 };
 use rustix::fs::{
+    Advice,
     FileType,
     Dir,
     Access,
@@ -31,6 +52,7 @@ use rustix::fs::{
     Mode,
     OFlags,
     accessat,
+    fadvise,
     fdatasync,
     flock,
     linkat,
@@ -38,10 +60,23 @@ use rustix::fs::{
     open,
     openat,
     readlinkat,
+    renameat,
+    statat,
     symlinkat,
+    syncfs,
+    unlinkat,
+};
+use rustix::io::Errno;
+use tracing::{
+    Span,
+    debug,
+    instrument,
+    trace,
 };
 
 use crate::{
+    cdc,
+    error::Error,
     fsverity::{
         FsVerityHashValue,
         Sha256HashValue,
@@ -49,19 +84,235 @@ use crate::{
         ioctl::{
             fs_ioc_enable_verity,
             fs_ioc_measure_verity,
+            fs_ioc_read_verity_signature,
         },
     },
     mount::mount_fd,
+    progress::{
+        self,
+        Progress,
+    },
+    signing::{
+        PublicKey,
+        SigningKey,
+    },
     splitstream::{
-        splitstream_merge,
+        SPLITSTREAM_VERSION,
+        SplitStreamData,
+        SplitStreamHeader,
+        SplitStreamMerger,
+        read_splitstream_chunk,
+        read_splitstream_header,
+        read_splitstream_dictionary,
+        read_splitstream_object_table,
+        splitstream_merge_range,
         splitstream_objects,
+        write_splitstream_header,
+    },
+    systemd::{
+        MountUnit,
+        composefs_mount_units,
+    },
+    util::{
+        Mmap,
+        Sha256Reader,
+        proc_self_fd,
     },
-    util::proc_self_fd,
 };
+#[cfg(feature = "io-uring")]
+use crate::uring::BulkReader;
+
+// Makes sure that we only complain once about insecure mode
+static INSECURE_WARNING: Once = Once::new();
+
+// The number of objects that merge_splitstream() reads ahead
+const READAHEAD_OBJECTS: usize = 16;
+
+// Objects up to this size are read into memory with io_uring by merge_splitstream()
+#[cfg(feature = "io-uring")]
+const BULK_READ_MAX_SIZE: u64 = 1 << 20;
+
+// The number of objects that with_object_writers() queues up for its worker threads
+const WRITE_QUEUE_OBJECTS: usize = 64;
+
+// How much of an object ensure_object_from_reader() hashes at a time before writing it out, while
+// it's in the cache
+const TEE_CHUNK_SIZE: usize = 256 << 10;
+
+enum Prefetched {
+    Inline(Vec<u8>),
+    Object(File),
+}
+
+/// The (compressed) file of a stream.  It's mapped if it has fs-verity enabled, which means that
+/// it can't change while it's mapped, and decompressing straight from the mapping saves copying
+/// everything into a buffer first.  Otherwise (in an insecure repository), it's read through a
+/// buffer: truncating a mapped file would make us crash.
+pub enum StreamFile {
+    Mapped(Cursor<Mmap>),
+    Buffered(BufReader<File>),
+}
+
+impl Read for StreamFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            StreamFile::Mapped(file) => file.read(buf),
+            StreamFile::Buffered(file) => file.read(buf),
+        }
+    }
+}
+
+impl BufRead for StreamFile {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        match self {
+            StreamFile::Mapped(file) => file.fill_buf(),
+            StreamFile::Buffered(file) => file.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            StreamFile::Mapped(file) => file.consume(amt),
+            StreamFile::Buffered(file) => file.consume(amt),
+        }
+    }
+}
+
+/// A stream which was opened for reading, as returned by Repository::open_stream().
+pub type StreamReader = zstd::stream::read::Decoder<'static, StreamFile>;
+
+/// The zstd dictionary for compressing new streams: an object, which the streams refer to by its
+/// digest.
+#[derive(Clone)]
+struct ZstdDictionary {
+    id: Sha256HashValue,
+    data: Arc<Vec<u8>>,
+}
+
+/// A new stream, as returned by Repository::create_stream().  The blocks are buffered until
+/// finish() is called, since the header (which lists the referenced objects) comes first.
+pub struct StreamWriter {
+    level: i32,
+    dictionary: Option<ZstdDictionary>,
+    body: Vec<u8>,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.body.write(data)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl StreamWriter {
+    /// Returns the compressed stream, including the header.
+    pub fn finish(self) -> Result<Vec<u8>> {
+        let mut seen = HashSet::new();
+        let mut objects = vec![];
+        splitstream_objects(&mut self.body.as_slice(), |id| {
+            if seen.insert(id) {
+                objects.push(id);
+            }
+        })?;
+
+        // The header is a zstd frame of its own, compressed without the dictionary, so that it can
+        // be read before knowing which dictionary the rest needs.
+        let mut header = vec![];
+        write_splitstream_header(&mut header, self.dictionary.as_ref().map(|dictionary| &dictionary.id), &objects)?;
+        let stream = zstd::stream::encode_all(header.as_slice(), self.level)?;
+
+        let mut encoder = match &self.dictionary {
+            Some(dictionary) => zstd::stream::write::Encoder::with_dictionary(stream, self.level, &dictionary.data)?,
+            None => zstd::stream::write::Encoder::new(stream, self.level)?,
+        };
+        encoder.write_all(&self.body)?;
+        Ok(encoder.finish()?)
+    }
+}
+
+/// Stores objects on worker threads.  See Repository::with_object_writers().
+pub struct ObjectWriters<'r> {
+    repo: &'r Repository,
+    sender: SyncSender<(Sha256HashValue, Vec<u8>)>,
+}
+
+impl ObjectWriters<'_> {
+    /// Like Repository::ensure_object(), but the object might not be written yet when this
+    /// returns.  The data is handed over to the worker threads as-is, without copying it.
+    pub fn ensure_object(&self, data: Vec<u8>) -> Result<Sha256HashValue> {
+        let digest = FsVerityHasher::<Sha256HashValue>::hash_with_salt(&data, &self.repo.salt);
+        if self.sender.send((digest, data)).is_err() {
+            bail!("All object writer threads failed");
+        }
+        Ok(digest)
+    }
+}
+
+/// A group of objects removed by Repository::gc().
+pub struct Garbage {
+    /// The images and streams which referred to the objects (empty if nothing did)
+    pub roots: Vec<String>,
+    pub objects: Vec<Sha256HashValue>,
+    /// The total size of the objects, in bytes
+    pub size: u64,
+}
 
 pub struct Repository {
     repository: OwnedFd,
     path: String,
+    insecure: bool,
+    salt: Vec<u8>,
+    zstd_level: i32,
+    dictionary: Option<ZstdDictionary>,
+    durability: Durability,
+}
+
+/// When the data of new objects gets flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    /// Each object is synced before it's linked into objects/ (the default)
+    Object,
+    /// Objects aren't synced individually, but the whole filesystem is synced once before a new
+    /// ref is written, which is much faster for many small objects on some filesystems.  A crash
+    /// can leave objects with incomplete content behind (which verify-objects will find).  Objects
+    /// without fs-verity get hashed again before they're reused, and replaced if they're damaged,
+    /// which is slower in an insecure repository.
+    Batch,
+    /// Nothing is synced: only for repositories which can be thrown away after a crash, since refs
+    /// can end up pointing to objects which are damaged or missing
+    None,
+}
+
+/// Checks if an error from an fs-verity ioctl means that fs-verity isn't available for the file,
+/// as opposed to indicating a real problem.
+fn verity_unsupported(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<Errno>(),
+        Some(&Errno::NOTSUP) | Some(&Errno::NOTTY) | Some(&Errno::NODATA)
+    )
+}
+
+fn warn_insecure() {
+    INSECURE_WARNING.call_once(|| progress::warn(
+        "WARNING: fs-verity isn't available: digests are only checked in userspace, \
+         which doesn't protect against later modification of the repository"
+    ));
+}
+
+/// Reads the content of a file in the repository, if it exists.
+fn read_optional(repository: &OwnedFd, name: &str) -> Result<Option<Vec<u8>>> {
+    match openat(repository, name, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty()) {
+        Ok(fd) => {
+            let mut data = vec![];
+            File::from(fd).read_to_end(&mut data)?;
+            Ok(Some(data))
+        },
+        Err(Errno::NOENT) => Ok(None),
+        Err(err) => Err(err)?,
+    }
 }
 
 impl Drop for Repository {
@@ -80,7 +331,32 @@ impl Repository {
         flock(&repository, FlockOperation::LockShared).
             with_context(|| format!("Cannot lock repository '{path}'"))?;
 
-        Ok(Repository { repository, path })
+        // The salt is part of the digest of every object, so it can't change once set.
+        let salt = match read_optional(&repository, "salt")? {
+            Some(hex_salt) => hex::decode(String::from_utf8(hex_salt)?.trim())
+                .with_context(|| "Invalid salt in repository")?,
+            None => vec![],
+        };
+
+        // A repository on a filesystem without fs-verity can be marked as always insecure.
+        let insecure = accessat(&repository, "insecure", Access::EXISTS, AtFlags::empty()) == Ok(());
+
+        let mut repo = Repository {
+            repository, path, insecure, salt, zstd_level: 0, dictionary: None, durability: Durability::Object
+        };
+
+        // The dictionary for new streams, if there is one
+        repo.dictionary = match readlinkat(&repo.repository, "zstd-dictionary", []) {
+            Ok(target) => {
+                let id = Repository::parse_object_path(target.to_bytes())
+                    .with_context(|| format!("Invalid zstd-dictionary symlink {target:?}"))?;
+                Some(ZstdDictionary { id, data: repo.read_dictionary(id)? })
+            },
+            Err(Errno::NOENT) => None,
+            Err(err) => Err(err).context("Failed to read zstd-dictionary")?,
+        };
+
+        Ok(repo)
     }
 
     pub fn open_user() -> Result<Repository> {
@@ -94,6 +370,68 @@ impl Repository {
         Repository::open_path("/sysroot/composefs".to_string())
     }
 
+    /// Sets up a new, empty repository.  If a salt is given (up to 32 bytes), it's used for
+    /// computing the fs-verity digests of all objects in the repository.  If the repository is
+    /// in insecure mode, it's marked as insecure, so that it stays that way.
+    pub fn init(&self, salt: &[u8]) -> Result<()> {
+        if accessat(&self.repository, "objects", Access::EXISTS, AtFlags::empty()) == Ok(()) {
+            bail!("Repository is already initialized");
+        }
+        if salt.len() > 32 {
+            bail!("Salt can be at most 32 bytes");
+        }
+
+        if !salt.is_empty() {
+            let fd = openat(&self.repository, "salt", OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC, 0o644.into())?;
+            File::from(fd).write_all(format!("{}\n", hex::encode(salt)).as_bytes())?;
+        }
+
+        if self.insecure {
+            openat(&self.repository, "insecure", OFlags::WRONLY | OFlags::CREATE | OFlags::CLOEXEC, 0o644.into())?;
+        }
+
+        self.ensure_dir("objects")
+    }
+
+    /// In insecure mode, objects are stored without fs-verity if the filesystem doesn't support
+    /// it, and digests are verified in userspace when opening them instead.  That's fine for
+    /// development, but it provides no protection against later modification of the objects.
+    pub fn set_insecure(&mut self, insecure: bool) {
+        self.insecure = insecure;
+    }
+
+    /// Sets the zstd compression level used for new streams.  0 means the zstd default.
+    pub fn set_zstd_level(&mut self, level: i32) {
+        self.zstd_level = level;
+    }
+
+    /// Sets when the data of new objects gets flushed to disk.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// With Durability::Batch, flushes everything written so far to disk.  This is done before
+    /// writing refs, so there's no need to call it for that.
+    #[instrument(level = "debug", skip(self))]
+    pub fn sync(&self) -> Result<()> {
+        if self.durability == Durability::Batch {
+            syncfs(&self.repository)?;
+        }
+        Ok(())
+    }
+
+    /// Measures the fs-verity digest of the file, falling back to computing it in userspace if the
+    /// repository is in insecure mode and the file doesn't have fs-verity enabled.
+    fn measure_verity(&self, fd: &OwnedFd) -> Result<Sha256HashValue> {
+        match fs_ioc_measure_verity(fd) {
+            Err(err) if self.insecure && verity_unsupported(&err) => {
+                warn_insecure();
+                FsVerityHasher::hash_fd(fd, &self.salt)
+            },
+            result => result,
+        }
+    }
+
     fn ensure_parent<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         match path.as_ref().parent() {
             None => Ok(()),
@@ -113,50 +451,226 @@ impl Repository {
     }
 
     pub fn ensure_object(&self, data: &[u8]) -> Result<Sha256HashValue> {
-        let digest = FsVerityHasher::hash(data);
-        let dir = PathBuf::from(format!("objects/{:02x}", digest[0]));
-        let file = dir.join(hex::encode(&digest[1..]));
+        self.ensure_object_with_signature(data, None)
+    }
+
+    /// Like ensure_object(), but passes a PKCS#7 signature of the fs-verity digest of the data to
+    /// the kernel when enabling verity.  Verity can't be enabled twice, so if the object already
+    /// exists without that signature, it's replaced by a copy which has it.
+    #[instrument(level = "trace", skip_all, fields(size = data.len()))]
+    pub fn ensure_object_with_signature(
+        &self, data: &[u8], signature: Option<&[u8]>
+    ) -> Result<Sha256HashValue> {
+        // Hashing first means that objects which exist already (the common case) aren't written
+        let digest = FsVerityHasher::<Sha256HashValue>::hash_with_salt(data, &self.salt);
+        self.write_object(digest, data, signature)?;
+        Ok(digest)
+    }
 
-        if accessat(&self.repository, &file, Access::READ_OK, AtFlags::empty()) == Ok(()) {
-            return Ok(digest);
+    /// Like ensure_object(), for data which isn't in memory.  The data is hashed as it's written
+    /// out, so that it only gets read once.  The name of the object is only known at the end, so
+    /// the data goes to an anonymous file in objects/ first, which is simply dropped if the object
+    /// exists already.
+    pub fn ensure_object_from_reader<R: Read>(&self, mut reader: R) -> Result<Sha256HashValue> {
+        let mut file = self.object_tmpfile()?;
+        let mut hasher = FsVerityHasher::<Sha256HashValue>::new_with_salt(&self.salt);
+        let mut buffer = vec![0u8; TEE_CHUNK_SIZE];
+        loop {
+            let size = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(size) => size,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err)?,
+            };
+            hasher.update(&buffer[..size]);
+            file.write_all(&buffer[..size])?;
         }
+        let digest = hasher.digest();
 
-        self.ensure_dir(&dir)?;
+        match self.existing_object(digest, None)? {
+            Some(true) => trace!(object = hex::encode(digest), "object exists already"),
+            existing => self.link_object(digest, file, existing.is_some(), None)?,
+        }
+        Ok(digest)
+    }
 
-        let fd = openat(&self.repository, &dir, OFlags::RDWR | OFlags::CLOEXEC | OFlags::TMPFILE, 0o666.into())?;
-        rustix::io::write(&fd, data)?;  // TODO: no write_all() here...
-        fdatasync(&fd)?;
+    /// Checks if there's an object with the digest, and if so, if it can be kept as it is: if it
+    /// has the expected content, and the signature (if one is given).
+    fn existing_object(&self, digest: Sha256HashValue, signature: Option<&[u8]>) -> Result<Option<bool>> {
+        let file = format!("objects/{:02x}/{}", digest[0], hex::encode(&digest[1..]));
+        if accessat(&self.repository, &file, Access::READ_OK, AtFlags::empty()).is_err() {
+            return Ok(None);
+        }
+        let fd = openat(&self.repository, &file, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty())?;
+
+        // Unless it was synced (Durability::Object), a crash can leave an object with incomplete
+        // content behind.  fs-verity is only enabled after the data has been written out, but
+        // without it (in an insecure repository) this hashes the content again.
+        let measured = self.measure_verity(&fd)?;
+        if measured != digest {
+            debug!(measured = hex::encode(measured), "existing object is damaged: replacing it");
+            return Ok(Some(false));
+        }
+
+        let Some(signature) = signature else {
+            return Ok(Some(true));
+        };
+        match fs_ioc_read_verity_signature(&fd) {
+            Ok(existing) => Ok(Some(existing.as_deref() == Some(signature))),
+            Err(err) if self.insecure && verity_unsupported(&err) => {
+                warn_insecure();
+                Ok(Some(true))
+            },
+            Err(err) => {
+                Err(err).with_context(|| format!("Failed to read the signature of object {}", hex::encode(digest)))
+            },
+        }
+    }
+
+    /// Stores data as the object with the given digest, unless it exists already (intact, and
+    /// with the signature, if one is given).
+    #[instrument(level = "trace", skip_all, fields(object = hex::encode(digest), size = data.len()))]
+    fn write_object(&self, digest: Sha256HashValue, data: &[u8], signature: Option<&[u8]>) -> Result<()> {
+        let existing = self.existing_object(digest, signature)?;
+        if existing == Some(true) {
+            trace!("object exists already");
+            return Ok(());
+        }
+
+        let mut file = self.object_tmpfile()?;
+        file.write_all(data)?;
+        self.link_object(digest, file, existing.is_some(), signature)
+    }
+
+    /// Creates an anonymous file for a new object, which link_object() links into place.
+    fn object_tmpfile(&self) -> Result<File> {
+        self.ensure_dir("objects")?;
+        let fd = openat(&self.repository, "objects", OFlags::RDWR | OFlags::CLOEXEC | OFlags::TMPFILE, 0o666.into())?;
+        Ok(File::from(fd))
+    }
+
+    /// Enables verity on a new object (an anonymous file with the content, in objects/) and links
+    /// it into place, replacing the existing object if there is one.  The digest is checked again
+    /// (by the kernel, when possible) first.
+    fn link_object(
+        &self, digest: Sha256HashValue, file: File, replace: bool, signature: Option<&[u8]>
+    ) -> Result<()> {
+        if self.durability == Durability::Object {
+            fdatasync(&file)?;
+        }
 
         // We can't enable verity with an open writable fd, so re-open and close the old one.
-        let ro_fd = open(proc_self_fd(&fd), OFlags::RDONLY, Mode::empty())?;
-        drop(fd);
+        let ro_fd = open(proc_self_fd(&file), OFlags::RDONLY, Mode::empty())?;
+        drop(file);
 
-        fs_ioc_enable_verity::<&OwnedFd, Sha256HashValue>(&ro_fd)?;
+        let verity_enabled = match fs_ioc_enable_verity::<&OwnedFd, Sha256HashValue>(&ro_fd, &self.salt, signature) {
+            Ok(()) => true,
+            Err(err) if self.insecure && verity_unsupported(&err) => {
+                warn_insecure();
+                false
+            },
+            Err(err) => {
+                let path = format!("objects/{:02x}/{}", digest[0], hex::encode(&digest[1..]));
+                return Err(err).with_context(|| format!("Failed to enable fs-verity on {path}"));
+            },
+        };
 
-        // double-check
-        let measured_digest: Sha256HashValue = fs_ioc_measure_verity(&ro_fd)?;
-        assert!(measured_digest == digest);
+        // Double-check the digest that the kernel computed.  Without fs-verity there's nothing to
+        // check: the digest was computed from exactly the data we wrote, and computing it again
+        // would mean reading the whole object back in.
+        if verity_enabled {
+            let measured_digest = self.measure_verity(&ro_fd)?;
+            if measured_digest != digest {
+                bail!(Error::DigestMismatch {
+                    what: format!("Object {} (after enabling fs-verity)", hex::encode(digest)),
+                    expected: digest,
+                    measured: measured_digest,
+                });
+            }
+        }
 
-        if let Err(err) = linkat(CWD, proc_self_fd(&ro_fd), &self.repository, file, AtFlags::SYMLINK_FOLLOW) {
+        let dir = format!("objects/{:02x}", digest[0]);
+        self.ensure_dir(&dir)?;
+        let filename = format!("{dir}/{}", hex::encode(&digest[1..]));
+        if replace {
+            // linkat() can't replace the existing object, but a rename can
+            let tmp = format!("objects/.{}.{}.tmp", hex::encode(digest), std::process::id());
+            linkat(CWD, proc_self_fd(&ro_fd), &self.repository, &tmp, AtFlags::SYMLINK_FOLLOW)?;
+            renameat(&self.repository, &tmp, &self.repository, &filename)?;
+        } else if let Err(err) = linkat(CWD, proc_self_fd(&ro_fd), &self.repository, filename, AtFlags::SYMLINK_FOLLOW) {
             if err.kind() != ErrorKind::AlreadyExists {
                 return Err(err.into());
             }
         }
 
         drop(ro_fd);
-        Ok(digest)
+        Ok(())
+    }
+
+    /// Calls f with an ObjectWriters, which stores objects like ensure_object(), but writes them
+    /// (and enables fs-verity on them) on a number of worker threads.  Only computing the digest
+    /// happens on the calling thread, so it can get on with producing the next object.  All
+    /// objects have been written when this returns.
+    pub fn with_object_writers<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&ObjectWriters) -> Result<T>,
+    {
+        let (sender, receiver) = sync_channel::<(Sha256HashValue, Vec<u8>)>(WRITE_QUEUE_OBJECTS);
+        // Each worker has a reference to the receiver: if they all fail, it gets dropped, and
+        // ObjectWriters::ensure_object() fails instead of blocking forever.
+        let receiver = Arc::new(Mutex::new(receiver));
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        debug!(n_threads, "starting object writers");
+        // The objects are written as part of whatever operation we're called from
+        let span = Span::current();
+
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..n_threads).map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let span = span.clone();
+                scope.spawn(move || -> Result<()> {
+                    let _entered = span.enter();
+                    loop {
+                        // Only hold the lock while waiting for the next object, not while writing it
+                        let next = receiver.lock().expect("object writer thread panicked").recv();
+                        let Ok((digest, data)) = next else {
+                            return Ok(());
+                        };
+                        self.write_object(digest, &data, None)?;
+                    }
+                })
+            }).collect();
+            drop(receiver);
+
+            // The sender gets dropped along with the ObjectWriters, so the workers stop once the
+            // queue is empty.  Their errors are more interesting than the one from f about the
+            // queue being closed.
+            let result = f(&ObjectWriters { repo: self, sender });
+            for worker in workers {
+                worker.join().expect("object writer thread panicked")?;
+            }
+            result
+        })
     }
 
     pub fn open_with_verity(&self, filename: &str, expected_verity: Sha256HashValue) -> Result<OwnedFd> {
-        let fd = openat(&self.repository, filename, OFlags::RDONLY, Mode::empty())?;
-        let measured_verity: Sha256HashValue = fs_ioc_measure_verity(&fd)?;
+        let fd = self.open_existing(filename)?;
+        let measured_verity = self.measure_verity(&fd)?;
         if measured_verity != expected_verity {
-            bail!("bad verity!")
+            bail!(Error::DigestMismatch { what: filename.to_string(), expected: expected_verity, measured: measured_verity })
         } else {
             Ok(fd)
         }
     }
 
+    /// Opens a file for reading, failing with Error::NotFound if it doesn't exist.
+    fn open_existing(&self, filename: &str) -> Result<OwnedFd> {
+        match openat(&self.repository, filename, OFlags::RDONLY, Mode::empty()) {
+            Err(err @ Errno::NOENT) => Err(err).context(Error::NotFound(filename.to_string())),
+            result => Ok(result?),
+        }
+    }
+
     /// category is like "streams" or "images"
     /// name is like "refs/1000/user/xyz" (with '/') or a sha256 hex hash value (without '/')
     fn open_in_category(&self, category: &str, name: &str) -> Result<OwnedFd> {
@@ -164,7 +678,7 @@ impl Repository {
 
         if name.contains("/") {
             // no fsverity checking on this path
-            Ok(openat(&self.repository, filename, OFlags::RDONLY, Mode::empty())?)
+            self.open_existing(&filename)
         } else {
             // this must surely be a hash value, and we want to verify it
             let mut hash = Sha256HashValue::EMPTY;
@@ -172,42 +686,608 @@ impl Repository {
             self.open_with_verity(&filename, hash)
         }
     }
-    pub fn open_stream(&self, name: &str) -> Result<zstd::stream::read::Decoder<BufReader<File>>> {
-        let file = File::from(self.open_in_category("streams", name)?);
-        Ok(zstd::stream::read::Decoder::new(file)?)
+
+    /// Returns a writer for a new stream, which is compressed using the configured compression
+    /// level and the repository's dictionary, if it has one.  Store the result of finish() with
+    /// ensure_object().
+    pub fn create_stream(&self) -> Result<StreamWriter> {
+        Ok(StreamWriter { level: self.zstd_level, dictionary: self.dictionary.clone(), body: vec![] })
+    }
+
+    /// Reads a zstd dictionary from the object store.
+    fn read_dictionary(&self, id: Sha256HashValue) -> Result<Arc<Vec<u8>>> {
+        if let Some(dictionary) = self.dictionary.as_ref().filter(|dictionary| dictionary.id == id) {
+            return Ok(Arc::clone(&dictionary.data));
+        }
+        let mut data = vec![];
+        File::from(self.open_object(id)?).read_to_end(&mut data)
+            .with_context(|| format!("Failed to read zstd dictionary {}", hex::encode(id)))?;
+        Ok(Arc::new(data))
+    }
+
+    fn open_stream_file(&self, name: &str) -> Result<StreamFile> {
+        let fd = self.open_in_category("streams", name)?;
+        // Measuring only succeeds if fs-verity is enabled, not just in userspace
+        Ok(match fs_ioc_measure_verity::<_, Sha256HashValue>(&fd) {
+            Ok(_) => StreamFile::Mapped(Cursor::new(Mmap::map(&fd)?)),
+            Err(_) => StreamFile::Buffered(BufReader::new(File::from(fd))),
+        })
+    }
+
+    /// Reads the header of a stream, which is a zstd frame of its own.  Returns the rest of the
+    /// stream (still compressed), the dictionary that it's compressed with, and the table of the
+    /// objects it refers to.
+    fn read_stream_header(
+        &self, name: &str
+    ) -> Result<(StreamFile, Option<Sha256HashValue>, Vec<Sha256HashValue>)> {
+        let mut header = zstd::stream::read::Decoder::with_buffer(self.open_stream_file(name)?)?.single_frame();
+        match read_splitstream_header(&mut header)? {
+            SplitStreamHeader::Version(SPLITSTREAM_VERSION) => {
+                let dictionary = read_splitstream_dictionary(&mut header)?;
+                let objects = read_splitstream_object_table(&mut header)?;
+                if header.read(&mut [0])? != 0 {
+                    bail!(Error::InvalidFormat(format!("Stream {name} has data in the frame of its header")));
+                }
+                Ok((header.finish(), dictionary, objects))
+            },
+            SplitStreamHeader::Version(version) => {
+                bail!(Error::InvalidFormat(format!("Stream {name} has unsupported format version {version}")))
+            },
+            SplitStreamHeader::Legacy(_) => {
+                bail!(Error::InvalidFormat(format!(
+                    "Stream {name} is in the old format without a header (see cfsctl migrate-streams)"
+                )))
+            },
+        }
+    }
+
+    /// Opens a stream for reading, positioned after the header.
+    #[instrument(level = "debug", skip(self))]
+    pub fn open_stream(&self, name: &str) -> Result<StreamReader> {
+        let (blocks, dictionary, _) = self.read_stream_header(name)?;
+        Ok(match dictionary {
+            Some(id) => zstd::stream::read::Decoder::with_dictionary(blocks, &self.read_dictionary(id)?)?,
+            None => zstd::stream::read::Decoder::with_buffer(blocks)?,
+        })
+    }
+
+    /// Returns the (distinct) objects referenced by a stream, including its zstd dictionary.  This
+    /// only reads the header of the stream.
+    pub fn stream_objects(&self, name: &str) -> Result<Vec<Sha256HashValue>> {
+        let (_, dictionary, mut objects) = self.read_stream_header(name)?;
+        objects.extend(dictionary);
+        Ok(objects)
     }
 
-    fn open_object(&self, id: Sha256HashValue) -> Result<OwnedFd> {
+    /// Rewrites all streams which were written in the old format without a header, and updates
+    /// the refs that point to them.  Since the digest of a stream changes when it's rewritten, the
+    /// old entries in streams/ are removed.  The old objects stay around until the next garbage
+    /// collection.  Returns the number of streams which were migrated.
+    #[instrument(skip(self))]
+    pub fn migrate_streams(&self) -> Result<usize> {
+        flock(&self.repository, FlockOperation::LockExclusive)?;
+
+        let mut migrated = vec![];
+        for item in Dir::read_from(&self.openat("streams", OFlags::RDONLY | OFlags::DIRECTORY)?)? {
+            let entry = item?;
+            if entry.file_type() != FileType::Symlink {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mut stream = zstd::stream::read::Decoder::with_buffer(self.open_stream_file(&name)?)?;
+            let SplitStreamHeader::Legacy(start) = read_splitstream_header(&mut stream)? else {
+                continue;
+            };
+
+            let mut new_stream = self.create_stream()?;
+            new_stream.write_all(&start)?;
+            std::io::copy(&mut stream, &mut new_stream)?;
+            let object_id = self.ensure_object(&new_stream.finish()?)?;
+            migrated.push((name, object_id));
+        }
+
+        self.sync()?;
+        for (old_name, object_id) in migrated.iter() {
+            let new_name = hex::encode(object_id);
+            let object_path = format!("objects/{:02x}/{}", object_id[0], hex::encode(&object_id[1..]));
+            match self.symlink(format!("streams/{new_name}"), &object_path) {
+                Ok(()) => {},
+                Err(err) if err.downcast_ref::<Errno>() == Some(&Errno::EXIST) => {},
+                Err(err) => Err(err)?,
+            }
+
+            let refs = self.openat("streams/refs", OFlags::RDONLY | OFlags::DIRECTORY)?;
+            Repository::relink_symlinkdir(&refs, old_name, &new_name)?;
+            unlinkat(&self.repository, format!("streams/{old_name}"), AtFlags::empty())?;
+            progress::info(format!("{old_name} -> {new_name}"));
+        }
+
+        flock(&self.repository, FlockOperation::LockShared)?;
+        Ok(migrated.len())
+    }
+
+    /// Changes all symlinks below dirfd which point to old (a stream or image name) to point to new
+    /// instead.
+    fn relink_symlinkdir(dirfd: &OwnedFd, old: &str, new: &str) -> Result<()> {
+        for item in Dir::read_from(dirfd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            match entry.file_type() {
+                FileType::Directory if filename != c"." && filename != c".." => {
+                    let subdir = openat(dirfd, filename, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())?;
+                    Repository::relink_symlinkdir(&subdir, old, new)?;
+                },
+                FileType::Symlink => {
+                    let target = readlinkat(dirfd, filename, [])?.into_string()?;
+                    if let Some(prefix) = target.strip_suffix(old) {
+                        // replace the link atomically
+                        let tmp = format!(".{}.tmp", filename.to_string_lossy());
+                        symlinkat(format!("{prefix}{new}"), dirfd, &tmp)?;
+                        renameat(dirfd, &tmp, dirfd, filename)?;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Trains a zstd dictionary (of at most max_size bytes) on the inline data of all streams in
+    /// the repository, and stores it for compressing future streams.  The inline data is mostly
+    /// tar headers, which compress very well against a dictionary.  The dictionary is stored as an
+    /// object, which each stream refers to by its digest, so training a new one replaces the old
+    /// one for new streams only.  Returns the size of the dictionary.
+    #[instrument(skip(self))]
+    pub fn train_dictionary(&self, max_size: usize) -> Result<usize> {
+        let mut samples = vec![];
+        for item in Dir::read_from(&self.openat("streams", OFlags::RDONLY | OFlags::DIRECTORY)?)? {
+            let entry = item?;
+            if entry.file_type() != FileType::Symlink {
+                continue;
+            }
+
+            let mut split_stream = self.open_stream(&entry.file_name().to_string_lossy())?;
+            while let Some(chunk) = read_splitstream_chunk(&mut split_stream)? {
+                if let SplitStreamData::Inline(data) = chunk {
+                    samples.push(data);
+                }
+            }
+        }
+
+        if samples.is_empty() {
+            bail!("No streams to train the dictionary on");
+        }
+
+        let dictionary = zstd::dict::from_samples(&samples, max_size)
+            .with_context(|| format!("Failed to train dictionary on {} samples", samples.len()))?;
+
+        let id = self.ensure_object(&dictionary)?;
+        let tmp = format!("zstd-dictionary.{}.tmp", std::process::id());
+        symlinkat(format!("objects/{:02x}/{}", id[0], hex::encode(&id[1..])), &self.repository, &tmp)?;
+        renameat(&self.repository, &tmp, &self.repository, "zstd-dictionary")?;
+
+        Ok(dictionary.len())
+    }
+
+    pub fn open_object(&self, id: Sha256HashValue) -> Result<OwnedFd> {
         self.open_with_verity(&format!("objects/{:02x}/{}", id[0], hex::encode(&id[1..])), id)
     }
 
+    /// Reads the chunks of the stream and opens the objects it refers to, asking the kernel to
+    /// start reading each of them, and sends them to merge_splitstream().
+    fn prefetch_splitstream<R: Read>(
+        &self, split_stream: &mut R, sender: &SyncSender<Result<Prefetched>>
+    ) -> Result<()> {
+        #[cfg(feature = "io-uring")]
+        if let Some(mut reader) = BulkReader::new(READAHEAD_OBJECTS) {
+            return self.prefetch_splitstream_bulk(split_stream, sender, &mut reader);
+        }
+
+        while let Some(chunk) = read_splitstream_chunk(split_stream)? {
+            let item = match chunk {
+                SplitStreamData::Inline(data) => Prefetched::Inline(data),
+                SplitStreamData::External(id) => {
+                    let fd = self.open_object(id)?;
+                    fadvise(&fd, 0, 0, Advice::WillNeed)?;
+                    Prefetched::Object(File::from(fd))
+                },
+            };
+            if sender.send(Ok(item)).is_err() {
+                break;  // the writer failed
+            }
+        }
+        Ok(())
+    }
+
+    /// Like prefetch_splitstream(), but works in batches of READAHEAD_OBJECTS chunks, and reads all
+    /// of the small objects in a batch at once, with io_uring.
+    #[cfg(feature = "io-uring")]
+    fn prefetch_splitstream_bulk<R: Read>(
+        &self, split_stream: &mut R, sender: &SyncSender<Result<Prefetched>>, reader: &mut BulkReader
+    ) -> Result<()> {
+        loop {
+            let mut batch = vec![];
+            let mut small = vec![];  // (index in batch, file, size)
+            while batch.len() < READAHEAD_OBJECTS {
+                let Some(chunk) = read_splitstream_chunk(split_stream)? else {
+                    break;
+                };
+                batch.push(match chunk {
+                    SplitStreamData::Inline(data) => Some(Prefetched::Inline(data)),
+                    SplitStreamData::External(id) => {
+                        let fd = self.open_object(id)?;
+                        let size = rustix::fs::fstat(&fd)?.st_size as u64;
+                        if size <= BULK_READ_MAX_SIZE {
+                            small.push((batch.len(), File::from(fd), size as usize));
+                            None
+                        } else {
+                            fadvise(&fd, 0, 0, Advice::WillNeed)?;
+                            Some(Prefetched::Object(File::from(fd)))
+                        }
+                    },
+                });
+            }
+            if batch.is_empty() {
+                return Ok(());
+            }
+
+            let (indices, files): (Vec<_>, Vec<_>) = small.into_iter()
+                .map(|(idx, file, size)| (idx, (file, size)))
+                .unzip();
+            for (idx, data) in indices.into_iter().zip(reader.read_all(&files)?) {
+                batch[idx] = Some(Prefetched::Inline(data));
+            }
+
+            for item in batch {
+                if sender.send(Ok(item.expect("every chunk was read"))).is_err() {
+                    return Ok(());  // the writer failed
+                }
+            }
+        }
+    }
+
+    /// Writes the merged content of the stream.  The objects are read ahead of the writer, which
+    /// keeps several reads in flight at once on slow storage.
+    #[instrument(skip(self, stream))]
     pub fn merge_splitstream<W: Write>(&self, name: &str, stream: &mut W) -> Result<()> {
         let mut split_stream = self.open_stream(name)?;
-        splitstream_merge(
+        let (sender, receiver) = sync_channel(READAHEAD_OBJECTS);
+
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                if let Err(err) = self.prefetch_splitstream(&mut split_stream, &sender) {
+                    let _ = sender.send(Err(err));
+                }
+            });
+
+            // If we return early, the receiver gets dropped, which stops the prefetching thread.
+            for item in receiver {
+                match item? {
+                    Prefetched::Inline(data) => stream.write_all(&data)?,
+                    Prefetched::Object(mut file) => {
+                        std::io::copy(&mut file, stream)?;
+                    },
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns a reader for the merged content of a splitstream, which produces the original file
+    /// on the fly.  Wrap it in a compressing reader (like zstd::stream::read::Encoder) to stream a
+    /// compressed blob somewhere without writing it to disk first.
+    pub fn read_splitstream(&self, name: &str) -> Result<impl Read + '_> {
+        let split_stream = self.open_stream(name)?;
+        Ok(SplitStreamMerger::new(split_stream, |id: Sha256HashValue| -> Result<File> {
+            Ok(File::from(self.open_object(id)?))
+        }))
+    }
+
+    /// Writes part of the merged content of a splitstream.  Objects before the start of the range
+    /// are skipped over using only their size, so this doesn't need to read everything that comes
+    /// before the range.
+    pub fn merge_splitstream_range<W: Write>(
+        &self, name: &str, stream: &mut W, offset: u64, length: u64
+    ) -> Result<()> {
+        let mut split_stream = self.open_stream(name)?;
+        splitstream_merge_range(
             &mut split_stream,
             stream,
+            offset,
+            length,
+            |id: Sha256HashValue| self.object_size(id),
             |id: Sha256HashValue| -> Result<Vec<u8>> {
                 let mut data = vec![];
                 File::from(self.open_object(id)?).read_to_end(&mut data)?;
                 Ok(data)
             }
-        )?;
-
-        Ok(())
+        )
     }
 
     /// this function is not safe for untrusted users
-    pub fn import_image<R: Read>(&self, name: &str, image: &mut R) -> Result<Sha256HashValue> {
+    #[instrument(skip(self, image, signature))]
+    pub fn import_image<R: Read>(
+        &self, name: &str, image: &mut R, signature: Option<&[u8]>
+    ) -> Result<Sha256HashValue> {
         let mut data = vec![];
         image.read_to_end(&mut data)?;
-        let object_id = self.ensure_object(&data)?;
+        let object_id = self.ensure_object_with_signature(&data, signature)?;
         self.link_ref(name, "images", object_id)
     }
 
-    pub fn mount(self, name: &str, mountpoint: &str) -> Result<()> {
+    /// Mounts the image on mountpoint.  If writable is set, the mount gets an upper layer which
+    /// is kept in state/ under the digest of the image, so changes persist across mounts of the
+    /// same image.  Prune it with prune_state() once the image is gone.
+    ///
+    /// If require_verity is set, the image must have fs-verity enabled (even in insecure mode) with
+    /// the digest that it's stored under, and overlayfs is told to check the digests of all
+    /// objects as they're opened.
+    ///
+    /// If the repository has trusted keys, the image must be signed by one of them.
+    pub fn mount(self, name: &str, mountpoint: &str, writable: bool, require_verity: bool) -> Result<()> {
         let image = self.open_in_category("images", name)?;
         let object_path = format!("{}/objects", self.path);
-        mount_fd(image, &object_path, mountpoint)
+
+        if require_verity {
+            let measured: Sha256HashValue = fs_ioc_measure_verity(&image)
+                .with_context(|| format!("Image {name} doesn't have fs-verity enabled"))?;
+            let expected = Repository::object_id_of(&image)?;
+            if measured != expected {
+                bail!(Error::DigestMismatch { what: format!("Image {name}"), expected, measured });
+            }
+        }
+
+        let keys = self.trusted_keys()?;
+        if !keys.is_empty() {
+            self.check_signature(name, self.measure_verity(&image)?, &keys)?;
+        }
+
+        if writable {
+            let state = self.ensure_state(self.measure_verity(&image)?)?;
+            let upperdir = format!("{}/{state}/upper", self.path);
+            let workdir = format!("{}/{state}/work", self.path);
+            mount_fd(image, &object_path, Some((&upperdir, &workdir)), require_verity, mountpoint)
+        } else {
+            mount_fd(image, &object_path, None, require_verity, mountpoint)
+        }
+    }
+
+    /// Returns the fs-verity digest of the named image, as used for the composefs= kernel
+    /// argument, for example.
+    pub fn image_digest(&self, name: &str) -> Result<Sha256HashValue> {
+        self.measure_verity(&self.open_in_category("images", name)?)
+    }
+
+    /// Signs the named image with the key, and stores the signature in signatures/.  Returns the
+    /// fs-verity digest of the image.
+    pub fn sign_image(&self, name: &str, key: &SigningKey) -> Result<Sha256HashValue> {
+        let digest = self.image_digest(name)?;
+        let dir = format!("signatures/{}", hex::encode(digest));
+        self.ensure_dir(&dir)?;
+        let filename = format!("{dir}/{}", key.public_key().id());
+        // Replace an existing signature atomically, so that a reader never sees a partial one
+        let tmp = format!("{dir}/.{}.tmp", key.public_key().id());
+        let fd = openat(
+            &self.repository, &tmp, OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::CLOEXEC, 0o644.into()
+        )?;
+        File::from(fd).write_all(&key.sign_image(&digest))?;
+        renameat(&self.repository, &tmp, &self.repository, &filename)?;
+        Ok(digest)
+    }
+
+    /// Returns the keys in trusted-keys/.  If there are any, mount() only mounts images which
+    /// are signed by one of them.
+    pub fn trusted_keys(&self) -> Result<Vec<PublicKey>> {
+        let Some(dirfd) = self.open_optional_dir("trusted-keys")? else {
+            return Ok(vec![]);
+        };
+
+        let mut keys = vec![];
+        for item in Dir::read_from(&dirfd)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"." || filename == c".." {
+                continue;
+            }
+            let mut pem = String::new();
+            File::from(openat(&dirfd, filename, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty())?)
+                .read_to_string(&mut pem)?;
+            keys.push(PublicKey::parse(&pem).with_context(|| format!("Invalid key trusted-keys/{filename:?}"))?);
+        }
+        Ok(keys)
+    }
+
+    /// Checks that the named image is signed by one of the keys, and returns that key.  Fails
+    /// with Error::Untrusted if it isn't.
+    pub fn verify_image_signature(&self, name: &str, keys: &[PublicKey]) -> Result<PublicKey> {
+        self.check_signature(name, self.image_digest(name)?, keys)
+    }
+
+    fn check_signature(&self, name: &str, digest: Sha256HashValue, keys: &[PublicKey]) -> Result<PublicKey> {
+        for key in keys {
+            let filename = format!("signatures/{}/{}", hex::encode(digest), key.id());
+            if let Some(signature) = read_optional(&self.repository, &filename)? {
+                if key.verify_image(&digest, &signature) {
+                    return Ok(*key);
+                }
+                progress::warn(format!("Invalid signature of {name} by {}", key.id()));
+            }
+        }
+        bail!(Error::Untrusted(format!("Image {name}")))
+    }
+
+    /// Returns systemd units which mount the image on mountpoint at boot.  See systemd.rs.
+    pub fn mount_units(&self, name: &str, mountpoint: &str) -> Result<Vec<MountUnit>> {
+        let digest = self.image_digest(name)?;
+        let path = std::fs::canonicalize(&self.path)?;
+        let path = path.to_str().with_context(|| format!("Repository path {path:?} isn't UTF-8"))?;
+
+        let image_path = format!("{path}/objects/{:02x}/{}", digest[0], hex::encode(&digest[1..]));
+        composefs_mount_units(&image_path, &hex::encode(digest), &format!("{path}/objects"), mountpoint)
+    }
+
+    /// Creates the state directory (see mount()) for the image with the given digest, if it
+    /// doesn't exist yet.  Returns its path relative to the repository.
+    fn ensure_state(&self, digest: Sha256HashValue) -> Result<String> {
+        let state = format!("state/{}", hex::encode(digest));
+        self.ensure_dir(format!("{state}/upper"))?;
+        self.ensure_dir(format!("{state}/work"))?;
+        Ok(state)
+    }
+
+    /// Returns the absolute path of the upper directory in the state of the named image (see
+    /// mount()), if it has state.
+    pub fn state_upperdir(&self, name: &str) -> Result<Option<PathBuf>> {
+        let upper = format!("state/{}/upper", hex::encode(self.image_digest(name)?));
+        if accessat(&self.repository, &upper, Access::EXISTS, AtFlags::empty()).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(std::fs::canonicalize(format!("{}/{upper}", self.path))?))
+    }
+
+    /// Carries the local changes to path (like "etc") in the state of the old image over to the
+    /// state of the new image.  Since the state is the upper layer of the overlayfs, it contains
+    /// exactly the files which were changed or added locally, and whiteouts for the ones which
+    /// were removed.  Mounting the new image with that gives a three-way merge with the old image
+    /// as the base: local changes win, and everything else comes from the new image.  Returns
+    /// false if there were no local changes.
+    pub fn merge_state(&self, old: &str, new: &str, path: &str) -> Result<bool> {
+        let old_state = format!("state/{}/upper/{path}", hex::encode(self.image_digest(old)?));
+        let new_state = format!("{}/upper/{path}", self.ensure_state(self.image_digest(new)?)?);
+
+        if accessat(&self.repository, &old_state, Access::EXISTS, AtFlags::SYMLINK_NOFOLLOW).is_err() {
+            return Ok(false);
+        }
+        if accessat(&self.repository, &new_state, Access::EXISTS, AtFlags::SYMLINK_NOFOLLOW) == Ok(()) {
+            bail!("Image {new} already has state for {path}");
+        }
+        self.ensure_parent(&new_state)?;
+
+        // cp knows how to preserve everything, including the overlayfs xattrs and whiteouts
+        let status = Command::new("cp")
+            .args(["-a", "--reflink=auto"])
+            .arg(format!("{}/{old_state}", self.path))
+            .arg(format!("{}/{new_state}", self.path))
+            .status()?;
+        if !status.success() {
+            self.remove_state(new, path)?;
+            bail!("Failed to copy {old_state} to {new_state}: {status}");
+        }
+        Ok(true)
+    }
+
+    /// Removes path (like "etc") from the state of the named image, undoing merge_state(), so
+    /// that it can be run again.
+    pub fn remove_state(&self, name: &str, path: &str) -> Result<()> {
+        let state = format!("{}/state/{}/upper/{path}", self.path, hex::encode(self.image_digest(name)?));
+        let result = match std::fs::symlink_metadata(&state) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&state),
+            Ok(..) => std::fs::remove_file(&state),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        };
+        result.with_context(|| format!("Failed to remove {state}"))
+    }
+
+    /// Returns the digest that an open object is stored under, from its path in objects/.
+    fn object_id_of(fd: &OwnedFd) -> Result<Sha256HashValue> {
+        let path = std::fs::read_link(proc_self_fd(fd))?;
+        let (Some(dir), Some(file)) = (path.parent().and_then(Path::file_name), path.file_name()) else {
+            bail!("{path:?} isn't an object");
+        };
+
+        let mut id = Sha256HashValue::EMPTY;
+        hex::decode_to_slice(format!("{}{}", dir.to_string_lossy(), file.to_string_lossy()), &mut id)
+            .with_context(|| format!("{path:?} isn't an object"))?;
+        Ok(id)
+    }
+
+    /// Removes the state directories (see mount()) of images which are no longer in the
+    /// repository.  This doesn't check if the state is still in use by a mount, so only do it
+    /// after unmounting.  Returns the digests of the images whose state was removed.
+    pub fn prune_state(&self) -> Result<Vec<String>> {
+        let state = match openat(&self.repository, "state", OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::NOENT) => return Ok(vec![]),
+            Err(err) => Err(err)?,
+        };
+
+        flock(&self.repository, FlockOperation::LockExclusive)?;
+
+        let mut pruned = vec![];
+        for item in Dir::read_from(&state)? {
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"." || filename == c".." {
+                continue;
+            }
+
+            let digest = filename.to_string_lossy().to_string();
+            if accessat(&self.repository, format!("images/{digest}"), Access::EXISTS, AtFlags::SYMLINK_NOFOLLOW) == Ok(()) {
+                continue;
+            }
+
+            std::fs::remove_dir_all(format!("{}/state/{digest}", self.path))
+                .with_context(|| format!("Failed to remove state/{digest}"))?;
+            pruned.push(digest);
+        }
+
+        flock(&self.repository, FlockOperation::LockShared)?;
+        Ok(pruned)
+    }
+
+    fn remove_signatures(&self, image: Sha256HashValue) -> Result<()> {
+        match std::fs::remove_dir_all(format!("{}/signatures/{}", self.path, hex::encode(image))) {
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Returns the content of the image in composefs dumpfile format, one entry per line.
+    pub fn dump_image(&self, name: &str) -> Result<String> {
+        let image = self.open_in_category("images", name)?;
+
+        // see the comment in gc() about passing the file via stdin
+        let output = Command::new("composefs-info")
+            .stdin(File::from(image))
+            .args(["dump", "/proc/self/fd/0"])
+            .output()?;
+
+        if !output.status.success() {
+            bail!("composefs-info dump failed: {}", String::from_utf8_lossy(&output.stderr));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Stores an arbitrary file as a stream, split into content-defined chunks, so that different
+    /// versions of the same file share most of their objects.  The stream gets the ref
+    /// sha256/{digest}, where digest is the sha256 of the content, and optionally another name.
+    /// Returns the sha256 digest.
+    #[instrument(skip(self, file))]
+    pub fn add_file<R: Read>(&self, file: &mut R, name: Option<&str>) -> Result<[u8; 32]> {
+        let mut reader = Sha256Reader::new(file);
+        let mut split_stream = self.create_stream()?;
+        let mut progress = Progress::new("Adding file", "chunks", None);
+        self.with_object_writers(|writers| cdc::split(&mut reader, &mut split_stream, |data: Vec<u8>| {
+            progress.inc(1);
+            writers.ensure_object(data)
+        }))?;
+        drop(progress);
+        let sha256 = reader.digest();
+        let object_id = self.ensure_object(&split_stream.finish()?)?;
+
+        let sha256_ref = format!("sha256/{}", hex::encode(sha256));
+        if accessat(&self.repository, format!("streams/refs/{sha256_ref}"), Access::EXISTS, AtFlags::SYMLINK_NOFOLLOW).is_err() {
+            self.link_ref(&sha256_ref, "streams", object_id)?;
+        }
+        if let Some(name) = name {
+            self.link_ref(name, "streams", object_id)?;
+        }
+
+        Ok(sha256)
     }
 
     pub fn link_ref(
@@ -217,9 +1297,15 @@ impl Repository {
         let category_path = format!("{}/{}", category, hex::encode(object_id));
         let ref_path = format!("{}/refs/{}", category, name);
 
+        // Make sure that the objects are on disk before anything refers to them
+        self.sync()?;
         self.symlink(&ref_path, &category_path)?;
-        self.symlink(&category_path, &object_path)?;
-        Ok(object_id)
+        // This exists already if the same content was stored under another name before.
+        match self.symlink(&category_path, &object_path) {
+            Ok(()) => Ok(object_id),
+            Err(err) if err.downcast_ref::<Errno>() == Some(&Errno::EXIST) => Ok(object_id),
+            Err(err) => Err(err),
+        }
     }
 
     fn symlink<P: AsRef<Path>>(&self, name: P, target: &str) -> Result<()> {
@@ -252,6 +1338,18 @@ impl Repository {
         }
     }
 
+    // The digest of an "objects/xx/..." path
+    fn parse_object_path(path: &[u8]) -> Result<Sha256HashValue> {
+        match path.strip_prefix(b"objects/") {
+            Some(name) if name.len() == 65 && name[2] == b'/' => {
+                let mut value = Sha256HashValue::EMPTY;
+                hex::decode_to_slice([&name[..2], &name[3..]].concat(), &mut value)?;
+                Ok(value)
+            },
+            _ => bail!("Not an object path"),
+        }
+    }
+
     fn walk_symlinkdir(fd: OwnedFd, objects: &mut HashSet<Sha256HashValue>) -> Result<()> {
         for item in Dir::read_from(&fd)? {
             match item {
@@ -285,107 +1383,295 @@ impl Repository {
         Ok(openat(&self.repository, name, flags, Mode::empty())?)
     }
 
-    fn gc_category(&self, category: &str) -> Result<HashSet<Sha256HashValue>> {
-        let mut objects = HashSet::<Sha256HashValue>::new();
+    /// Opens a directory in the repository, or returns None if it doesn't exist (yet).
+    fn open_optional_dir(&self, name: &str) -> Result<Option<OwnedFd>> {
+        match openat(&self.repository, name, OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty()) {
+            Ok(fd) => Ok(Some(fd)),
+            Err(Errno::NOENT) => Ok(None),
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Returns the digests of the entries in images/ or streams/ which a ref points to, and the
+    /// ones that no ref points to.
+    fn gc_category(&self, category: &str) -> Result<(HashSet<Sha256HashValue>, Vec<Sha256HashValue>)> {
+        let mut live = HashSet::<Sha256HashValue>::new();
+        let mut dead = vec![];
 
-        let category_fd = self.openat(category, OFlags::RDONLY | OFlags::DIRECTORY)?;
+        let Some(category_fd) = self.open_optional_dir(category)? else {
+            return Ok((live, dead));
+        };
 
-        let refs = openat(&category_fd, "refs", OFlags::RDONLY | OFlags::DIRECTORY, Mode::empty())?;
-        Repository::walk_symlinkdir(refs, &mut objects)?;
+        if let Some(refs) = self.open_optional_dir(&format!("{category}/refs"))? {
+            Repository::walk_symlinkdir(refs, &mut live)?;
+        }
 
         for item in Dir::read_from(&category_fd)? {
-            match item {
-                Err(x) => Err(x)?,
-                Ok(entry) => {
-                    let filename = entry.file_name();
-                    if filename != c"refs" && filename != c"." && filename != c".." {
-                        if entry.file_type() != FileType::Symlink {
-                            bail!("category directory contains non-symlink");
-                        }
-                        let mut value = Sha256HashValue::EMPTY;
-                        hex::decode_to_slice(filename.to_bytes(), &mut value)?;
+            let entry = item?;
+            let filename = entry.file_name();
+            if filename == c"refs" || filename == c"." || filename == c".." {
+                continue;
+            }
+            if entry.file_type() != FileType::Symlink {
+                bail!("category directory contains non-symlink");
+            }
 
-                        if !objects.contains(&value) {
-                            println!("rm {}/{:?}", category, filename);
-                        }
-                    }
-                }
+            let mut value = Sha256HashValue::EMPTY;
+            hex::decode_to_slice(filename.to_bytes(), &mut value)?;
+            if !live.contains(&value) {
+                dead.push(value);
             }
         }
 
+        Ok((live, dead))
+    }
+
+    /// Pins an image or a stream (depending on category), so that gc() keeps it even if no other
+    /// ref points to it.  A pin is a ref named pinned/{digest}.  Returns the digest.
+    pub fn pin(&self, category: &str, name: &str) -> Result<Sha256HashValue> {
+        let id = Repository::object_id_of(&self.open_in_category(category, name)?)?;
+        match self.link_ref(&format!("pinned/{}", hex::encode(id)), category, id) {
+            Err(err) if err.downcast_ref::<Errno>() == Some(&Errno::EXIST) => Ok(id),
+            result => result,
+        }
+    }
+
+    /// Removes the pin of an image or a stream added by pin().  Returns the digest.
+    pub fn unpin(&self, category: &str, name: &str) -> Result<Sha256HashValue> {
+        let id = Repository::object_id_of(&self.open_in_category(category, name)?)?;
+        match unlinkat(&self.repository, format!("{category}/refs/pinned/{}", hex::encode(id)), AtFlags::empty()) {
+            Ok(()) => Ok(id),
+            Err(Errno::NOENT) => bail!("{name} isn't pinned"),
+            Err(err) => Err(err)?,
+        }
+    }
+
+    /// Returns the objects which an image refers to for its file content.
+    fn image_objects(&self, image: Sha256HashValue) -> Result<Vec<Sha256HashValue>> {
+        // composefs-info mmaps the file, so pipes aren't normally OK but we pass the
+        // underlying file directly, which works.
+        let output = Command::new("composefs-info")
+            .stdin(File::from(self.open_object(image)?))
+            .args(["objects", "/proc/self/fd/0"])
+            .output()?
+            .stdout;
+
+        if output.len() % 66 != 0 {
+            bail!("composefs-info gave invalid output (wrong size)");
+        }
+
+        let mut objects = vec![];
+        for line in output.chunks_exact(66) {
+            if line[2] != b'/' || line[65] != b'\n' {
+                bail!("composefs-info gave invalid output");
+            }
+            let mut value = Sha256HashValue::EMPTY;
+            hex::decode_to_slice(&line[0..2], &mut value[0..1])?;
+            hex::decode_to_slice(&line[3..65], &mut value[1..32])?;
+            objects.push(value);
+        }
         Ok(objects)
     }
 
-    pub fn gc(&self) -> Result<()> {
-        flock(&self.repository, FlockOperation::LockExclusive)?;
+    /// Returns the objects which an entry in images/ or streams/ refers to, including itself.
+    fn category_objects(&self, category: &str, id: Sha256HashValue) -> Result<Vec<Sha256HashValue>> {
+        let mut objects = match category {
+            "images" => self.image_objects(id)?,
+            _ => self.stream_objects(&hex::encode(id))?,
+        };
+        objects.push(id);
+        Ok(objects)
+    }
+
+    fn object_size(&self, id: Sha256HashValue) -> Result<u64> {
+        let filename = format!("objects/{:02x}/{}", id[0], hex::encode(&id[1..]));
+        Ok(statat(&self.repository, filename, AtFlags::empty())?.st_size as u64)
+    }
+
+    /// Lists the streams which no ref points to.  Returns the digest and the (compressed) size of
+    /// each stream, along with the number of distinct objects it references and their total size.
+    /// Those objects might still be shared with other streams or images.
+    pub fn orphan_streams(&self) -> Result<Vec<(Sha256HashValue, u64, usize, u64)>> {
+        let (_, dead) = self.gc_category("streams")?;
+
+        let mut orphans = vec![];
+        for stream_id in dead {
+            let objects = self.stream_objects(&hex::encode(stream_id))?;
+            let mut objects_size = 0;
+            for id in objects.iter() {
+                objects_size += self.object_size(*id)?;
+            }
+
+            orphans.push((stream_id, self.object_size(stream_id)?, objects.len(), objects_size));
+        }
+
+        Ok(orphans)
+    }
 
-        let mut objects = HashSet::new();
+    /// Returns the modification time of a file (or symlink), in seconds since the epoch.
+    fn mtime<P: rustix::path::Arg>(dirfd: &OwnedFd, name: P) -> Result<u64> {
+        Ok(statat(dirfd, name, AtFlags::SYMLINK_NOFOLLOW)?.st_mtime as u64)
+    }
 
-        for object in self.gc_category("images")? {
-            println!("{} lives as an image", hex::encode(object));
-            objects.insert(object);
+    /// Removes the images and streams which no ref points to, and the objects which are no longer
+    /// referenced by any of the remaining images and streams.  If dry_run is set, nothing is
+    /// removed, but the result is the same.
+    ///
+    /// The result groups the removed objects by the images and streams which referred to them (as
+    /// "images/{digest}" or "streams/{digest}"), so it's possible to see what removing a ref
+    /// actually freed.  Objects which were referred to by several of them are in a group of their
+    /// own, and objects which nothing referred to at all (left over from an interrupted import,
+    /// for example) are in a group with no roots.
+    ///
+    /// If keep_younger_than is given, images, streams and objects which were added less than that
+    /// long ago are kept as if a ref pointed to them, so that content which was just pulled isn't
+    /// removed before something gets around to adding a ref for it.
+    #[instrument(skip(self))]
+    pub fn gc(&self, dry_run: bool, keep_younger_than: Option<Duration>) -> Result<Vec<Garbage>> {
+        let cutoff = match keep_younger_than {
+            Some(age) => SystemTime::now().duration_since(UNIX_EPOCH)?.saturating_sub(age).as_secs(),
+            None => 0,
+        };
 
-            // composefs-info mmaps the file, so pipes aren't normally OK but we pass the
-            // underlying file directly, which works.
-            let output = Command::new("composefs-info")
-                .stdin(File::from(self.open_object(object)?))
-                .args(["objects", "/proc/self/fd/0"])
-                .output()?
-                .stdout;
+        flock(&self.repository, FlockOperation::LockExclusive)?;
 
-            if output.len() % 66 != 0 {
-                bail!("composefs-info gave invalid output (wrong size)");
+        // New streams get compressed with the current dictionary, even if no stream uses it yet
+        let mut live: HashSet<_> = HashSet::from_iter(self.dictionary.as_ref().map(|dictionary| dictionary.id));
+        let mut dead = vec![];
+        for category in ["images", "streams"] {
+            let (live_roots, dead_roots) = self.gc_category(category)?;
+            for root in live_roots {
+                live.extend(self.category_objects(category, root)?);
             }
+            for root in dead_roots {
+                let filename = format!("{category}/{}", hex::encode(root));
+                if keep_younger_than.is_some() && Repository::mtime(&self.repository, &filename)? >= cutoff {
+                    live.extend(self.category_objects(category, root)?);
+                } else {
+                    dead.push((category, root));
+                }
+            }
+        }
 
-            for line in output.chunks_exact(66) {
-                if line[2] != b'/' || line[65] != b'\n' {
-                    bail!("composefs-info gave invalid output");
+        let mut roots = HashMap::<Sha256HashValue, Vec<String>>::new();
+        for (category, root) in dead.iter() {
+            for object in self.category_objects(category, *root)? {
+                if !live.contains(&object) {
+                    roots.entry(object).or_default().push(format!("{category}/{}", hex::encode(root)));
+                }
+            }
+        }
+
+        // Remove the entries in images/ and streams/ first, so they never point to missing objects
+        if !dry_run {
+            for (category, root) in dead {
+                unlinkat(&self.repository, format!("{category}/{}", hex::encode(root)), AtFlags::empty())?;
+                if category == "images" {
+                    self.remove_signatures(root)?;
                 }
-                let mut value = Sha256HashValue::EMPTY;
-                hex::decode_to_slice(&line[0..2], &mut value[0..1])?;
-                hex::decode_to_slice(&line[3..65], &mut value[1..32])?;
-                println!("    with {}", hex::encode(value));
-                objects.insert(value);
             }
         }
 
-        for object in self.gc_category("streams")? {
-            println!("{} lives as a stream", hex::encode(object));
-            objects.insert(object);
+        let mut groups = BTreeMap::<Vec<String>, Garbage>::new();
+        let mut progress = Progress::new("Collecting garbage", "directories", Some(256));
+        for first_byte in 0x0..=0xff {
+            progress.inc(1);
+            let dirname = format!("objects/{first_byte:02x}");
+            let Some(dirfd) = self.open_optional_dir(&dirname)? else {
+                continue;
+            };
+
+            for item in Dir::read_from(&dirfd)? {
+                let entry = item?;
+                let filename = entry.file_name();
+                if filename == c"." || filename == c".." {
+                    continue;
+                }
+
+                let mut value = Sha256HashValue::EMPTY;
+                value[0] = first_byte;
+                hex::decode_to_slice(filename.to_bytes(), &mut value[1..])?;
+                if live.contains(&value) {
+                    continue;
+                }
 
-            let mut split_stream = self.open_stream(&hex::encode(object))?;
-            splitstream_objects(
-                &mut split_stream,
-                |obj: Sha256HashValue| {
-                    println!("   with {}", hex::encode(obj));
-                    objects.insert(obj);
+                if keep_younger_than.is_some() && Repository::mtime(&dirfd, filename)? >= cutoff {
+                    continue;
                 }
-            )?;
+
+                let key = roots.remove(&value).unwrap_or_default();
+                let group = groups.entry(key.clone()).or_insert_with(|| Garbage {
+                    roots: key, objects: vec![], size: 0
+                });
+                group.objects.push(value);
+                group.size += statat(&dirfd, filename, AtFlags::SYMLINK_NOFOLLOW)?.st_size as u64;
+
+                if !dry_run {
+                    unlinkat(&dirfd, filename, AtFlags::empty())?;
+                }
+                progress::debug(format!("rm {dirname}/{}", filename.to_string_lossy()));
+            }
         }
+        drop(progress);
+
+        flock(&self.repository, FlockOperation::LockShared)?;  // XXX: finally { } ?
+        Ok(groups.into_values().collect())
+    }
+
+    fn measure_object(&self, dirfd: &OwnedFd, filename: &CStr) -> Result<Sha256HashValue> {
+        let fd = openat(dirfd, filename, OFlags::RDONLY, Mode::empty())?;
+        self.measure_verity(&fd)
+    }
 
+    /// Re-measures the fs-verity digest of every object in the repository and compares it to the
+    /// name of the object.  Objects which fail the check are reported, and moved to quarantine/ if
+    /// requested.  Returns the number of objects which failed.
+    #[instrument(skip(self))]
+    pub fn verify_objects(&self, quarantine: bool) -> Result<usize> {
+        let mut failed = 0;
+
+        let mut progress = Progress::new("Verifying objects", "objects", None);
         for first_byte in 0x0..=0xff {
-            let dirfd = self.openat(&format!("objects/{first_byte:02x}"), OFlags::RDONLY | OFlags::DIRECTORY)?;
-            for item in Dir::new(dirfd)? {
-                match item {
-                    Err(e) => Err(e)?,
-                    Ok(entry) => {
-                        let filename = entry.file_name();
-                        if filename != c"." && filename != c".." {
-                            let mut value = Sha256HashValue::EMPTY;
-                            value[0] = first_byte;
-                            hex::decode_to_slice(filename.to_bytes(), &mut value[1..])?;
-                            if !objects.contains(&value) {
-                                println!("rm objects/{first_byte:02x}/{filename:?}");
-                            } else {
-                                println!("# objects/{first_byte:02x}/{filename:?} lives");
-                            }
-                        }
+            let dirname = format!("objects/{first_byte:02x}");
+            let dirfd = match self.openat(&dirname, OFlags::RDONLY | OFlags::DIRECTORY) {
+                Ok(fd) => fd,
+                Err(err) if err.downcast_ref::<Errno>() == Some(&Errno::NOENT) => continue,
+                Err(err) => Err(err)?,
+            };
+
+            for item in Dir::read_from(&dirfd)? {
+                let entry = item?;
+                let filename = entry.file_name();
+                if filename == c"." || filename == c".." {
+                    continue;
+                }
+
+                progress.inc(1);
+                let mut expected = Sha256HashValue::EMPTY;
+                expected[0] = first_byte;
+                let problem = if hex::decode_to_slice(filename.to_bytes(), &mut expected[1..]).is_err() {
+                    "invalid object name".to_string()
+                } else {
+                    match self.measure_object(&dirfd, filename) {
+                        Ok(digest) if digest == expected => continue,
+                        Ok(digest) => format!("measured {}", hex::encode(digest)),
+                        Err(err) => format!("{err}"),
                     }
+                };
+
+                progress::warn(format!("{dirname}/{filename:?}: {problem}"));
+                failed += 1;
+
+                if quarantine {
+                    self.ensure_dir("quarantine")?;
+                    let target = format!("quarantine/{first_byte:02x}{}", filename.to_string_lossy());
+                    renameat(&dirfd, filename, &self.repository, &target)?;
+                    progress::info(format!("  moved to {target}"));
                 }
             }
         }
 
-        Ok(flock(&self.repository, FlockOperation::LockShared)?)  // XXX: finally { } ?
+        Ok(failed)
     }
 
     pub fn fsck(&self) -> Result<()> {
@@ -0,0 +1,278 @@
+/* SELinux labels from file_contexts
+ *
+ * Layers built from plain directories have no security.selinux xattrs, and a system which
+ * enforces SELinux can't do much with unlabeled files.  Normally the package manager or setfiles
+ * labels files as they're written, according to the file_contexts of the policy.  We do the same
+ * lookup for the entries of a dumpfile, so that the labels are in the image from the start.
+ *
+ * The lookup works like libselinux's: each line of file_contexts is a regular expression (matched
+ * against the whole path), optionally a file type, and a context.  The last matching line wins,
+ * except that lines without any regular expression characters win over those with them.  Paths
+ * are first rewritten according to file_contexts.subs and file_contexts.subs_dist, and the
+ * file_contexts.homedirs and file_contexts.local files are read after file_contexts, if they exist.
+ */
+
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    os::unix::ffi::OsStrExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::OnceLock,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use composefs::dumpfile::{
+    Entry,
+    Item,
+    Xattr,
+};
+use regex_automata::{
+    meta::Regex,
+    util::syntax,
+};
+use rustix::fs::FileType;
+
+use crate::progress;
+
+pub const XATTR_NAME: &str = "security.selinux";
+
+struct Spec {
+    pattern: String,
+    // The (unescaped) part of the pattern before the first regular expression character: paths
+    // which don't start with it can't match, which saves compiling and running most of the
+    // expressions.  For patterns without any, that's the whole path.
+    stem: String,
+    has_meta: bool,
+    file_type: Option<FileType>,
+    // None for <<none>>: files which shouldn't be labeled
+    context: Option<String>,
+    regex: OnceLock<Option<Regex>>,
+}
+
+impl Spec {
+    fn matches(&self, path: &[u8]) -> bool {
+        if !self.has_meta {
+            return path == self.stem.as_bytes();
+        }
+        if !path.starts_with(self.stem.as_bytes()) {
+            return false;
+        }
+
+        let regex = self.regex.get_or_init(|| {
+            let syntax = syntax::Config::new().unicode(false).utf8(false);
+            match Regex::builder().syntax(syntax).build(&format!("^(?:{})$", self.pattern)) {
+                Ok(regex) => Some(regex),
+                Err(err) => {
+                    progress::warn(format!("Ignoring invalid file_contexts expression {:?}: {err}", self.pattern));
+                    None
+                },
+            }
+        });
+        regex.as_ref().is_some_and(|regex| regex.is_match(path))
+    }
+}
+
+// The length of the literal prefix of a pattern, or None if it has no regular expression characters
+// (as libselinux sees them).  Like libselinux, a '?', '*' or '{' takes the character before it out
+// of the prefix too, since that character might not be there: "/sbin/mount\.nfs4?" matches
+// "/sbin/mount.nfs".
+fn stem_len(pattern: &str) -> Option<usize> {
+    let mut escaped = false;
+    let mut atom = 0;  // where the last literal character (or escape sequence) starts
+    for (i, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => {
+                escaped = true;
+                atom = i;
+            },
+            '?' | '*' | '{' => return Some(atom),
+            '.' | '^' | '$' | '+' | '|' | '[' | '(' => return Some(i),
+            _ => atom = i,
+        }
+    }
+    None
+}
+
+fn parse_file_type(field: &str) -> Result<FileType> {
+    Ok(match field {
+        "--" => FileType::RegularFile,
+        "-d" => FileType::Directory,
+        "-l" => FileType::Symlink,
+        "-c" => FileType::CharacterDevice,
+        "-b" => FileType::BlockDevice,
+        "-p" => FileType::Fifo,
+        "-s" => FileType::Socket,
+        _ => bail!("Unknown file type {field:?}"),
+    })
+}
+
+fn parse_spec(line: &str) -> Result<Spec> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    let (pattern, file_type, context) = match fields[..] {
+        [pattern, context] => (pattern, None, context),
+        [pattern, file_type, context] => (pattern, Some(parse_file_type(file_type)?), context),
+        _ => bail!("Expected a path, an optional file type and a context"),
+    };
+
+    let (stem, has_meta) = match stem_len(pattern) {
+        Some(len) => (pattern[..len].replace('\\', ""), true),
+        None => (pattern.replace('\\', ""), false),
+    };
+    Ok(Spec {
+        pattern: pattern.to_string(),
+        stem,
+        has_meta,
+        file_type,
+        context: (context != "<<none>>").then(|| context.to_string()),
+        regex: OnceLock::new(),
+    })
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {path:?}")),
+    }
+}
+
+fn lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content.lines().enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// The labeling rules of an SELinux policy.
+pub struct FileContexts {
+    // The specs with regular expressions first, then the others, each in file order
+    specs: Vec<Spec>,
+    subs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl FileContexts {
+    /// Loads file_contexts (like /etc/selinux/targeted/contexts/files/file_contexts), along with
+    /// the .homedirs, .local, .subs and .subs_dist files next to it, if they exist.
+    pub fn load(path: &Path) -> Result<FileContexts> {
+        let sibling = |suffix: &str| {
+            let mut name = path.as_os_str().to_owned();
+            name.push(suffix);
+            PathBuf::from(name)
+        };
+
+        let mut specs = vec![];
+        for file in [path.to_path_buf(), sibling(".homedirs"), sibling(".local")] {
+            let content = match read_optional(&file)? {
+                Some(content) => content,
+                None if file == path => bail!("{path:?} doesn't exist"),
+                None => continue,
+            };
+            for (n, line) in lines(&content) {
+                specs.push(parse_spec(line).with_context(|| format!("{file:?}, line {n}"))?);
+            }
+        }
+        let (mut specs, exact): (Vec<_>, Vec<_>) = specs.into_iter().partition(|spec| spec.has_meta);
+        specs.extend(exact);
+
+        // Local substitutions take precedence over the ones from the distribution
+        let mut subs = vec![];
+        for file in [sibling(".subs"), sibling(".subs_dist")] {
+            for (n, line) in lines(&read_optional(&file)?.unwrap_or_default()) {
+                let [from, to] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                    bail!("{file:?}, line {n}: expected two paths");
+                };
+                subs.push((PathBuf::from(from), PathBuf::from(to)));
+            }
+        }
+
+        Ok(FileContexts { specs, subs })
+    }
+
+    /// Returns the context for a file at the given (absolute) path, with the file type of the
+    /// given mode.  Returns None if the file shouldn't be labeled.
+    pub fn lookup(&self, path: &Path, mode: u32) -> Option<&str> {
+        let path = match self.subs.iter().find_map(|(from, to)| Some((to, path.strip_prefix(from).ok()?))) {
+            Some((to, rest)) if rest.as_os_str().is_empty() => Cow::Borrowed(to.as_path()),
+            Some((to, rest)) => Cow::Owned(to.join(rest)),
+            None => Cow::Borrowed(path),
+        };
+
+        let file_type = FileType::from_raw_mode(mode);
+        self.specs.iter().rev()
+            .filter(|spec| spec.file_type.is_none_or(|spec_type| spec_type == file_type))
+            .find(|spec| spec.matches(path.as_os_str().as_bytes()))?
+            .context.as_deref()
+    }
+}
+
+/// Adds a security.selinux xattr to the entry, with the context for its path in contexts, unless
+/// it has one already.  Hardlinks are left alone: they share the xattrs of their target.
+pub fn add_label_xattr(contexts: &FileContexts, entry: &mut Entry) {
+    if matches!(entry.item, Item::Hardlink { .. }) || entry.xattrs.iter().any(|xattr| *xattr.key == *XATTR_NAME) {
+        return;
+    }
+
+    // Paths in layers are often like "/./etc"
+    let path: PathBuf = entry.path.components().collect();
+    if let Some(context) = contexts.lookup(&path, entry.mode) {
+        // libselinux includes the NUL in the value
+        let mut value = context.as_bytes().to_vec();
+        value.push(0);
+        entry.xattrs.push(Xattr { key: Cow::Owned(OsString::from(XATTR_NAME)), value: Cow::Owned(value) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contexts(lines: &[&str]) -> FileContexts {
+        let specs = lines.iter().map(|line| parse_spec(line).unwrap());
+        let (mut specs, exact): (Vec<_>, Vec<_>) = specs.partition(|spec| spec.has_meta);
+        specs.extend(exact);
+        FileContexts { specs, subs: vec![] }
+    }
+
+    #[test]
+    fn stem() {
+        assert_eq!(stem_len("/etc/hosts"), None);
+        assert_eq!(stem_len("/etc(/.*)?"), Some(4));
+        assert_eq!(stem_len("/usr/sbin/mount\\.nfs4?"), Some(20));
+        assert_eq!(stem_len("/usr/sbin/mount\\.?nfs"), Some(15));
+        assert_eq!(stem_len("/var/lib/a*"), Some(9));
+        assert_eq!(stem_len("/dev/ttyS[0-9]"), Some(9));
+        assert_eq!(stem_len("/dev/tty{1,2}"), Some(7));
+        assert_eq!(stem_len("?"), Some(0));
+
+        assert_eq!(parse_spec("/usr/sbin/mount\\.nfs4? ctx").unwrap().stem, "/usr/sbin/mount.nfs");
+        assert_eq!(parse_spec("/etc/a\\.b ctx").unwrap().stem, "/etc/a.b");
+    }
+
+    #[test]
+    fn lookup() {
+        let contexts = contexts(&[
+            "/.* default_t",
+            "/usr/sbin/mount\\.nfs4? mount_exec_t",
+            "/etc(/.*)? etc_t",
+            "/etc/shadow -- shadow_t",
+            "/etc/hosts net_conf_t",
+            "/etc/skip <<none>>",
+        ]);
+        let lookup = |path: &str, mode| contexts.lookup(Path::new(path), mode);
+        assert_eq!(lookup("/usr/sbin/mount.nfs", 0o100755), Some("mount_exec_t"));
+        assert_eq!(lookup("/usr/sbin/mount.nfs4", 0o100755), Some("mount_exec_t"));
+        assert_eq!(lookup("/usr/sbin/mount.nfs44", 0o100755), Some("default_t"));
+        assert_eq!(lookup("/etc", 0o40755), Some("etc_t"));
+        assert_eq!(lookup("/etc/shadow", 0o100600), Some("shadow_t"));
+        assert_eq!(lookup("/etc/shadow", 0o40755), Some("etc_t"));
+        assert_eq!(lookup("/etc/hosts", 0o100644), Some("net_conf_t"));
+        assert_eq!(lookup("/etc/skip", 0o100644), None);
+    }
+}
@@ -0,0 +1,236 @@
+/* Detached signatures of images
+ *
+ * An image is signed by signing its fs-verity digest with an Ed25519 key.  Since the digest
+ * covers the content of every file (via the digests of the objects), that's a signature of the
+ * whole filesystem, which holds no matter where the image was pulled from.  The signed message is
+ * the digest with a prefix, so that a signature of an image can't be mistaken for something else:
+ *
+ *     "composefs-image-v1\0" || digest
+ *
+ * The keys are in the PEM formats that openssl uses (PKCS#8 for private keys, SubjectPublicKeyInfo
+ * for public keys), so they can be made with:
+ *
+ *     openssl genpkey -algorithm ed25519 -out private.pem
+ *     openssl pkey -in private.pem -pubout -out public.pem
+ *
+ * See doc/repository.md for where the repository keeps signatures and trusted keys.
+ */
+
+use std::path::Path;
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+
+use ed25519_dalek::{
+    Signature,
+    Signer,
+    VerifyingKey,
+};
+
+use crate::{
+    fsverity::Sha256HashValue,
+    util::base64_decode,
+};
+
+const MESSAGE_PREFIX: &[u8] = b"composefs-image-v1\0";
+
+// The DER encodings of Ed25519 keys are fixed, up to the key itself
+const PRIVATE_KEY_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const PUBLIC_KEY_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+fn message(digest: &Sha256HashValue) -> Vec<u8> {
+    [MESSAGE_PREFIX, digest].concat()
+}
+
+// The DER data in the PEM block with the given label
+fn pem_decode(pem: &str, label: &str) -> Result<Vec<u8>> {
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+    let Some(start) = pem.find(&begin) else {
+        bail!("No {label} found");
+    };
+    let body = &pem[start + begin.len()..];
+    let Some(stop) = body.find(&end) else {
+        bail!("Unterminated {label}");
+    };
+    base64_decode(&body[..stop])
+}
+
+/// An Ed25519 private key.
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    /// Loads a private key from a PEM (PKCS#8) file.
+    pub fn load(path: &Path) -> Result<SigningKey> {
+        let pem = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let der = pem_decode(&pem, "PRIVATE KEY").with_context(|| format!("Invalid key in {path:?}"))?;
+        match der.strip_prefix(&PRIVATE_KEY_PREFIX[..]) {
+            Some(seed) if seed.len() == 32 => Ok(SigningKey::from_seed(seed.try_into().unwrap())),
+            _ => bail!("{path:?} isn't an Ed25519 private key"),
+        }
+    }
+
+    fn from_seed(seed: &[u8; 32]) -> SigningKey {
+        SigningKey(ed25519_dalek::SigningKey::from_bytes(seed))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.verifying_key())
+    }
+
+    fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.0.sign(message).to_bytes()
+    }
+
+    /// Signs the image with the given fs-verity digest.
+    pub fn sign_image(&self, digest: &Sha256HashValue) -> [u8; 64] {
+        self.sign(&message(digest))
+    }
+}
+
+/// An Ed25519 public key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicKey(VerifyingKey);
+
+impl PublicKey {
+    /// Reads a public key from PEM (SubjectPublicKeyInfo).
+    pub fn parse(pem: &str) -> Result<PublicKey> {
+        let der = pem_decode(pem, "PUBLIC KEY")?;
+        match der.strip_prefix(&PUBLIC_KEY_PREFIX[..]) {
+            Some(key) if key.len() == 32 => Ok(PublicKey(VerifyingKey::from_bytes(key.try_into().unwrap())?)),
+            _ => bail!("Not an Ed25519 public key"),
+        }
+    }
+
+    /// Loads a public key from a PEM file.
+    pub fn load(path: &Path) -> Result<PublicKey> {
+        let pem = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        PublicKey::parse(&pem).with_context(|| format!("Invalid key in {path:?}"))
+    }
+
+    /// The name of the key, which is what signatures by it are stored under: the key, in hex.
+    pub fn id(&self) -> String {
+        hex::encode(self.0.as_bytes())
+    }
+
+    // verify_strict() refuses the signatures that are valid for more than one message or key:
+    // non-canonical encodings and keys of small order
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match Signature::from_slice(signature) {
+            Ok(signature) => self.0.verify_strict(message, &signature).is_ok(),
+            Err(..) => false,
+        }
+    }
+
+    /// Checks a signature of the image with the given fs-verity digest.
+    pub fn verify_image(&self, digest: &Sha256HashValue, signature: &[u8]) -> bool {
+        self.verify(&message(digest), signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Verifier;
+
+    use super::*;
+
+    fn decode<const N: usize>(data: &str) -> [u8; N] {
+        hex::decode(data).unwrap().try_into().unwrap()
+    }
+
+    fn public_key(data: &[u8; 32]) -> PublicKey {
+        PublicKey(VerifyingKey::from_bytes(data).unwrap())
+    }
+
+    // RFC 8032, section 7.1: TEST 1, 2 and 3
+    const VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032() {
+        for (seed, public, message, signature) in VECTORS {
+            let (key, public) = (SigningKey::from_seed(&decode(seed)), public_key(&decode(public)));
+            let signature = decode::<64>(signature);
+            let message = hex::decode(message).unwrap();
+            assert_eq!(key.public_key(), public);
+            assert_eq!(key.sign(&message), signature);
+            assert!(public.verify(&message, &signature));
+            assert!(!public.verify(b"something else", &signature));
+            assert!(!public.verify(&message, &signature[..63]));
+        }
+    }
+
+    #[test]
+    fn malleated_s() {
+        let (_, public, message, signature) = VECTORS[1];
+        let (public, mut signature) = (public_key(&decode(public)), decode::<64>(signature));
+        let message = hex::decode(message).unwrap();
+
+        // s + L is the same scalar, but a different encoding of it
+        let l: [u8; 32] = decode("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+        let mut carry = 0;
+        for i in 0..32 {
+            let sum = signature[32 + i] as u16 + l[i] as u16 + carry;
+            signature[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+        assert_eq!(carry, 0);
+        assert!(!public.verify(&message, &signature));
+    }
+
+    #[test]
+    fn small_order_key() {
+        // With the identity as the key and R, and s = 0, the signature holds for any message,
+        // unless keys of small order are refused
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&identity);
+        let public = public_key(&identity);
+        assert!(public.0.verify(b"anything", &Signature::from_bytes(&signature)).is_ok());
+        assert!(!public.verify(b"anything", &signature));
+    }
+
+    #[test]
+    fn bad_points() {
+        // There's no point with y = 2
+        let mut off_curve = [0u8; 32];
+        off_curve[0] = 2;
+        assert!(VerifyingKey::from_bytes(&off_curve).is_err());
+        assert!(PublicKey::parse(&format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            crate::util::base64_encode(&[&PUBLIC_KEY_PREFIX[..], &off_curve].concat())
+        )).is_err());
+
+        let (_, public, message, signature) = VECTORS[2];
+        let (public, signature) = (public_key(&decode(public)), decode::<64>(signature));
+        let message = hex::decode(message).unwrap();
+
+        let mut bad_r = signature;
+        bad_r[..32].copy_from_slice(&off_curve);
+        assert!(!public.verify(&message, &bad_r));
+    }
+}
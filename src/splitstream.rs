@@ -4,8 +4,10 @@
  */
 
 use std::{
+    cmp::min,
     collections::VecDeque,
     io::{
+        Cursor,
         Read,
         Write,
     },
@@ -15,8 +17,14 @@ use anyhow::{
     Result,
     bail,
 };
+use tracing::{
+    debug,
+    instrument,
+    trace,
+};
 
 use crate::{
+    error::Error,
     fsverity::{
         FsVerityHashValue,
         Sha256HashValue,
@@ -24,6 +32,72 @@ use crate::{
     util::read_exactish,
 };
 
+/// Every stream starts with this, followed by the format version as a u64 le.  Interpreted as the
+/// size of an inline block, it would be absurdly large, so a header can't be confused with the
+/// start of a stream written before headers were introduced.
+pub const SPLITSTREAM_MAGIC: [u8; 8] = *b"CFSSPLIT";
+pub const SPLITSTREAM_VERSION: u64 = 1;
+
+pub enum SplitStreamHeader {
+    Version(u64),
+    /// A stream without a header.  Contains the bytes which were read while looking for it.
+    Legacy(Vec<u8>),
+}
+
+/// Writes the header for a stream which refers to the given objects, and whose blocks are
+/// compressed with the given zstd dictionary (an object too).  The header contains a table of the
+/// referenced objects, which makes it possible to find them without reading the entire stream.
+pub fn write_splitstream_header<W: Write>(
+    writer: &mut W, dictionary: Option<&Sha256HashValue>, objects: &[Sha256HashValue]
+) -> Result<()> {
+    debug!(objects = objects.len(), "writing stream header");
+    writer.write_all(&SPLITSTREAM_MAGIC)?;
+    writer.write_all(&SPLITSTREAM_VERSION.to_le_bytes())?;
+    writer.write_all(dictionary.unwrap_or(&Sha256HashValue::EMPTY))?;
+    writer.write_all(&(objects.len() as u64).to_le_bytes())?;
+    for id in objects {
+        writer.write_all(id)?;
+    }
+    Ok(())
+}
+
+pub fn read_splitstream_header<R: Read>(reader: &mut R) -> Result<SplitStreamHeader> {
+    let mut magic = [0u8; 8];
+    if !read_exactish(reader, &mut magic)? {
+        return Ok(SplitStreamHeader::Legacy(vec![]));
+    }
+    if magic != SPLITSTREAM_MAGIC {
+        return Ok(SplitStreamHeader::Legacy(magic.to_vec()));
+    }
+
+    let mut version = [0u8; 8];
+    reader.read_exact(&mut version)?;
+    let version = u64::from_le_bytes(version);
+    trace!(version, "read stream header");
+    Ok(SplitStreamHeader::Version(version))
+}
+
+/// Reads the digest of the zstd dictionary, which follows the version in the header.
+pub fn read_splitstream_dictionary<R: Read>(reader: &mut R) -> Result<Option<Sha256HashValue>> {
+    let mut id = Sha256HashValue::EMPTY;
+    reader.read_exact(&mut id)?;
+    Ok((id != Sha256HashValue::EMPTY).then_some(id))
+}
+
+/// Reads the table of referenced objects which follows the dictionary in the header.
+pub fn read_splitstream_object_table<R: Read>(reader: &mut R) -> Result<Vec<Sha256HashValue>> {
+    let mut count = [0u8; 8];
+    reader.read_exact(&mut count)?;
+
+    let mut objects = vec![];
+    for _ in 0..u64::from_le_bytes(count) {
+        let mut id = Sha256HashValue::EMPTY;
+        reader.read_exact(&mut id)?;
+        objects.push(id);
+    }
+    Ok(objects)
+}
+
 // utility class to help write splitstreams
 pub struct SplitStreamWriter<'w, W: Write> {
     inline_content: Vec<u8>,
@@ -44,8 +118,8 @@ impl<'w, W: Write> SplitStreamWriter<'w, W> {
     fn flush_inline(&mut self, new_value: Vec<u8>) -> Result<()> {
         if !self.inline_content.is_empty() {
             SplitStreamWriter::write_fragment(self.writer, self.inline_content.len(), &self.inline_content)?;
-            self.inline_content = new_value;
         }
+        self.inline_content = new_value;
         Ok(())
     }
 
@@ -71,6 +145,7 @@ impl<'w, W: Write> SplitStreamWriter<'w, W> {
     }
 }
 
+#[derive(Clone)]
 pub enum SplitStreamData {
     Inline(Vec<u8>),
     External(Sha256HashValue),
@@ -112,7 +187,7 @@ impl<'r, R: Read> SplitStreamReader<'r, R> {
             match read_splitstream_chunk(&mut self.reader)? {
                 None => { return Ok(false); }
                 Some(SplitStreamData::Inline(data)) => { self.inline_content = data.into() },
-                Some(SplitStreamData::External(_)) => { bail!("Expecting inline data but found external chunk") }
+                Some(SplitStreamData::External(_)) => { bail!(Error::InvalidFormat("Expecting inline data but found external chunk".to_string())) }
             }
         }
 
@@ -123,15 +198,15 @@ impl<'r, R: Read> SplitStreamReader<'r, R> {
     pub fn read_exact(&mut self, actual_size: usize, stored_size: usize) -> Result<SplitStreamData> {
         if self.inline_content.is_empty() {
             match read_splitstream_chunk(&mut self.reader)? {
-                None => { bail!("Unexpected EOF") },
+                None => { bail!(Error::InvalidFormat("Unexpected EOF".to_string())) },
                 Some(SplitStreamData::Inline(data)) => { self.inline_content = data.into() },
                 Some(ext) => {
                     if actual_size != stored_size {
                         // need to eat the padding...
                         match read_splitstream_chunk(&mut self.reader)? {
-                            None => { bail!("bad eof") },
+                            None => { bail!(Error::InvalidFormat("Unexpected EOF in padding".to_string())) },
                             Some(SplitStreamData::Inline(data)) => { self.inline_content = data.into() },
-                            Some(SplitStreamData::External(_)) => { bail!("Expecting inline data but found external chunk") }
+                            Some(SplitStreamData::External(_)) => { bail!(Error::InvalidFormat("Expecting inline data but found external chunk".to_string())) }
                         }
                         // TODO: make this suck less
                         let mut padding = vec![0u8; stored_size - actual_size];
@@ -143,7 +218,10 @@ impl<'r, R: Read> SplitStreamReader<'r, R> {
             }
         }
 
-        // must be inline
+        // must be inline, so it's all in the chunk that we have already (don't trust the size)
+        if stored_size > self.inline_content.len() {
+            bail!(Error::InvalidFormat(format!("Expected {stored_size} bytes of inline data, but there are only {}", self.inline_content.len())));
+        }
         let mut data = vec![0u8; stored_size];
         self.inline_content.read_exact(&mut data)?;
         data.truncate(actual_size);
@@ -164,6 +242,99 @@ pub fn splitstream_merge<R: Read, W: Write, F: FnMut(Sha256HashValue) -> Result<
     Ok(())
 }
 
+/// A streaming version of splitstream_merge(): reading from this gives the merged content of the
+/// splitstream.  load_object returns a reader for the content of an external object, which means
+/// that objects don't need to be loaded into memory in their entirety.
+pub struct SplitStreamMerger<R: Read, O: Read, F: FnMut(Sha256HashValue) -> Result<O>> {
+    split_stream: R,
+    load_object: F,
+    inline: Cursor<Vec<u8>>,
+    object: Option<O>,
+}
+
+impl<R: Read, O: Read, F: FnMut(Sha256HashValue) -> Result<O>> SplitStreamMerger<R, O, F> {
+    pub fn new(split_stream: R, load_object: F) -> SplitStreamMerger<R, O, F> {
+        SplitStreamMerger { split_stream, load_object, inline: Cursor::new(vec![]), object: None }
+    }
+}
+
+impl<R: Read, O: Read, F: FnMut(Sha256HashValue) -> Result<O>> Read for SplitStreamMerger<R, O, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if let Some(object) = &mut self.object {
+                match object.read(buf)? {
+                    0 => self.object = None,
+                    n => return Ok(n),
+                }
+            }
+
+            match self.inline.read(buf)? {
+                0 => {},
+                n => return Ok(n),
+            }
+
+            match read_splitstream_chunk(&mut self.split_stream).map_err(std::io::Error::other)? {
+                None => return Ok(0),
+                Some(SplitStreamData::Inline(data)) => self.inline = Cursor::new(data),
+                Some(SplitStreamData::External(id)) => {
+                    trace!(object = hex::encode(id), "loading object");
+                    self.object = Some((self.load_object)(id).map_err(std::io::Error::other)?);
+                },
+            }
+        }
+    }
+}
+
+/// Like splitstream_merge(), but only writes the part of the merged stream which starts at the
+/// given offset and has (at most) the given length.  object_size returns the size of an external
+/// object: external objects which are entirely outside of the range are skipped over without
+/// loading them.
+#[instrument(level = "debug", skip_all, fields(offset, length))]
+pub fn splitstream_merge_range<R, W, S, F>(
+    split_stream: &mut R, result: &mut W, offset: u64, length: u64, mut object_size: S, mut load_data: F,
+) -> Result<()>
+where
+    R: Read,
+    W: Write,
+    S: FnMut(Sha256HashValue) -> Result<u64>,
+    F: FnMut(Sha256HashValue) -> Result<Vec<u8>>,
+{
+    let end = offset.saturating_add(length);
+    let mut position = 0u64;
+
+    while position < end {
+        let Some(data) = read_splitstream_chunk(split_stream)? else {
+            break;
+        };
+
+        let (size, content) = match data {
+            SplitStreamData::Inline(data) => (data.len() as u64, Some(data)),
+            SplitStreamData::External(id) => match object_size(id)? {
+                size if position + size <= offset => (size, None),
+                _ => {
+                    let data = load_data(id)?;
+                    (data.len() as u64, Some(data))
+                },
+            },
+        };
+
+        if let Some(content) = content {
+            if position + size > offset {
+                let start = offset.saturating_sub(position) as usize;
+                let stop = (min(end, position + size) - position) as usize;
+                result.write_all(&content[start..stop])?;
+            }
+        }
+        position += size;
+    }
+
+    Ok(())
+}
+
 pub fn splitstream_objects<R: Read, F: FnMut(Sha256HashValue)>(
     split_stream: &mut R, mut callback: F
 ) -> Result<()> {
@@ -176,3 +347,34 @@ pub fn splitstream_objects<R: Read, F: FnMut(Sha256HashValue)>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_header(data: &[u8]) -> (Option<Sha256HashValue>, Vec<Sha256HashValue>) {
+        let mut reader = data;
+        assert!(matches!(read_splitstream_header(&mut reader).unwrap(), SplitStreamHeader::Version(SPLITSTREAM_VERSION)));
+        let dictionary = read_splitstream_dictionary(&mut reader).unwrap();
+        let objects = read_splitstream_object_table(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        (dictionary, objects)
+    }
+
+    #[test]
+    fn header() {
+        let objects = [[1; 32], [2; 32]];
+
+        let mut data = vec![];
+        write_splitstream_header(&mut data, Some(&[3; 32]), &objects).unwrap();
+        assert_eq!(data.len(), 8 + 8 + 32 + 8 + 2 * 32);
+        assert_eq!(read_header(&data), (Some([3; 32]), objects.to_vec()));
+
+        let mut data = vec![];
+        write_splitstream_header(&mut data, None, &[]).unwrap();
+        assert_eq!(read_header(&data), (None, vec![]));
+
+        // Anything else is a stream from before the header
+        assert!(matches!(read_splitstream_header(&mut &[0u8; 8][..]).unwrap(), SplitStreamHeader::Legacy(_)));
+    }
+}
@@ -0,0 +1,141 @@
+/* Building systemd-sysext extension images
+ *
+ * A system extension is a filesystem image with content for /usr (and/or /opt), and an
+ * extension-release file which says which hosts it's meant for.  systemd-sysext merges the
+ * extensions in /var/lib/extensions (and a few other places) onto the host with overlayfs.
+ *
+ * We build the image with mkfs.erofs (from erofs-utils), from a directory or from the /usr and
+ * /opt of an image in the repository, and store it in the repository like any other image: it
+ * gets an fs-verity digest, and gc treats it like the rest.
+ */
+
+use std::{
+    fs::File,
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+
+use crate::{
+    fsverity::Sha256HashValue,
+    image::{
+        self,
+        DevicePolicy,
+    },
+    progress,
+    repository::Repository,
+    tmpdir::TempDir,
+};
+
+// systemd-sysext only looks at these
+const EXTENSION_DIRS: [&str; 2] = ["usr", "opt"];
+
+/// Where the content of an extension comes from.
+pub enum Source<'a> {
+    /// The /usr and /opt of an image in the repository
+    Image(&'a str),
+    /// A directory containing usr/ and/or opt/
+    Directory(&'a Path),
+}
+
+/// The content of the extension-release file, which systemd-sysext matches against the
+/// os-release of the host.
+pub struct ExtensionRelease {
+    /// The ID of the host OS, or "_any"
+    pub id: String,
+    pub version_id: Option<String>,
+    pub sysext_level: Option<String>,
+}
+
+impl ExtensionRelease {
+    fn contents(&self) -> String {
+        let mut contents = format!("ID={}\n", self.id);
+        if let Some(version_id) = &self.version_id {
+            contents.push_str(&format!("VERSION_ID={version_id}\n"));
+        }
+        if let Some(sysext_level) = &self.sysext_level {
+            contents.push_str(&format!("SYSEXT_LEVEL={sysext_level}\n"));
+        }
+        contents
+    }
+}
+
+fn stage(repo: &Repository, source: &Source, name: &str, release: &ExtensionRelease, root: &Path) -> Result<()> {
+    match source {
+        Source::Image(image) => {
+            let dump = repo.dump_image(image)?;
+            for dir in EXTENSION_DIRS {
+                let path = Path::new("/").join(dir);
+                if image::find_entry(&dump, &path)?.is_some() {
+                    std::fs::create_dir(root.join(dir))?;
+                    image::extract_subtree(repo, image, &path, &root.join(dir), DevicePolicy::Skip)?;
+                }
+            }
+        },
+        Source::Directory(source_dir) => {
+            for dir in EXTENSION_DIRS {
+                if source_dir.join(dir).is_dir() {
+                    let status = Command::new("cp")
+                        .arg("-a")
+                        .arg("--reflink=auto")
+                        .arg(source_dir.join(dir))
+                        .arg(root)
+                        .status()?;
+                    if !status.success() {
+                        bail!("Failed to copy {:?}: {status}", source_dir.join(dir));
+                    }
+                }
+            }
+        },
+    }
+
+    if !root.join("usr").is_dir() && !root.join("opt").is_dir() {
+        bail!("The extension has neither /usr nor /opt");
+    }
+
+    let release_dir = root.join("usr/lib/extension-release.d");
+    let release_file = release_dir.join(format!("extension-release.{name}"));
+    if release_file.exists() {
+        progress::info(format!("Using the existing extension-release.{name}"));
+    } else {
+        std::fs::create_dir_all(&release_dir)?;
+        std::fs::write(&release_file, release.contents())?;
+    }
+    Ok(())
+}
+
+fn run_mkfs(root: &Path, output: &Path) -> Result<()> {
+    let status = Command::new("mkfs.erofs")
+        .arg(output)
+        .arg(root)
+        .status()
+        .context("Failed to run mkfs.erofs (is erofs-utils installed?)")?;
+    if !status.success() {
+        bail!("mkfs.erofs failed: {status}");
+    }
+    Ok(())
+}
+
+/// Builds a system extension with the given name, and stores it in the repository as the image
+/// sysext/<name>.  If output is given, the image is also written there (it needs to be called
+/// <name>.raw for systemd-sysext).  Returns the fs-verity digest of the image.
+pub fn build(
+    repo: &Repository, source: &Source, name: &str, release: &ExtensionRelease, output: Option<&Path>
+) -> Result<Sha256HashValue> {
+    let tmp = TempDir::new()?;
+    let root = tmp.path.join("root");
+    let raw = tmp.path.join(format!("{name}.raw"));
+
+    std::fs::create_dir(&root)?;
+    stage(repo, source, name, release, &root)?;
+    run_mkfs(&root, &raw)?;
+    if let Some(output) = output {
+        std::fs::copy(&raw, output).with_context(|| format!("Failed to write {output:?}"))?;
+    }
+    repo.import_image(&format!("sysext/{name}"), &mut File::open(&raw)?, None)
+}
@@ -0,0 +1,120 @@
+/* Generation of systemd mount units for composefs images
+ *
+ * mount(8) can't do what mount_fd() does with file descriptors, so the units use the older
+ * way of stacking things: the erofs image is mounted (via a loop device) on a directory in
+ * /run/composefs/, and the overlayfs refers to that by path, with the objects directory as a
+ * data-only lower layer.
+ */
+
+use anyhow::{
+    Result,
+    bail,
+};
+
+/// Escapes a path for use as the name of a unit, like `systemd-escape --path`.
+pub fn escape_path(path: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut escaped = String::new();
+    for (i, byte) in trimmed.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{byte:02x}")),
+        }
+    }
+    escaped
+}
+
+// Unit files expand % specifiers in most settings.
+fn escape_value(value: &str) -> String {
+    value.replace('%', "%%")
+}
+
+pub struct MountUnit {
+    pub description: String,
+    pub what: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub options: String,
+    /// other mount units which must be mounted first
+    pub requires: Vec<String>,
+    /// paths which must be available (like the repository)
+    pub requires_mounts_for: Vec<String>,
+    /// if the unit should be pulled in by local-fs.target when it's enabled
+    pub install: bool,
+}
+
+impl MountUnit {
+    /// The name of the unit, which systemd requires to match the mountpoint.
+    pub fn name(&self) -> String {
+        format!("{}.mount", escape_path(&self.mountpoint))
+    }
+
+    pub fn render(&self) -> String {
+        let mut unit = String::from("# Generated by cfsctl systemd generate\n\n[Unit]\n");
+        unit.push_str(&format!("Description={}\n", escape_value(&self.description)));
+        for path in self.requires_mounts_for.iter() {
+            unit.push_str(&format!("RequiresMountsFor={}\n", escape_value(path)));
+        }
+        for name in self.requires.iter() {
+            unit.push_str(&format!("Requires={name}\nAfter={name}\n"));
+        }
+
+        unit.push_str("\n[Mount]\n");
+        unit.push_str(&format!("What={}\n", escape_value(&self.what)));
+        unit.push_str(&format!("Where={}\n", escape_value(&self.mountpoint)));
+        unit.push_str(&format!("Type={}\n", self.fstype));
+        unit.push_str(&format!("Options={}\n", escape_value(&self.options)));
+
+        if self.install {
+            unit.push_str("\n[Install]\nWantedBy=local-fs.target\n");
+        }
+        unit
+    }
+}
+
+/// Returns the units for mounting the image at image_path (with the given digest) on mountpoint,
+/// with its objects in objects_path: one for the erofs, and one for the overlayfs on top of it.
+/// All of the paths must be absolute.
+pub fn composefs_mount_units(
+    image_path: &str, digest: &str, objects_path: &str, mountpoint: &str
+) -> Result<Vec<MountUnit>> {
+    for path in [image_path, objects_path, mountpoint] {
+        if !path.starts_with('/') {
+            bail!("Path {path} must be absolute");
+        }
+    }
+    // this would need escaping in the overlayfs options, which mount(8) can't do
+    if objects_path.contains([',', ':', '\\']) {
+        bail!("Path {objects_path} can't be used in the options of a mount unit");
+    }
+
+    let lower = MountUnit {
+        description: format!("composefs image {digest} (erofs)"),
+        what: image_path.to_string(),
+        mountpoint: format!("/run/composefs/{digest}"),
+        fstype: "erofs".to_string(),
+        options: "ro,loop".to_string(),
+        requires: vec![],
+        requires_mounts_for: vec![image_path.to_string()],
+        install: false,
+    };
+
+    let overlay = MountUnit {
+        description: format!("composefs image {digest}"),
+        what: "composefs".to_string(),
+        mountpoint: mountpoint.to_string(),
+        fstype: "overlay".to_string(),
+        options: format!("ro,metacopy=on,redirect_dir=on,lowerdir={}::{objects_path}", lower.mountpoint),
+        requires: vec![lower.name()],
+        requires_mounts_for: vec![objects_path.to_string()],
+        install: true,
+    };
+
+    Ok(vec![lower, overlay])
+}
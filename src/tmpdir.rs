@@ -6,6 +6,8 @@ use anyhow::{
 };
 use rand::distributions::{Alphanumeric, DistString};
 
+use crate::progress;
+
 pub struct TempDir {
     pub path: PathBuf,
 }
@@ -29,7 +31,15 @@ impl TempDir {
 }
 
 impl Drop for TempDir {
+    /// Removes the directory and everything in it.  A directory which is still a mount point
+    /// (EBUSY) is left alone, instead of deleting the content of whatever is mounted there.
     fn drop(&mut self) {
-        std::fs::remove_dir(&self.path).expect("can't remove tempdir");
+        let result = match std::fs::remove_dir(&self.path) {
+            Err(err) if err.kind() == std::io::ErrorKind::DirectoryNotEmpty => std::fs::remove_dir_all(&self.path),
+            result => result,
+        };
+        if let Err(err) = result {
+            progress::warn(format!("Failed to remove temporary directory {:?}: {err}", self.path));
+        }
     }
 }
@@ -0,0 +1,248 @@
+/* Measuring images into a TPM
+ *
+ * For remote attestation to cover the root filesystem, the initramfs extends a PCR with the
+ * fs-verity digest of the image before mounting it.  Since the digest covers the content of every
+ * file, a quote of that PCR proves exactly which image was booted.  The measured event is the
+ * string
+ *
+ *     "composefs:" || <digest in hex>
+ *
+ * which goes into every PCR bank that the TPM has allocated, hashed with the algorithm of the
+ * bank, so that a quote of any of them shows it.  Use a PCR which nothing else extends after boot
+ * (like 15, "system identity" in systemd's terms), so that the expected value can be computed from
+ * the digest alone.
+ *
+ * We talk to the kernel's resource manager (/dev/tpmrm0) directly, since the initramfs is unlikely
+ * to have tpm2-tools.  See "TPM 2.0 Part 3: Commands", TPM2_GetCapability and TPM2_PCR_Extend.
+ */
+
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::{
+        Read,
+        Write,
+    },
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use sha1::Sha1;
+use sha2::{
+    Digest,
+    Sha256,
+    Sha384,
+    Sha512,
+};
+
+use crate::fsverity::Sha256HashValue;
+
+const DEVICE: &str = "/dev/tpmrm0";
+const N_PCRS: u32 = 24;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_GET_CAPABILITY: u32 = 0x017a;
+const TPM_CC_PCR_EXTEND: u32 = 0x0182;
+const TPM_CAP_PCRS: u32 = 0x0005;
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_ALG_SHA1: u16 = 0x0004;
+const TPM_ALG_SHA256: u16 = 0x000b;
+const TPM_ALG_SHA384: u16 = 0x000c;
+const TPM_ALG_SHA512: u16 = 0x000d;
+
+/// The event that an image is measured as.  Each bank of the PCR gets extended with the hash of
+/// this, using the hash algorithm of the bank.
+pub fn image_event(digest: &Sha256HashValue) -> String {
+    format!("composefs:{}", hex::encode(digest))
+}
+
+fn bank_digest(algorithm: u16, event: &[u8]) -> Result<Vec<u8>> {
+    Ok(match algorithm {
+        TPM_ALG_SHA1 => Sha1::digest(event).to_vec(),
+        TPM_ALG_SHA256 => Sha256::digest(event).to_vec(),
+        TPM_ALG_SHA384 => Sha384::digest(event).to_vec(),
+        TPM_ALG_SHA512 => Sha512::digest(event).to_vec(),
+        // Leaving a bank out would make it look like nothing was measured
+        _ => bail!("The TPM has a PCR bank with an unsupported hash algorithm ({algorithm:#06x})"),
+    })
+}
+
+fn finish_command(mut command: Vec<u8>) -> Vec<u8> {
+    let size = command.len() as u32;
+    command[2..6].copy_from_slice(&size.to_be_bytes());
+    command
+}
+
+// Asks for the PCR banks and which PCRs each of them has
+fn get_pcr_banks_command() -> Vec<u8> {
+    let mut command = vec![];
+    command.extend_from_slice(&TPM_ST_NO_SESSIONS.to_be_bytes());
+    command.extend_from_slice(&0u32.to_be_bytes());  // size, filled in by finish_command()
+    command.extend_from_slice(&TPM_CC_GET_CAPABILITY.to_be_bytes());
+    command.extend_from_slice(&TPM_CAP_PCRS.to_be_bytes());
+    command.extend_from_slice(&0u32.to_be_bytes());  // property: unused for TPM_CAP_PCRS
+    command.extend_from_slice(&1u32.to_be_bytes());  // property count
+    finish_command(command)
+}
+
+// The hash algorithms of the banks which have the PCR, from the response to get_pcr_banks_command():
+// the header, moreData, the capability and a TPML_PCR_SELECTION.
+fn parse_pcr_banks(response: &[u8], pcr: u32) -> Result<Vec<u16>> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if data.len() < n {
+            bail!("Short response from the TPM");
+        }
+        let (head, tail) = data.split_at(n);
+        *data = tail;
+        Ok(head)
+    }
+
+    let mut data = response.get(10..).context("Short response from the TPM")?;
+    take(&mut data, 1)?;  // moreData
+    let capability = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+    if capability != TPM_CAP_PCRS {
+        bail!("The TPM returned capability {capability:#x} instead of the PCR banks");
+    }
+
+    let mut banks = vec![];
+    let count = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+    for _ in 0..count {
+        let algorithm = u16::from_be_bytes(take(&mut data, 2)?.try_into().unwrap());
+        let size = take(&mut data, 1)?[0];
+        let select = take(&mut data, size as usize)?;
+        if select.get(pcr as usize / 8).is_some_and(|bits| bits & (1 << (pcr % 8)) != 0) {
+            banks.push(algorithm);
+        }
+    }
+    Ok(banks)
+}
+
+fn pcr_extend_command(pcr: u32, digests: &[(u16, Vec<u8>)]) -> Vec<u8> {
+    let mut command = vec![];
+    command.extend_from_slice(&TPM_ST_SESSIONS.to_be_bytes());
+    command.extend_from_slice(&0u32.to_be_bytes());  // size, filled in by finish_command()
+    command.extend_from_slice(&TPM_CC_PCR_EXTEND.to_be_bytes());
+    command.extend_from_slice(&pcr.to_be_bytes());
+    // The authorization area: an empty password, which is what PCRs 0-23 need
+    command.extend_from_slice(&9u32.to_be_bytes());
+    command.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+    command.extend_from_slice(&0u16.to_be_bytes());  // nonce
+    command.push(0);  // session attributes
+    command.extend_from_slice(&0u16.to_be_bytes());  // password
+    // The digests (one per bank) to extend with
+    command.extend_from_slice(&(digests.len() as u32).to_be_bytes());
+    for (algorithm, digest) in digests {
+        command.extend_from_slice(&algorithm.to_be_bytes());
+        command.extend_from_slice(digest);
+    }
+    finish_command(command)
+}
+
+// Sends a command and returns the response, after checking its response code
+fn transact(tpm: &mut File, command: &[u8]) -> Result<Vec<u8>> {
+    tpm.write_all(command)?;
+
+    // The response comes in a single read, starting with a header: tag, size, response code
+    let mut response = vec![0u8; 4096];
+    let size = tpm.read(&mut response)?;
+    if size < 10 {
+        bail!("Short response from the TPM ({size} bytes)");
+    }
+    response.truncate(size);
+    let code = u32::from_be_bytes(response[6..10].try_into().unwrap());
+    if code != 0 {
+        bail!("TPM error {code:#x}");
+    }
+    Ok(response)
+}
+
+/// Extends every bank of the PCR with the measurement of the image with the given fs-verity
+/// digest.
+pub fn measure_image(pcr: u32, digest: &Sha256HashValue) -> Result<()> {
+    if pcr >= N_PCRS {
+        bail!("Invalid PCR {pcr}: PCRs are numbered 0 to {}", N_PCRS - 1);
+    }
+
+    let mut tpm = OpenOptions::new().read(true).write(true).open(DEVICE)
+        .with_context(|| format!("Failed to open the TPM ({DEVICE})"))?;
+
+    let response = transact(&mut tpm, &get_pcr_banks_command()).context("Failed to get the PCR banks")?;
+    let banks = parse_pcr_banks(&response, pcr)?;
+    if banks.is_empty() {
+        bail!("PCR {pcr} isn't in any of the PCR banks of the TPM");
+    }
+
+    let event = image_event(digest);
+    let digests = banks.iter()
+        .map(|algorithm| Ok((*algorithm, bank_digest(*algorithm, event.as_bytes())?)))
+        .collect::<Result<Vec<_>>>()?;
+    transact(&mut tpm, &pcr_extend_command(pcr, &digests)).with_context(|| format!("Failed to extend PCR {pcr}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_pcr_banks() {
+        let expected = [
+            "8001",  // TPM_ST_NO_SESSIONS
+            "00000016",  // size: 22
+            "0000017a",  // TPM_CC_GetCapability
+            "00000005",  // TPM_CAP_PCRS
+            "00000000",  // property
+            "00000001",  // propertyCount
+        ].concat();
+        assert_eq!(hex::encode(get_pcr_banks_command()), expected);
+    }
+
+    #[test]
+    fn pcr_banks() {
+        let response = hex::decode(concat!(
+            "80010000002500000000",  // TPM_ST_NO_SESSIONS, size 37, TPM_RC_SUCCESS
+            "00",  // moreData
+            "00000005",  // TPM_CAP_PCRS
+            "00000003",  // three banks
+            "0004", "03", "ffffff",  // SHA-1, all PCRs
+            "000b", "03", "00800f",  // SHA-256, PCRs 0-3 and 15
+            "000c", "03", "000000",  // SHA-384, not allocated
+        )).unwrap();
+        assert_eq!(parse_pcr_banks(&response, 15).unwrap(), [TPM_ALG_SHA1, TPM_ALG_SHA256]);
+        assert_eq!(parse_pcr_banks(&response, 4).unwrap(), [TPM_ALG_SHA1]);
+        assert!(parse_pcr_banks(&response[..response.len() - 1], 15).is_err());
+    }
+
+    #[test]
+    fn pcr_extend() {
+        let command = pcr_extend_command(15, &[(TPM_ALG_SHA256, vec![0xab; 32]), (TPM_ALG_SHA1, vec![0xcd; 20])]);
+        let expected = [
+            "8002",  // TPM_ST_SESSIONS
+            "00000057",  // size: 10 + 4 + 4 + 9 + 4 + (2 + 32) + (2 + 20) = 87
+            "00000182",  // TPM_CC_PCR_Extend
+            "0000000f",  // pcrHandle
+            "00000009",  // authorizationSize
+            "40000009", "0000", "00", "0000",  // TPM_RS_PW, empty nonce, no attributes, empty password
+            "00000002",  // TPML_DIGEST_VALUES.count
+            "000b", &"ab".repeat(32),
+            "0004", &"cd".repeat(20),
+        ].concat();
+        assert_eq!(hex::encode(&command), expected);
+        assert_eq!(command.len(), 87);
+    }
+
+    #[test]
+    fn event() {
+        let event = image_event(&[0x12; 32]);
+        assert_eq!(event, format!("composefs:{}", "12".repeat(32)));
+        assert_eq!(bank_digest(TPM_ALG_SHA256, event.as_bytes()).unwrap(), Sha256::digest(&event).to_vec());
+        assert_eq!(bank_digest(TPM_ALG_SHA384, event.as_bytes()).unwrap().len(), 48);
+        assert!(bank_digest(0x0012, event.as_bytes()).is_err());  // SM3
+    }
+}
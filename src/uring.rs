@@ -0,0 +1,95 @@
+/* Bulk reading of small files with io_uring
+ *
+ * Reassembling a stream means reading thousands of small objects, and doing that with one read()
+ * at a time is syscall-heavy and only ever has one request in flight.  BulkReader submits the
+ * reads for a whole batch of files at once instead.  This is only built with the io-uring
+ * feature, and even then io_uring might not be usable at runtime (old kernels, seccomp filters,
+ * ...), so callers always need a fallback to ordinary reads.
+ */
+
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+};
+
+use anyhow::{
+    Result,
+    bail,
+};
+use io_uring::{
+    IoUring,
+    opcode,
+    types,
+};
+
+pub struct BulkReader {
+    ring: IoUring,
+}
+
+impl BulkReader {
+    /// Sets up a ring which can have up to max_files reads in flight.  Returns None if io_uring
+    /// isn't available.
+    pub fn new(max_files: usize) -> Option<BulkReader> {
+        Some(BulkReader { ring: IoUring::new(max_files as u32).ok()? })
+    }
+
+    /// Reads the complete content of each of the files, which have the given sizes.  There can't be
+    /// more files than the max_files that the reader was created with.
+    pub fn read_all(&mut self, files: &[(File, usize)]) -> Result<Vec<Vec<u8>>> {
+        // Check this up front: we can't back out of a partially-queued batch
+        if files.len() > self.ring.params().sq_entries() as usize {
+            bail!("Too many files for one batch of reads");
+        }
+
+        let mut buffers: Vec<Vec<u8>> = files.iter().map(|(_, size)| vec![0u8; *size]).collect();
+
+        for (idx, ((file, size), buffer)) in files.iter().zip(buffers.iter_mut()).enumerate() {
+            let read = opcode::Read::new(types::Fd(file.as_raw_fd()), buffer.as_mut_ptr(), *size as u32)
+                .offset(0)
+                .build()
+                .user_data(idx as u64);
+            // SAFETY: the buffers and the files outlive the requests: we wait for all of them below
+            unsafe { self.ring.submission().push(&read) }.expect("the queue has room for the batch");
+        }
+
+        let mut done = vec![0usize; files.len()];
+        let mut pending = files.len();
+        let mut error = None;
+        while pending > 0 {
+            match self.ring.submit_and_wait(pending) {
+                Ok(_) => {},
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    // The kernel might still write to the buffers, so they can't be freed
+                    std::mem::forget(buffers);
+                    return Err(err.into());
+                },
+            }
+            for completion in self.ring.completion() {
+                let idx = completion.user_data() as usize;
+                match completion.result() {
+                    result if result < 0 => {
+                        error.get_or_insert(std::io::Error::from_raw_os_error(-result));
+                    },
+                    result => done[idx] = result as usize,
+                }
+                pending -= 1;
+            }
+        }
+        if let Some(err) = error {
+            Err(err)?;
+        }
+
+        // Short reads are possible (in theory): finish those the old-fashioned way
+        for (idx, (file, size)) in files.iter().enumerate() {
+            while done[idx] < *size {
+                match rustix::io::pread(file, &mut buffers[idx][done[idx]..], done[idx] as u64)? {
+                    0 => bail!("File shrunk while reading it"),
+                    n => done[idx] += n,
+                }
+            }
+        }
+
+        Ok(buffers)
+    }
+}
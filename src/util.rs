@@ -1,12 +1,71 @@
 use std::{
-    io::Read,
+    io::{
+        Cursor,
+        ErrorKind,
+        Read,
+    },
     os::fd::{
         AsFd,
         AsRawFd,
     },
+    ptr::null_mut,
+    sync::mpsc::{
+        Receiver,
+        sync_channel,
+    },
+    thread::Scope,
+};
+
+use anyhow::{
+    Result,
+    bail,
 };
+use rustix::mm::{
+    MapFlags,
+    ProtFlags,
+    mmap,
+    munmap,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
-use anyhow::Result;
+/// Standard base64, with padding.
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// Decodes standard base64, ignoring whitespace.
+pub fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let mut result = vec![];
+    let (mut bits, mut n_bits) = (0u32, 0);
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let Some(value) = BASE64_ALPHABET.iter().position(|a| *a == c) else {
+            bail!("Invalid base64 character {:?}", c as char);
+        };
+        bits = (bits << 6 | value as u32) & 0xffff;  // never more than 14 bits pending
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            result.push((bits >> n_bits) as u8);
+        }
+    }
+    Ok(result)
+}
 
 pub fn proc_self_fd<A: AsFd>(fd: &A) -> String {
     format!("/proc/self/fd/{}", fd.as_fd().as_raw_fd())
@@ -37,3 +96,120 @@ pub fn read_exactish<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
 
     Ok(true)
 }
+
+/// Computes the sha256 digest of everything which is read through it.
+pub struct Sha256Reader<'r, R: Read> {
+    reader: &'r mut R,
+    context: Sha256,
+}
+
+impl<'r, R: Read> Sha256Reader<'r, R> {
+    pub fn new(reader: &'r mut R) -> Sha256Reader<'r, R> {
+        Sha256Reader { reader, context: Sha256::new() }
+    }
+
+    pub fn digest(self) -> [u8; 32] {
+        self.context.finalize().into()
+    }
+}
+
+impl<R: Read> Read for Sha256Reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.context.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// The size of the blocks that ReadAhead reads, and how many of them it reads ahead
+const READAHEAD_BLOCK_SIZE: usize = 1 << 20;
+const READAHEAD_BLOCKS: usize = 16;
+
+/// Reads from another reader on a separate thread, so that producing the data (decompressing it,
+/// for example) happens in parallel with consuming it.
+pub struct ReadAhead {
+    receiver: Receiver<std::io::Result<Vec<u8>>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl ReadAhead {
+    pub fn spawn<'s, R: Read + Send>(scope: &'s Scope<'s, '_>, reader: &'s mut R) -> ReadAhead {
+        let (sender, receiver) = sync_channel(READAHEAD_BLOCKS);
+
+        scope.spawn(move || loop {
+            let mut block = vec![0u8; READAHEAD_BLOCK_SIZE];
+            let result = match reader.read(&mut block) {
+                Ok(0) => return,  // EOF
+                Ok(n) => {
+                    block.truncate(n);
+                    Ok(block)
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                return;  // the consumer went away, or we told it about the error
+            }
+        });
+
+        ReadAhead { receiver, current: Cursor::new(vec![]) }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.current.read(buf)? {
+                0 if !buf.is_empty() => match self.receiver.recv() {
+                    Ok(block) => self.current = Cursor::new(block?),
+                    Err(_) => return Ok(0),  // the reader thread is done
+                },
+                n => return Ok(n),
+            }
+        }
+    }
+}
+
+/// A read-only mapping of a complete file.  Only map files which can't change underneath us (like
+/// objects with fs-verity enabled): truncating a mapped file makes accessing the mapping crash.
+pub struct Mmap {
+    ptr: *mut std::ffi::c_void,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned by this struct
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    pub fn map<Fd: AsFd>(fd: Fd) -> Result<Mmap> {
+        let len = rustix::fs::fstat(&fd)?.st_size as usize;
+        if len == 0 {
+            // mmap() refuses empty mappings
+            return Ok(Mmap { ptr: null_mut(), len });
+        }
+        // SAFETY: we ask the kernel for a new mapping, which doesn't affect any existing memory
+        let ptr = unsafe { mmap(null_mut(), len, ProtFlags::READ, MapFlags::PRIVATE, fd, 0)? };
+        Ok(Mmap { ptr, len })
+    }
+}
+
+impl AsRef<[u8]> for Mmap {
+    fn as_ref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: the mapping is valid (and immutable) until we drop it
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: nothing can refer to the mapping anymore
+            let _ = unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
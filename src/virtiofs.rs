@@ -0,0 +1,67 @@
+/* Sharing images with virtual machines over virtiofs
+ *
+ * A microVM can boot from (or mount) an image in the host's repository via virtiofs: we mount
+ * the image as a composefs, so that the content comes from the objects in the repository and is
+ * checked against their fs-verity digests, and run virtiofsd to share that mount with the guest.
+ *
+ * The mount is made in a new mount namespace, which virtiofsd inherits, so it's neither visible
+ * to the rest of the system nor left behind when we're interrupted: it goes away along with the
+ * namespace once virtiofsd and cfsctl have exited.
+ */
+
+use std::{
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{
+    Context,
+    Result,
+    bail,
+};
+use rustix::{
+    mount::{
+        MountPropagationFlags,
+        mount_change,
+    },
+    thread::{
+        UnshareFlags,
+        unshare,
+    },
+};
+
+use crate::{
+    progress,
+    repository::Repository,
+    tmpdir::TempDir,
+};
+
+/// Mounts the named image and shares it on the vhost-user socket with virtiofsd, until virtiofsd
+/// exits (when the guest disconnects, or when it's interrupted).  The guest sees the image
+/// read-only.  require_verity is as for Repository::mount().
+///
+/// This moves the calling process into a new mount namespace, and must be called before any
+/// threads are started.
+pub fn serve(repo: Repository, name: &str, socket: &Path, virtiofsd: &Path, require_verity: bool) -> Result<()> {
+    unshare(UnshareFlags::NEWNS).context("Failed to create a mount namespace")?;
+    mount_change("/", MountPropagationFlags::PRIVATE | MountPropagationFlags::REC)?;
+
+    let tmp = TempDir::new()?;
+    let mountpoint = tmp.path.to_str().context("Temporary directory isn't valid UTF-8")?;
+    repo.mount(name, mountpoint, false, require_verity)?;
+
+    progress::info(format!("Sharing {name} on {}", socket.display()));
+    let status = Command::new(virtiofsd)
+        .arg("--socket-path").arg(socket)
+        .arg("--shared-dir").arg(&tmp.path)
+        .arg("--readonly")
+        .status();
+
+    // Make the directory removable again (when tmp is dropped), even if virtiofsd didn't run
+    crate::mount::unmount_composefs(mountpoint, true)?;
+    let status = status.with_context(|| format!("Failed to run {}", virtiofsd.display()))?;
+    if !status.success() {
+        bail!("virtiofsd failed: {status}");
+    }
+    Ok(())
+}